@@ -1,4 +1,4 @@
-use teloxide::{prelude::*, types::*, utils::command::BotCommands};
+use teloxide::{prelude::*, types::*, utils::{command::BotCommands, markdown}};
 use sqlx::SqlitePool;
 use tempfile::Builder;
 use tokio::fs;
@@ -6,23 +6,270 @@ use teloxide::net::Download;
 use std::path::{Path, PathBuf};
 use std::cmp::Reverse;
 use std::env;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use once_cell::sync::{Lazy, OnceCell};
 
 // Imports for computer vision and inline editing.
 use image::{io::Reader as ImageReader, Luma};
 use imageproc::{contours::{find_contours, Contour}, rect::Rect};
 use reqwest::Url;
 use std::process::Stdio;
+use std::future::Future;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncBufReadExt;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use dashmap::DashMap;
 
 // --- Data Structures ---
 
 #[derive(Clone, Debug, sqlx::FromRow)]
-struct VideoData { caption: String, file_id: String }
+struct VideoData {
+    caption: String,
+    file_id: String,
+    /// `KIND_ANIMATION` for silent clips stored as animations, `KIND_AUDIO` for `/audio` saves;
+    /// anything else is a regular video.
+    #[sqlx(default)]
+    kind: String,
+    /// Space-separated tags from `#tag` words in the save caption, if any.
+    #[sqlx(default)]
+    tags: Option<String>,
+}
+
+/// `videos.kind` of clips uploaded with `send_animation`.
+const KIND_ANIMATION: &str = "animation";
+
+/// `videos.kind` of sounds saved with `/audio` and uploaded with `send_audio`.
+const KIND_AUDIO: &str = "audio";
 type SharedState = SqlitePool;
 
 #[derive(sqlx::FromRow)]
 struct Count { count: i64 }
 
+/// Options parsed from `/flag` tokens in a save caption.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+struct SaveOptions {
+    /// 1-based audio track chosen with `/audiotrack N`.
+    audio_track: Option<usize>,
+    /// `/cpu` or `/gpu` override of the default encoder.
+    encoder: Option<EncoderChoice>,
+    /// `/trim START-END`: keep only this range, in seconds.
+    trim: Option<(f64, f64)>,
+    /// `#tag` words, lowercased and without the `#`.
+    tags: Vec<String>,
+    /// `/hd` or `/sd` override of the yt-dlp format for link downloads.
+    quality: Option<DownloadQuality>,
+    /// Display name of the saving user, for the `{user}` caption template placeholder.
+    uploader: String,
+    /// `/audio`: save only the sound, as an audio file.
+    audio_only: bool,
+    /// The normalized link a download came from; `None` for uploaded videos.
+    source_url: Option<String>,
+    /// `/nocrop`: skip crop detection and save the video as it is.
+    no_crop: bool,
+    /// The saving user's caption template, resolved when the save is queued.
+    caption_template: Option<String>,
+}
+
+/// Per-message yt-dlp quality override.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+enum DownloadQuality {
+    /// Allow files up to 50M instead of 20M.
+    Hd,
+    /// Cap the resolution at 480p.
+    Sd,
+}
+
+impl DownloadQuality {
+    fn take_override(text: &mut String) -> Option<Self> {
+        if take_flag(text, "/hd") {
+            Some(DownloadQuality::Hd)
+        } else if take_flag(text, "/sd") {
+            Some(DownloadQuality::Sd)
+        } else {
+            None
+        }
+    }
+
+    /// The yt-dlp `--format` selector; `None` is the default 20M-capped selector.
+    fn format_selector(quality: Option<Self>) -> &'static str {
+        match quality {
+            None => "bv*[ext=mp4][filesize<20M]+ba[ext=m4a]/b[ext=mp4][filesize<20M]/bv*+ba/b",
+            Some(DownloadQuality::Hd) => "bv*[ext=mp4][filesize<50M]+ba[ext=m4a]/b[ext=mp4][filesize<50M]/bv*+ba/b",
+            Some(DownloadQuality::Sd) => "bv*[ext=mp4][height<=480][filesize<20M]+ba[ext=m4a]/b[ext=mp4][height<=480][filesize<20M]/bv*[height<=480]+ba/b[height<=480]/b",
+        }
+    }
+}
+
+/// Options parsed from `/flag` tokens in an inline `/edit` query.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+struct EditOptions {
+    /// 1-based audio track chosen with `/audiotrack N`.
+    audio_track: Option<usize>,
+    /// `/softsub`: mux the text as a selectable subtitle track instead of burning it in.
+    soft_subs: bool,
+    /// `/cpu` or `/gpu` override of the default encoder.
+    encoder: Option<EncoderChoice>,
+    /// `/transcribe`: caption the clip with its own speech, timed per segment.
+    transcribe: bool,
+    /// `/color NAME`: fill color for detected boxes and color of caption text.
+    color: Option<String>,
+    /// `/gif`: send a looping GIF of at most [`MAX_GIF_SECS`] instead of an mp4.
+    gif: bool,
+    /// `/mute`: drop the audio entirely.
+    mute: bool,
+    /// `/dub`: replace the audio with the last audio file the user sent the bot privately.
+    dub: bool,
+    /// `/padcolor NAME`: color of the bar added above the clip in caption mode, instead of black.
+    pad_color: Option<String>,
+    /// `/fontsize N`: caption mode font size, instead of one derived from the bar height.
+    font_size: Option<u32>,
+    /// `/preview`: show the detected crop and text boxes on a frame instead of encoding.
+    preview: bool,
+    /// `/bottom`: put the caption mode bar below the clip. `/top`, the default, is also accepted.
+    caption_bottom: bool,
+    /// `/speed N`: playback speed factor, clamped to [`EDIT_SPEEDS`].
+    speed: Option<f64>,
+}
+
+/// Font sizes `/fontsize` accepts.
+const EDIT_FONT_SIZES: std::ops::RangeInclusive<u32> = 10..=200;
+
+/// Playback speeds `/speed` clamps to.
+const EDIT_SPEEDS: std::ops::RangeInclusive<f64> = 0.25..=4.0;
+
+/// An `atempo` chain for `speed`. Each stage is kept within 0.5-2.0, which older ffmpeg
+/// builds require, so extreme speeds take several stages.
+fn atempo_chain(speed: f64) -> String {
+    let mut stages = vec![];
+    let mut remaining = speed;
+    while remaining > 2.0 {
+        stages.push("atempo=2.0".to_string());
+        remaining /= 2.0;
+    }
+    while remaining < 0.5 {
+        stages.push("atempo=0.5".to_string());
+        remaining /= 0.5;
+    }
+    stages.push(format!("atempo={}", remaining));
+    stages.join(",")
+}
+
+/// Longest clip `/gif` converts, in seconds, to keep the palette GIF a sane size.
+const MAX_GIF_SECS: u32 = 10;
+
+/// Colors accepted by `/color`: the ffmpeg color name, its ASS `&HAABBGGRR` value, and whether
+/// it is dark enough to need white text on top.
+const EDIT_COLORS: &[(&str, &str, bool)] = &[
+    ("white", "&H00FFFFFF", false),
+    ("black", "&H00000000", true),
+    ("yellow", "&H0000FFFF", false),
+    ("orange", "&H0000A5FF", false),
+    ("red", "&H000000FF", true),
+    ("pink", "&H00CBC0FF", false),
+    ("magenta", "&H00FF00FF", true),
+    ("blue", "&H00FF0000", true),
+    ("cyan", "&H00FFFF00", false),
+    ("green", "&H00008000", true),
+    ("gray", "&H00808080", true),
+];
+
+/// Looks up a `/color` name in [`EDIT_COLORS`].
+fn edit_color(name: &str) -> Option<(&'static str, &'static str, bool)> {
+    EDIT_COLORS.iter().find(|(color, _, _)| color.eq_ignore_ascii_case(name)).copied()
+}
+
+/// Removes a standalone `flag` token from `text`, returning whether it was present.
+fn take_flag(text: &mut String, flag: &str) -> bool {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let Some(pos) = tokens.iter().position(|t| *t == flag) else { return false };
+    let remaining: Vec<&str> = tokens.iter().enumerate().filter(|(i, _)| *i != pos).map(|(_, t)| *t).collect();
+    *text = remaining.join(" ");
+    true
+}
+
+/// Removes `#tag` words from `text`, returning the tags lowercased and without the `#`.
+fn take_tags(text: &mut String) -> Vec<String> {
+    let (tags, rest): (Vec<&str>, Vec<&str>) = text.split_whitespace().partition(|t| t.len() > 1 && t.starts_with('#'));
+    if tags.is_empty() {
+        return vec![];
+    }
+    let tags = tags.iter().map(|t| t.trim_start_matches('#').to_lowercase()).collect();
+    *text = rest.join(" ");
+    tags
+}
+
+/// Removes a `flag value` token pair from `text`, returning the value.
+fn take_flag_value(text: &mut String, flag: &str) -> Option<String> {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let pos = tokens.iter().position(|t| *t == flag)?;
+    let value = tokens.get(pos + 1)?.to_string();
+    let remaining: Vec<&str> = tokens.iter().enumerate().filter(|(i, _)| *i != pos && *i != pos + 1).map(|(_, t)| *t).collect();
+    *text = remaining.join(" ");
+    Some(value)
+}
+
+/// Parses a `/trim` range like "3-12" or "1.5-4" in seconds. Returns `None` unless both ends
+/// are finite, the start isn't negative and the start comes before the end.
+fn parse_trim_range(range: &str) -> Option<(f64, f64)> {
+    let (start, end) = range.split_once('-')?;
+    let start: f64 = start.trim().parse().ok()?;
+    let end: f64 = end.trim().parse().ok()?;
+    (start.is_finite() && end.is_finite() && start >= 0.0 && start < end).then_some((start, end))
+}
+
+/// Splits the `/flag` options out of a save caption.
+/// Returns the reply for the user if a flag's value is invalid.
+fn parse_save_options(caption: &str) -> Result<(String, SaveOptions), String> {
+    let mut caption = caption.to_string();
+    let trim = match take_flag_value(&mut caption, "/trim") {
+        Some(range) => Some(parse_trim_range(&range).ok_or_else(|| format!("❌ Error: '{}' isn't a valid range. {}", range, TRIM_USAGE))?),
+        None if caption.split_whitespace().any(|token| token == "/trim") => return Err(format!("❌ Error: {}", TRIM_USAGE)),
+        None => None,
+    };
+    let options = SaveOptions {
+        audio_track: take_flag_value(&mut caption, "/audiotrack").and_then(|v| v.parse().ok()),
+        encoder: EncoderChoice::take_override(&mut caption),
+        trim,
+        tags: take_tags(&mut caption),
+        quality: DownloadQuality::take_override(&mut caption),
+        audio_only: take_flag(&mut caption, "/audio"),
+        no_crop: take_flag(&mut caption, "/nocrop"),
+        ..Default::default()
+    };
+    Ok((caption.trim().to_string(), options))
+}
+
+const TRIM_USAGE: &str = "Use /trim START-END in seconds, with START before END, e.g. /trim 3-12.";
+
+/// Splits the `/flag` options out of the raw `/edit` parameters.
+fn parse_edit_options(edit_params: &str) -> (String, EditOptions) {
+    let mut params = edit_params.to_string();
+    let options = EditOptions {
+        audio_track: take_flag_value(&mut params, "/audiotrack").and_then(|v| v.parse().ok()),
+        soft_subs: take_flag(&mut params, "/softsub"),
+        encoder: EncoderChoice::take_override(&mut params),
+        transcribe: take_flag(&mut params, "/transcribe"),
+        color: take_flag_value(&mut params, "/color").filter(|name| edit_color(name).is_some()).map(|name| name.to_lowercase()),
+        gif: take_flag(&mut params, "/gif"),
+        mute: take_flag(&mut params, "/mute"),
+        dub: take_flag(&mut params, "/dub"),
+        pad_color: take_flag_value(&mut params, "/padcolor").filter(|name| edit_color(name).is_some()).map(|name| name.to_lowercase()),
+        font_size: take_flag_value(&mut params, "/fontsize").and_then(|v| v.parse().ok()).filter(|size| EDIT_FONT_SIZES.contains(size)),
+        preview: take_flag(&mut params, "/preview"),
+        caption_bottom: {
+            // `/top` is the default, so it only needs removing from the text.
+            let top = take_flag(&mut params, "/top");
+            take_flag(&mut params, "/bottom") && !top
+        },
+        speed: take_flag_value(&mut params, "/speed").and_then(|v| v.parse::<f64>().ok())
+            .filter(|speed| speed.is_finite() && *speed != 1.0)
+            .map(|speed| speed.clamp(*EDIT_SPEEDS.start(), *EDIT_SPEEDS.end())),
+    };
+    (params.trim().to_string(), options)
+}
+
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "These commands are supported:")]
 enum Command {
@@ -30,13 +277,65 @@ enum Command {
     Help,
     #[command(description = "Start a dialog to remove a saved video")]
     Remove,
+    #[command(description = "Start a dialog to rename a saved video")]
+    Rename,
+    #[command(description = "Toggle keeping the original video visible while an edit is processing")]
+    KeepMedia,
+    #[command(description = "(admin) Allow this chat, or the given chat id, to use the bot")]
+    AllowChat(String),
+    #[command(description = "(admin) Remove this chat, or the given chat id, from the allowlist")]
+    DisallowChat(String),
+    #[command(description = "(admin) Toggle automatic cropping of saved videos for everyone")]
+    AutoCrop,
+    #[command(description = "Show the most recently saved videos")]
+    Recent,
+    #[command(description = "Toggle keeping a copy of every edited video in this chat with the bot")]
+    DmCopy,
+    #[command(description = "(admin) Show the text boxes detected in a saved video as JSON, e.g. /boxes cat video")]
+    Boxes(String),
+    #[command(description = "Save all of your recently sent uncaptioned videos with one caption")]
+    SaveAll(String),
+    #[command(description = "Toggle showing a thumbnail of the clip while an inline edit is processing")]
+    ThumbPreview,
+    #[command(description = "(admin) Reload the caption blocklist from its file")]
+    ReloadBlocklist,
+    #[command(description = "Reply to an edited video to get the original version back")]
+    Original,
+    #[command(description = "Reply to a muted video to get the version with sound back")]
+    Unmute,
+    #[command(description = "Set a saved video's thumbnail to the frame at a time, e.g. /setthumb cat video 2.5")]
+    SetThumb(String),
+    #[command(description = "(admin) Compact the database file")]
+    Vacuum,
+    #[command(description = "Show how much processing time your saves and edits used, e.g. /mytime 7 for the last week")]
+    MyTime(String),
+    #[command(description = "(admin) Show the bot's active configuration")]
+    Config,
+    #[command(description = "(admin) Run crop detection over a server folder or matching saved videos, e.g. /croptest cat")]
+    CropTest(String),
+    #[command(description = "Get a JSON backup of your saved videos")]
+    Export,
+    #[command(description = "(admin) Send a message to everyone who has saved a video")]
+    Broadcast(String),
+    #[command(description = "Delete your older videos that have the same caption as a newer one")]
+    Dedup,
+    #[command(description = "Find your saved videos by caption and send one here, e.g. /search cat")]
+    Search(String),
+    #[command(description = "Show the link a saved video was downloaded from, e.g. /source cat video")]
+    Source(String),
+    #[command(description = "Pick a saved video and get it back as a round video note")]
+    Note,
+    #[command(description = "Set how your captions are shown, e.g. /captiontemplate {caption} via @me; no argument clears it")]
+    CaptionTemplate(String),
+    #[command(description = "Show a numbered list of all your saved captions")]
+    List,
 }
 
 // --- Computer Vision Logic ---
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Serialize)]
 struct BoundingBox { x: i32, y: i32, w: u32, h: u32 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct CropRect { x: u32, y: u32, w: u32, h: u32 }
 
 /// Helper function to convert contours into bounding boxes. Used only for the `/edit` feature.
@@ -62,20 +361,193 @@ fn contours_to_bounding_boxes(
         .collect()
 }
 
-/// Detects large white or black boxes for the `/edit` command to place text into. NOT used for cropping.
-fn detect_white_or_black_boxes(image_path: &Path) -> Vec<BoundingBox> {
+/// What an `/edit` asks for, once its `/flag` options are taken out.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+enum EditSpec {
+    /// One text for every detected box.
+    Plain(String),
+    /// `/box2`, `/box3`, ...: one text per box, in the order written.
+    Boxes(Vec<String>),
+    /// `first /TIME second`: the first text, switching to the second at `at` seconds.
+    Timed { first: String, at: f64, second: String },
+}
+
+impl EditSpec {
+    /// The texts to place, one per box in order.
+    fn texts(&self) -> Vec<&str> {
+        match self {
+            EditSpec::Plain(text) => vec![text.as_str()],
+            EditSpec::Boxes(texts) => texts.iter().map(String::as_str).collect(),
+            EditSpec::Timed { first, second, .. } => vec![first.as_str(), second.as_str()],
+        }
+    }
+
+    /// The inline result description shown before the edit is sent.
+    fn describe(&self) -> String {
+        match self {
+            EditSpec::Plain(text) => format!("Click to replace text with: '{}'", text),
+            EditSpec::Boxes(texts) => texts.iter().enumerate()
+                .map(|(i, text)| format!("BOX {}: '{}'", i + 1, text))
+                .collect::<Vec<_>>().join(" | "),
+            EditSpec::Timed { first, at, second } => format!("TEXT 1: '{}' | TEXT 2: '{}' (at {}s)", first, second, at),
+        }
+    }
+}
+
+/// Parses a timed edit's switch time in seconds: "5.5", "0:05", "1:02:03.5", "1m2s" or "1h".
+fn parse_edit_time(text: &str) -> Option<f64> {
+    if text.is_empty() {
+        return None;
+    }
+    let seconds = if let Ok(seconds) = text.parse::<f64>() {
+        seconds
+    } else if text.contains(':') {
+        let parts: Vec<&str> = text.split(':').collect();
+        let (whole, last) = parts.split_at(parts.len() - 1);
+        if !(1..=2).contains(&whole.len()) {
+            return None;
+        }
+        let secs: f64 = last[0].parse().ok().filter(|s| (0.0..60.0).contains(s))?;
+        let mut units = Vec::with_capacity(whole.len());
+        for (i, part) in whole.iter().enumerate() {
+            let value: u32 = part.parse().ok()?;
+            // In H:MM:SS the minutes have to be under an hour.
+            if i == 1 && value >= 60 {
+                return None;
+            }
+            units.push(value as f64);
+        }
+        units.iter().fold(0.0, |total, unit| (total + unit) * 60.0) + secs
+    } else {
+        let mut total = 0.0;
+        let mut rest = text;
+        let mut allowed_units = "hms";
+        while !rest.is_empty() {
+            let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+            let value: f64 = rest[..number_len].parse().ok()?;
+            let unit = rest[number_len..].chars().next()?;
+            // Each of h, m and s at most once, in that order.
+            let unit_pos = allowed_units.find(unit)?;
+            total += value * match unit { 'h' => 3600.0, 'm' => 60.0, _ => 1.0 };
+            allowed_units = &allowed_units[unit_pos + 1..];
+            rest = &rest[number_len + 1..];
+        }
+        total
+    };
+    (seconds.is_finite() && seconds >= 0.0).then_some(seconds)
+}
+
+/// Parses `/edit` text with its options already removed. The last `/` followed by a time
+/// and more text makes a timed edit; otherwise `/boxN` markers split it per box.
+fn parse_edit_params(edit_params: &str, max_boxes: usize) -> EditSpec {
+    if let Some((first, rest)) = edit_params.rsplit_once('/') {
+        if let Some((time_str, second)) = rest.trim().split_once(' ') {
+            if let Some(at) = parse_edit_time(time_str) {
+                return EditSpec::Timed { first: first.trim().to_string(), at, second: second.trim().to_string() };
+            }
+        }
+    }
+    match split_box_texts(edit_params, max_boxes) {
+        Some(texts) => EditSpec::Boxes(texts),
+        None => EditSpec::Plain(edit_params.to_string()),
+    }
+}
+
+/// Splits `/edit` text on `/box2`, `/box3`, ... up to `/box{max_boxes}` markers into one text
+/// per box, in the order written. Returns `None` if there are no markers.
+fn split_box_texts(edit_params: &str, max_boxes: usize) -> Option<Vec<String>> {
+    let markers: Vec<(usize, usize)> = edit_params.match_indices("/box")
+        .filter_map(|(pos, _)| {
+            let digits = edit_params[pos + 4..].chars().take_while(|c| c.is_ascii_digit()).count();
+            let box_number: usize = edit_params[pos + 4..pos + 4 + digits].parse().ok()?;
+            (2..=max_boxes).contains(&box_number).then_some((pos, pos + 4 + digits))
+        })
+        .collect();
+    if markers.is_empty() {
+        return None;
+    }
+    let mut texts = vec![];
+    let mut text_start = 0;
+    for (marker_start, marker_end) in markers {
+        texts.push(edit_params[text_start..marker_start].trim().to_string());
+        text_start = marker_end;
+    }
+    texts.push(edit_params[text_start..].trim().to_string());
+    Some(texts)
+}
+
+/// Runs `detect_white_or_black_boxes` on the blocking thread pool so the decode and contour
+/// search don't stall the async runtime.
+async fn detect_boxes_off_runtime(image_path: &Path, config: &Config) -> Vec<BoundingBox> {
+    let image_path = image_path.to_path_buf();
+    let (ratios, max_boxes) = (config.box_ratios, config.max_edit_boxes);
+    tokio::task::spawn_blocking(move || detect_white_or_black_boxes(&image_path, ratios, max_boxes)).await.unwrap_or_else(|e| {
+        log::error!("Box detection task failed: {}", e);
+        vec![]
+    })
+}
+
+/// Runs a motion crop detection closure on the blocking thread pool.
+async fn detect_motion_off_runtime(detect: impl FnOnce() -> Option<CropRect> + Send + 'static) -> Option<CropRect> {
+    tokio::task::spawn_blocking(detect).await.unwrap_or_else(|e| {
+        log::error!("Motion detection task failed: {}", e);
+        None
+    })
+}
+
+/// Smallest text box `/edit` fills, as fractions of the frame size, from `BOX_MIN_WIDTH_RATIO`
+/// and `BOX_MIN_HEIGHT_RATIO`.
+#[derive(Clone, Copy, Debug)]
+struct BoxRatios {
+    min_width: f32,
+    min_height: f32,
+}
+
+impl BoxRatios {
+    const DEFAULT: BoxRatios = BoxRatios { min_width: 0.4, min_height: 0.2 };
+
+    fn from_env() -> Self {
+        let ratio = |key: &str, default: f32| {
+            let value = env_parse(key, default);
+            if value > 0.0 && value <= 1.0 {
+                value
+            } else {
+                log::warn!("{} must be a fraction between 0 and 1. Using the default.", key);
+                default
+            }
+        };
+        BoxRatios {
+            min_width: ratio("BOX_MIN_WIDTH_RATIO", Self::DEFAULT.min_width),
+            min_height: ratio("BOX_MIN_HEIGHT_RATIO", Self::DEFAULT.min_height),
+        }
+    }
+}
+
+/// Detects up to `max_boxes` large white or black boxes for the `/edit` command to place text
+/// into. NOT used for cropping.
+fn detect_white_or_black_boxes(image_path: &Path, ratios: BoxRatios, max_boxes: usize) -> Vec<BoundingBox> {
     let Some(img) = ImageReader::open(image_path).ok().and_then(|r| r.decode().ok()) else { return vec![]; };
+    let (full_width, full_height) = (img.width(), img.height());
+
+    // `find_contours` gets very slow on large frames, so detect on a downscaled copy and
+    // scale the boxes back up to the real resolution afterwards.
+    const MAX_DETECTION_DIMENSION: u32 = 1280;
+    let img = if full_width.max(full_height) > MAX_DETECTION_DIMENSION {
+        img.resize(MAX_DETECTION_DIMENSION, MAX_DETECTION_DIMENSION, image::imageops::FilterType::Triangle)
+    } else {
+        img
+    };
     let original_luma = img.to_luma8();
     let (original_width, original_height) = original_luma.dimensions();
+    let scale_x = full_width as f32 / original_width as f32;
+    let scale_y = full_height as f32 / original_height as f32;
 
     const PADDING: u32 = 1;
     let mut padded_image = image::GrayImage::new(original_width + PADDING * 2, original_height + PADDING * 2);
     image::imageops::replace(&mut padded_image, &original_luma, PADDING as i64, PADDING as i64);
 
-    const MIN_BOX_WIDTH_RATIO: f32 = 0.4;
-    const MIN_BOX_HEIGHT_RATIO: f32 = 0.2;
-    let min_width = (original_width as f32 * MIN_BOX_WIDTH_RATIO) as u32;
-    let min_height = (original_height as f32 * MIN_BOX_HEIGHT_RATIO) as u32;
+    let min_width = (original_width as f32 * ratios.min_width) as u32;
+    let min_height = (original_height as f32 * ratios.min_height) as u32;
 
     let white_binary_image = imageproc::map::map_pixels(&padded_image, |_, _, p| {
         if p[0] > 230 { image::Luma([255]) } else { image::Luma([0]) }
@@ -97,21 +569,73 @@ fn detect_white_or_black_boxes(image_path: &Path) -> Vec<BoundingBox> {
     boxes.sort_by_key(|b| Reverse(b.width() * b.height()));
     boxes.into_iter()
          .filter(|rect| rect.height() < original_height)
-        .take(2)
+        .take(max_boxes)
         .map(|rect| BoundingBox {
-            x: rect.left(),
-            y: rect.top(),
-            w: rect.width(),
-            h: rect.height(),
+            x: (rect.left() as f32 * scale_x).round() as i32,
+            y: (rect.top() as f32 * scale_y).round() as i32,
+            w: ((rect.width() as f32 * scale_x).round() as u32).min(full_width),
+            h: ((rect.height() as f32 * scale_y).round() as u32).min(full_height),
         })
         .collect()
 }
 
+/// Sensitivity of the motion crop, from `MOTION_PIXEL_THRESHOLD` and `MOTION_LINE_PERCENT`.
+#[derive(Clone, Copy, Debug)]
+struct MotionThresholds {
+    /// How much a pixel's brightness can change and still be considered "static noise".
+    pixel_change: u8,
+    /// If more than this fraction of pixels in a line have changed, the line is "motion".
+    line_motion_percent: f32,
+}
+
+impl MotionThresholds {
+    const DEFAULT: MotionThresholds = MotionThresholds { pixel_change: 15, line_motion_percent: 0.02 };
+
+    fn from_env() -> Self {
+        let mut line_motion_percent = env_parse("MOTION_LINE_PERCENT", Self::DEFAULT.line_motion_percent);
+        if !(0.0..1.0).contains(&line_motion_percent) {
+            log::warn!("MOTION_LINE_PERCENT must be a fraction between 0 and 1. Using the default.");
+            line_motion_percent = Self::DEFAULT.line_motion_percent;
+        }
+        MotionThresholds { pixel_change: env_parse("MOTION_PIXEL_THRESHOLD", Self::DEFAULT.pixel_change), line_motion_percent }
+    }
+}
+
 /// **FINAL, ROBUST METHOD**
 /// Compares two frames to find the content area by scanning inwards from the edges
 /// and stopping at the first sign of significant motion. This correctly ignores static bars.
-fn detect_motion_crop(frame_a_path: &Path, frame_b_path: &Path) -> Option<CropRect> {
+fn detect_motion_crop(frame_a_path: &Path, frame_b_path: &Path, thresholds: MotionThresholds) -> Option<CropRect> {
     log::info!("Starting robust motion detection by scanning from edges...");
+    let (area, width, height) = find_motion_area(frame_a_path, frame_b_path, thresholds)?;
+    finalize_motion_crop(area, width, height)
+}
+
+/// Runs motion detection across consecutive scene frames and unions the moving areas,
+/// so montage-style clips keep every region that shows content in at least one scene.
+fn detect_motion_crop_across_frames(frame_paths: &[PathBuf], thresholds: MotionThresholds) -> Option<CropRect> {
+    log::info!("Starting scene-sampled motion detection across {} frames...", frame_paths.len());
+    let mut union: Option<(CropRect, u32, u32)> = None;
+    for pair in frame_paths.windows(2) {
+        let Some((area, width, height)) = find_motion_area(&pair[0], &pair[1], thresholds) else { continue };
+        union = Some(match union {
+            Some((acc, acc_w, acc_h)) if (acc_w, acc_h) == (width, height) => {
+                let left = acc.x.min(area.x);
+                let top = acc.y.min(area.y);
+                let right = (acc.x + acc.w).max(area.x + area.w);
+                let bottom = (acc.y + acc.h).max(area.y + area.h);
+                (CropRect { x: left, y: top, w: right - left, h: bottom - top }, width, height)
+            }
+            Some(acc) => acc,
+            None => (area, width, height),
+        });
+    }
+    let (area, width, height) = union?;
+    finalize_motion_crop(area, width, height)
+}
+
+/// Scans two frames inwards from the edges and returns the raw moving area together with
+/// the frame dimensions. Returns `None` if the frames can't be compared or nothing moved.
+fn find_motion_area(frame_a_path: &Path, frame_b_path: &Path, thresholds: MotionThresholds) -> Option<(CropRect, u32, u32)> {
     let img_a = ImageReader::open(frame_a_path).ok()?.decode().ok()?.to_luma8();
     let img_b = ImageReader::open(frame_b_path).ok()?.decode().ok()?.to_luma8();
 
@@ -126,95 +650,166 @@ fn detect_motion_crop(frame_a_path: &Path, frame_b_path: &Path) -> Option<CropRe
 
     let (width, height) = blurred_a.dimensions();
 
-    // How much a pixel's brightness can change and still be considered "static noise".
-    const PIXEL_CHANGE_THRESHOLD: u8 = 15;
-    // If more than this percentage of pixels in a line have changed, the line is "motion".
-    const LINE_MOTION_PERCENT_THRESHOLD: f32 = 0.02; // 2%
-
-    let mut top_edge = 0;
-    for y in 0..height {
-        let mut changed_pixels = 0;
-        for x in 0..width {
-            let luma_a = blurred_a.get_pixel(x, y)[0] as i16;
-            let luma_b = blurred_b.get_pixel(x, y)[0] as i16;
-            if (luma_a - luma_b).abs() > PIXEL_CHANGE_THRESHOLD as i16 {
-                changed_pixels += 1;
-            }
-        }
-        if (changed_pixels as f32 / width as f32) > LINE_MOTION_PERCENT_THRESHOLD {
-            top_edge = y;
-            break;
-        }
-    }
-
-    let mut bottom_edge = height;
-    for y in (0..height).rev() {
-        let mut changed_pixels = 0;
-        for x in 0..width {
-            let luma_a = blurred_a.get_pixel(x, y)[0] as i16;
-            let luma_b = blurred_b.get_pixel(x, y)[0] as i16;
-            if (luma_a - luma_b).abs() > PIXEL_CHANGE_THRESHOLD as i16 {
-                changed_pixels += 1;
-            }
-        }
-        if (changed_pixels as f32 / width as f32) > LINE_MOTION_PERCENT_THRESHOLD {
-            bottom_edge = y;
-            break;
-        }
-    }
-
-    let mut left_edge = 0;
-    for x in 0..width {
-        let mut changed_pixels = 0;
-        for y in top_edge..bottom_edge {
-            let luma_a = blurred_a.get_pixel(x, y)[0] as i16;
-            let luma_b = blurred_b.get_pixel(x, y)[0] as i16;
-            if (luma_a - luma_b).abs() > PIXEL_CHANGE_THRESHOLD as i16 {
-                changed_pixels += 1;
-            }
-        }
-        if (changed_pixels as f32 / (bottom_edge - top_edge) as f32) > LINE_MOTION_PERCENT_THRESHOLD {
-            left_edge = x;
-            break;
-        }
-    }
+    let pixel_threshold = thresholds.pixel_change as i16;
+    let line_threshold = thresholds.line_motion_percent;
+    let pixel_changed = |x: u32, y: u32| {
+        let luma_a = blurred_a.get_pixel(x, y)[0] as i16;
+        let luma_b = blurred_b.get_pixel(x, y)[0] as i16;
+        (luma_a - luma_b).abs() > pixel_threshold
+    };
+    let row_moves = |y: u32| {
+        let changed_pixels = (0..width).filter(|&x| pixel_changed(x, y)).count();
+        (changed_pixels as f32 / width as f32) > line_threshold
+    };
+    let column_moves = |x: u32, top_edge: u32, bottom_edge: u32| {
+        let changed_pixels = (top_edge..bottom_edge).filter(|&y| pixel_changed(x, y)).count();
+        (changed_pixels as f32 / (bottom_edge - top_edge) as f32) > line_threshold
+    };
 
-    let mut right_edge = width;
-    for x in (0..width).rev() {
-        let mut changed_pixels = 0;
-        for y in top_edge..bottom_edge {
-            let luma_a = blurred_a.get_pixel(x, y)[0] as i16;
-            let luma_b = blurred_b.get_pixel(x, y)[0] as i16;
-            if (luma_a - luma_b).abs() > PIXEL_CHANGE_THRESHOLD as i16 {
-                changed_pixels += 1;
-            }
-        }
-        if (changed_pixels as f32 / (bottom_edge - top_edge) as f32) > LINE_MOTION_PERCENT_THRESHOLD {
-            right_edge = x;
-            break;
-        }
-    }
+    // The top and bottom scans are independent, and the left and right scans only need their
+    // result, so each pair runs on two threads.
+    let (top_edge, bottom_edge) = std::thread::scope(|scope| {
+        let top = scope.spawn(|| (0..height).find(|&y| row_moves(y)).unwrap_or(0));
+        let bottom_edge = (0..height).rev().find(|&y| row_moves(y)).unwrap_or(height);
+        (top.join().unwrap_or(0), bottom_edge)
+    });
+    let (left_edge, right_edge) = std::thread::scope(|scope| {
+        let left = scope.spawn(|| (0..width).find(|&x| column_moves(x, top_edge, bottom_edge)).unwrap_or(0));
+        let right_edge = (0..width).rev().find(|&x| column_moves(x, top_edge, bottom_edge)).unwrap_or(width);
+        (left.join().unwrap_or(0), right_edge)
+    });
 
     if top_edge >= bottom_edge || left_edge >= right_edge {
         log::warn!("No consistent motion area found. Skipping crop.");
         return None;
     }
 
-    let crop_x = left_edge;
-    let crop_y = top_edge;
-    let crop_w = right_edge.saturating_sub(left_edge);
-    let crop_h = bottom_edge.saturating_sub(top_edge);
+    let area = CropRect {
+        x: left_edge,
+        y: top_edge,
+        w: right_edge.saturating_sub(left_edge),
+        h: bottom_edge.saturating_sub(top_edge),
+    };
+    Some((area, width, height))
+}
 
-    if (width.saturating_sub(crop_w) < 10) && (height.saturating_sub(crop_h) < 10) {
+/// Drops negligible crops and rounds the crop size up to even values for the encoder.
+fn finalize_motion_crop(area: CropRect, width: u32, height: u32) -> Option<CropRect> {
+    if (width.saturating_sub(area.w) < 10) && (height.saturating_sub(area.h) < 10) {
         log::info!("Crop area is negligible. Skipping crop.");
         return None;
     }
 
-    let final_w = if crop_w % 2 != 0 { crop_w.saturating_add(1).min(width) } else { crop_w };
-    let final_h = if crop_h % 2 != 0 { crop_h.saturating_add(1).min(height) } else { crop_h };
+    let final_w = if !area.w.is_multiple_of(2) { area.w.saturating_add(1).min(width) } else { area.w };
+    let final_h = if !area.h.is_multiple_of(2) { area.h.saturating_add(1).min(height) } else { area.h };
+
+    log::info!("Robust motion crop detected: x={}, y={}, w={}, h={}", area.x, area.y, final_w, final_h);
+    Some(CropRect { x: area.x, y: area.y, w: final_w, h: final_h })
+}
+
+
+// --- Retry Helpers ---
+
+#[derive(Debug, Clone, Copy)]
+struct BackoffConfig {
+    base: Duration,
+    max: Duration,
+    factor: f64,
+    /// Fraction of each delay that is randomized away, from 0.0 (none) to 1.0 (full jitter).
+    jitter: f64,
+    max_attempts: u32,
+}
+
+/// Backoff for reconnecting to the database at startup.
+const DB_CONNECT_BACKOFF: BackoffConfig = BackoffConfig {
+    base: Duration::from_millis(500), max: Duration::from_secs(10), factor: 2.0, jitter: 0.2, max_attempts: 5,
+};
+
+/// Backoff for yt-dlp downloads, which mostly fail on transient network errors.
+const DOWNLOAD_BACKOFF: BackoffConfig = BackoffConfig {
+    base: Duration::from_secs(2), max: Duration::from_secs(10), factor: 2.0, jitter: 0.2, max_attempts: 3,
+};
+
+impl BackoffConfig {
+    /// Delay before retry number `retry` (0-based), given a uniform `sample` in [0, 1).
+    fn delay(&self, retry: u32, sample: f64) -> Duration {
+        let exponential = self.base.as_secs_f64() * self.factor.powi(retry as i32);
+        let capped = exponential.min(self.max.as_secs_f64());
+        Duration::from_secs_f64((capped * (1.0 - self.jitter * sample)).max(0.0))
+    }
+}
+
+/// A uniform sample in [0, 1) for jitter. `RandomState` is randomly keyed, so this avoids
+/// pulling in a dedicated RNG crate.
+fn jitter_sample() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let bits = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Runs `operation` until it succeeds or `config.max_attempts` is reached, sleeping with
+/// exponential backoff and jitter between attempts. Returns the last error on exhaustion.
+async fn retry_with_backoff<T, E, F, Fut>(config: &BackoffConfig, operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    retry_with_backoff_if(config, |_| true, operation).await
+}
+
+/// Like `retry_with_backoff`, but gives up at once on errors `is_transient` rejects.
+async fn retry_with_backoff_if<T, E, F, Fut>(config: &BackoffConfig, is_transient: impl Fn(&E) -> bool, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= config.max_attempts || !is_transient(&e) => return Err(e),
+            Err(e) => {
+                let delay = config.delay(attempt - 1, jitter_sample());
+                log::warn!("Attempt {}/{} failed: {}. Retrying in {:.1}s.", attempt, config.max_attempts, e, delay.as_secs_f64());
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
 
-    log::info!("Robust motion crop detected: x={}, y={}, w={}, h={}", crop_x, crop_y, final_w, final_h);
-    Some(CropRect { x: crop_x, y: crop_y, w: final_w, h: final_h })
+/// Backoff for uploads that fail to connect. Rate limits wait for the delay Telegram asks for instead.
+const UPLOAD_BACKOFF: BackoffConfig = BackoffConfig {
+    base: Duration::from_secs(2), max: Duration::from_secs(15), factor: 2.0, jitter: 0.2, max_attempts: 3,
+};
+
+/// Runs an upload, retrying on Telegram 429s and on connection failures. Other errors are returned
+/// at once. Timeouts are deliberately not retried: Telegram may already have accepted the upload,
+/// and sending it again would post the message twice.
+async fn retry_upload<T, F, Fut>(mut operation: F) -> Result<T, teloxide::RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, teloxide::RequestError>>,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let delay = match &error {
+            _ if attempt >= UPLOAD_BACKOFF.max_attempts => return Err(error),
+            teloxide::RequestError::RetryAfter(after) => *after,
+            // A failed connect means the request never reached Telegram, so it is safe to resend.
+            teloxide::RequestError::Network(e) if e.is_connect() => UPLOAD_BACKOFF.delay(attempt - 1, jitter_sample()),
+            _ => return Err(error),
+        };
+        log::warn!("{}Upload attempt {}/{} failed: {}. Retrying in {:.1}s.", job_tag(), attempt, UPLOAD_BACKOFF.max_attempts, error, delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
 }
 
 
@@ -226,10 +821,49 @@ async fn main() {
     log::info!("Starting video saver bot...");
     dotenv::dotenv().expect("Failed to read .env file");
     let bot = Bot::from_env();
+    detect_encoder_support().await;
+    let config = match Config::from_env() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            log::error!("{} Set it to a .ttf or .otf font file.", e);
+            std::process::exit(1);
+        }
+    };
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = SqlitePool::connect(&database_url).await.expect("Failed to connect to database");
+    let database_url = database_url.as_str();
+    let pool = retry_with_backoff(&DB_CONNECT_BACKOFF, || SqlitePool::connect(database_url)).await
+        .expect("Failed to connect to database");
     sqlx::query(r#"CREATE TABLE IF NOT EXISTS videos (file_id TEXT PRIMARY KEY NOT NULL, caption TEXT NOT NULL, user_id INTEGER NOT NULL)"#)
         .execute(&pool).await.expect("Failed to create database table");
+    ensure_video_column(&pool, "caption_norm", "TEXT").await.expect("Failed to add caption_norm column");
+    ensure_video_column(&pool, "kind", "TEXT NOT NULL DEFAULT 'video'").await.expect("Failed to add kind column");
+    ensure_video_column(&pool, "tags", "TEXT").await.expect("Failed to add tags column");
+    ensure_video_column(&pool, "thumb_file_id", "TEXT").await.expect("Failed to add thumb_file_id column");
+    ensure_video_column(&pool, "use_count", "INTEGER NOT NULL DEFAULT 0").await.expect("Failed to add use_count column");
+    ensure_video_column(&pool, "source_url", "TEXT").await.expect("Failed to add source_url column");
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_videos_caption_norm ON videos (caption_norm)")
+        .execute(&pool).await.expect("Failed to create caption index");
+    backfill_caption_norm(&pool).await.expect("Failed to backfill normalized captions");
+    ensure_video_column(&pool, "caption_folded", "TEXT").await.expect("Failed to add caption_folded column");
+    backfill_caption_folded(&pool).await.expect("Failed to backfill accent-folded captions");
+    ensure_video_column(&pool, "caption_translit", "TEXT").await.expect("Failed to add caption_translit column");
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_videos_caption_translit ON videos (caption_translit)")
+        .execute(&pool).await.expect("Failed to create transliterated caption index");
+    if config.transliteration {
+        backfill_caption_translit(&pool).await.expect("Failed to backfill transliterated captions");
+    }
+    sqlx::query(r#"CREATE TABLE IF NOT EXISTS variants (file_unique_id TEXT PRIMARY KEY NOT NULL, file_id TEXT NOT NULL, source_file_id TEXT NOT NULL, kind TEXT NOT NULL)"#)
+        .execute(&pool).await.expect("Failed to create variants table");
+    sqlx::query(r#"CREATE TABLE IF NOT EXISTS jobs (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id INTEGER NOT NULL, kind TEXT NOT NULL, duration_ms INTEGER NOT NULL, finished_at INTEGER NOT NULL)"#)
+        .execute(&pool).await.expect("Failed to create jobs table");
+    sqlx::query(r#"CREATE TABLE IF NOT EXISTS failed_jobs (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id INTEGER NOT NULL, job TEXT NOT NULL, created_at INTEGER NOT NULL)"#)
+        .execute(&pool).await.expect("Failed to create failed jobs table");
+    sqlx::query(r#"CREATE TABLE IF NOT EXISTS user_settings (user_id INTEGER NOT NULL, key TEXT NOT NULL, value TEXT NOT NULL, PRIMARY KEY (user_id, key))"#)
+        .execute(&pool).await.expect("Failed to create user settings table");
+    sqlx::query(r#"CREATE TABLE IF NOT EXISTS allowed_chats (chat_id INTEGER PRIMARY KEY NOT NULL)"#)
+        .execute(&pool).await.expect("Failed to create allowed chats table");
+    sqlx::query(r#"CREATE TABLE IF NOT EXISTS settings (key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL)"#)
+        .execute(&pool).await.expect("Failed to create settings table");
 
     let handler = dptree::entry()
         .branch(Update::filter_message().filter_command::<Command>().endpoint(handle_command))
@@ -238,566 +872,3714 @@ async fn main() {
         .branch(Update::filter_callback_query().endpoint(handle_callback_query))
         .branch(Update::filter_message().endpoint(handle_message));
 
-    Dispatcher::builder(bot, handler).dependencies(dptree::deps![pool]).enable_ctrlc_handler().build().dispatch().await;
+    let mut http_servers: HashMap<u16, HttpRoutes> = HashMap::new();
+    if let Some(port) = env::var("HEALTHCHECK_PORT").ok().and_then(|p| p.trim().parse::<u16>().ok()) {
+        let telegram_ready = match bot.get_me().await {
+            Ok(_) => true,
+            Err(e) => { log::error!("get_me failed at startup: {}", e); false }
+        };
+        http_servers.entry(port).or_default().health = Some((pool.clone(), telegram_ready));
+    }
+    if let Some(port) = env::var("METRICS_PORT").ok().and_then(|p| p.trim().parse::<u16>().ok()) {
+        if let Some(handle) = install_metrics_recorder() {
+            http_servers.entry(port).or_default().metrics = Some(handle);
+        }
+    }
+    for (port, routes) in http_servers {
+        tokio::spawn(serve_http(port, routes));
+    }
+
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler).dependencies(dptree::deps![pool, config.clone()]).enable_ctrlc_handler().build();
+    let shutdown_token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        let Ok(mut sigterm) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) else {
+            log::error!("Failed to install the SIGTERM handler.");
+            return;
+        };
+        sigterm.recv().await;
+        log::info!("Received SIGTERM, no longer accepting updates.");
+        if let Ok(stopped) = shutdown_token.shutdown() {
+            stopped.await;
+        }
+    });
+    // Webhook mode: set `WEBHOOK_URL` to the public https URL the reverse proxy forwards to this
+    // bot, and optionally `WEBHOOK_PORT` (default 8443) for the local listener. Unset keeps long polling.
+    match env::var("WEBHOOK_URL").ok().filter(|url| !url.trim().is_empty()) {
+        Some(url) => {
+            let port: u16 = env_parse("WEBHOOK_PORT", 8443);
+            let options = teloxide::update_listeners::webhooks::Options::new(([0, 0, 0, 0], port).into(), url.trim().parse().expect("WEBHOOK_URL must be a valid URL"));
+            let listener = teloxide::update_listeners::webhooks::axum(bot.clone(), options).await.expect("Failed to set up the webhook");
+            log::info!("Receiving updates through the webhook on port {}.", port);
+            dispatcher.dispatch_with_listener(listener, LoggingErrorHandler::with_custom_text("An error from the webhook listener")).await;
+        }
+        None => dispatcher.dispatch().await,
+    }
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    drain_jobs(&bot, config.shutdown_timeout).await;
 }
 
-/// Helper function to format seconds into H:MM:SS.cs for ASS subtitles.
-fn format_ass_time(seconds: f64) -> String {
-    let hours = (seconds / 3600.0).floor();
-    let minutes = ((seconds % 3600.0) / 60.0).floor();
-    let secs = (seconds % 60.0).floor();
-    let centiseconds = ((seconds.fract() * 100.0).round() as i32).min(99);
-    format!("{}:{:02}:{:02}.{:02}", hours, minutes, secs, centiseconds)
+/// Adds a column to `videos` if it doesn't exist yet, so startup migrations are idempotent.
+async fn ensure_video_column(pool: &SharedState, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info('videos')").fetch_all(pool).await?;
+    if !columns.iter().any(|c| c == column) {
+        log::info!("Migrating database: adding videos.{}", column);
+        sqlx::query(&format!("ALTER TABLE videos ADD COLUMN {} {}", column, definition)).execute(pool).await?;
+    }
+    Ok(())
+}
+
+/// Size of the database file in bytes, as SQLite sees it.
+async fn database_size(conn: &mut sqlx::SqliteConnection) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()")
+        .fetch_one(conn).await
+}
+
+/// Runs `VACUUM` and `PRAGMA optimize` on a dedicated connection. VACUUM needs the database to
+/// itself, so the connection waits up to `busy_timeout` for in-flight queries instead of failing
+/// right away. Returns the size before and after, and how long the VACUUM took.
+async fn vacuum_database(pool: &SharedState) -> Result<(i64, i64, Duration), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("PRAGMA busy_timeout = 30000").execute(&mut *conn).await?;
+    let before = database_size(&mut conn).await?;
+    let started = Instant::now();
+    sqlx::query("VACUUM").execute(&mut *conn).await?;
+    let elapsed = started.elapsed();
+    sqlx::query("PRAGMA optimize").execute(&mut *conn).await?;
+    let after = database_size(&mut conn).await?;
+    Ok((before, after, elapsed))
 }
 
-fn configure_ffmpeg_encoder(command: &mut tokio::process::Command) {
-    if env::var("BAD_HARDWARE").is_ok_and(|v| v == "1") {
-        log::info!("BAD_HARDWARE flag detected. Using CPU-optimized FFMPEG settings.");
-        command.arg("-c:v").arg("libx264")
-               .arg("-preset").arg("ultrafast")
-               .arg("-crf").arg("26")
-               .arg("-threads").arg("4");
+fn format_size(bytes: i64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
     } else {
-        let encoder = env::var("FFMPEG_ENCODER").unwrap_or_default();
-        if !encoder.is_empty() {
-            command.arg("-c:v").arg(&encoder);
-        } else if env::var("CUDA_ENABLED").is_ok() {
-            command.arg("-c:v").arg("h264_nvenc")
-                   .arg("-preset").arg("p7")
-                   .arg("-rc").arg("vbr")
-                   .arg("-gpu").arg("0");
-        } else {
-            command.arg("-c:v").arg("libx264")
-                   .arg("-preset").arg("ultrafast");
-        }
+        format!("{:.1} KB", bytes as f64 / 1024.0)
     }
 }
 
-// --- Background Video Editing Task ---
+/// Fills `caption_norm` for rows saved before the column existed.
+async fn backfill_caption_norm(pool: &SharedState) -> Result<(), sqlx::Error> {
+    let rows: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption FROM videos WHERE caption_norm IS NULL")
+        .fetch_all(pool).await?;
+    if !rows.is_empty() {
+        log::info!("Backfilling normalized captions for {} videos.", rows.len());
+    }
+    for row in rows {
+        sqlx::query("UPDATE videos SET caption_norm = ? WHERE file_id = ?")
+            .bind(normalize_caption(&row.caption)).bind(&row.file_id).execute(pool).await?;
+    }
+    Ok(())
+}
 
-async fn perform_video_edit(bot: Bot, user_id: UserId, inline_message_id: String, file_id: String, text_parts: String) {
-    let temp_dir = match Builder::new().prefix("video_edit").tempdir() {
-        Ok(dir) => dir,
-        Err(e) => { log::error!("Failed to create temp dir: {}", e); return; }
-    };
-    let temp_dir_path = temp_dir.path();
-    let input_path = temp_dir_path.join("input.mp4");
-    let cropped_path = temp_dir_path.join("cropped.mp4");
-    let output_path = temp_dir_path.join("output.mp4");
-    let frame_path = temp_dir_path.join("frame.png");
+/// Fills `caption_folded` for rows saved before the column existed.
+async fn backfill_caption_folded(pool: &SharedState) -> Result<(), sqlx::Error> {
+    let rows: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption FROM videos WHERE caption_folded IS NULL")
+        .fetch_all(pool).await?;
+    if !rows.is_empty() {
+        log::info!("Backfilling accent-folded captions for {} videos.", rows.len());
+    }
+    for row in rows {
+        sqlx::query("UPDATE videos SET caption_folded = ? WHERE file_id = ?")
+            .bind(caption_folded(&row.caption)).bind(&row.file_id).execute(pool).await?;
+    }
+    Ok(())
+}
 
-    let Ok(file) = bot.get_file(&file_id).await else { return };
-    let Ok(mut dest) = fs::File::create(&input_path).await else { return };
-    if bot.download_file(&file.path, &mut dest).await.is_err() { return };
+/// Fills `caption_translit` for rows saved while transliteration was off.
+async fn backfill_caption_translit(pool: &SharedState) -> Result<(), sqlx::Error> {
+    let rows: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption FROM videos WHERE caption_translit IS NULL")
+        .fetch_all(pool).await?;
+    if !rows.is_empty() {
+        log::info!("Backfilling transliterated captions for {} videos.", rows.len());
+    }
+    for row in rows {
+        sqlx::query("UPDATE videos SET caption_translit = ? WHERE file_id = ?")
+            .bind(transliterate(&normalize_caption(&row.caption))).bind(&row.file_id).execute(pool).await?;
+    }
+    Ok(())
+}
 
-    // --- Start of Crop Detection and Cropping ---
-    let mut crop_command_output = tokio::process::Command::new("ffmpeg")
-        .arg("-i").arg(&input_path)
-        .arg("-vf").arg("cropdetect=24:16:0")
-        .arg("-f").arg("null")
-        .arg("-")
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn ffmpeg cropdetect");
+/// Romanizes Cyrillic (Russian, Ukrainian and Belarusian letters) in already normalized,
+/// lowercase text. Other characters pass through unchanged.
+fn transliterate(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        let latin = match c {
+            'а' => "a", 'б' => "b", 'в' => "v", 'г' => "g", 'ґ' => "g", 'д' => "d", 'е' => "e", 'ё' => "e",
+            'є' => "ye", 'ж' => "zh", 'з' => "z", 'и' => "i", 'і' => "i", 'ї' => "yi", 'й' => "y", 'к' => "k",
+            'л' => "l", 'м' => "m", 'н' => "n", 'о' => "o", 'п' => "p", 'р' => "r", 'с' => "s", 'т' => "t",
+            'у' => "u", 'ў' => "u", 'ф' => "f", 'х' => "kh", 'ц' => "ts", 'ч' => "ch", 'ш' => "sh", 'щ' => "shch",
+            'ъ' | 'ь' => "", 'ы' => "y", 'э' => "e", 'ю' => "yu", 'я' => "ya",
+            _ => { out.push(c); continue; }
+        };
+        out.push_str(latin);
+    }
+    out
+}
 
-    let mut crop_rect = None;
-    if let Some(stderr) = crop_command_output.stderr.take() {
-        let mut reader = tokio::io::BufReader::new(stderr).lines();
-        while let Ok(Some(line)) = reader.next_line().await {
-            if line.contains("crop=") {
-                let parts: Vec<&str> = line.split("crop=").collect();
-                if parts.len() > 1 {
-                    let crop_values: Vec<&str> = parts[1].split(':').collect();
-                    if crop_values.len() == 4 {
-                        let w = crop_values[0].parse::<u32>().unwrap_or(0);
-                        let h = crop_values[1].parse::<u32>().unwrap_or(0);
-                        let x = crop_values[2].parse::<u32>().unwrap_or(0);
-                        let y = crop_values[3].parse::<u32>().unwrap_or(0);
-                        crop_rect = Some(CropRect { w, h, x, y });
-                    }
-                }
-            }
-        }
+/// The `caption_translit` value to store for a caption, or `None` while transliteration is off.
+fn caption_translit(caption: &str, config: &Config) -> Option<String> {
+    config.transliteration.then(|| transliterate(&normalize_caption(caption)))
+
+}
+
+/// Normalizes a caption for search and dedup: trimmed, lowercased, whitespace collapsed.
+fn normalize_caption(caption: &str) -> String {
+    caption.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Strips accents from common Latin letters (é → e, ß → ss) so search ignores them.
+fn fold_accents(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        let plain = match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ą' => "a", 'æ' => "ae", 'ç' | 'ć' | 'č' => "c", 'ď' => "d",
+            'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ę' | 'ě' => "e", 'ğ' => "g", 'ì' | 'í' | 'î' | 'ï' | 'ī' | 'ı' => "i",
+            'ł' => "l", 'ñ' | 'ń' | 'ň' => "n", 'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => "o", 'œ' => "oe",
+            'ř' => "r", 'ś' | 'š' | 'ş' => "s", 'ß' => "ss", 'ť' | 'ţ' => "t", 'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' => "u",
+            'ý' | 'ÿ' => "y", 'ź' | 'ż' | 'ž' => "z",
+            _ => { out.push(c); continue; }
+        };
+        out.push_str(plain);
     }
+    out
+}
 
-    let mut processed_video_path = input_path.clone();
+/// The `caption_folded` value to store for a caption: normalized with accents folded away.
+fn caption_folded(caption: &str) -> String {
+    fold_accents(&normalize_caption(caption))
+}
 
-    if let Some(crop) = crop_rect {
-        let crop_filter = format!("crop={}:{}:{}:{}", crop.w, crop.h, crop.x, crop.y);
-        let crop_status = tokio::process::Command::new("ffmpeg")
-            .arg("-i").arg(&input_path)
-            .arg("-vf").arg(crop_filter)
-            .arg("-c:a").arg("copy")
-            .arg(&cropped_path)
-            .status().await;
-
-        if crop_status.is_ok() {
-            processed_video_path = cropped_path;
+/// Levenshtein distance between two short words.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
         }
+        previous = current;
     }
-    // --- End of Crop Detection and Cropping ---
+    previous[b.len()]
+}
 
-    let ffprobe_output = match tokio::process::Command::new("ffprobe")
-        .arg("-v").arg("error")
-        .arg("-select_streams").arg("v:0")
-        .arg("-show_entries").arg("stream=width,height")
-        .arg("-of").arg("csv=p=0:s=x")
-        .arg(&processed_video_path)
-        .output().await {
-            Ok(out) => out,
-            Err(e) => {
-                log::error!("ffprobe failed: {}", e);
-                bot.edit_message_text_inline(&inline_message_id, "❌ Error: Could not analyze video dimensions.").await.ok();
-                return;
+/// Scores how well a folded caption matches a folded query, or `None` for no match. Every query
+/// word has to appear in the caption, in any order, either as a substring or as a word within a
+/// small typo distance. An in-order substring match of the whole query ranks highest.
+fn fuzzy_caption_score(query: &str, caption: &str) -> Option<u32> {
+    let mut score = match caption.find(query) {
+        Some(0) => 300,
+        Some(_) => 200,
+        None => 0,
+    };
+    let caption_words: Vec<Vec<char>> = caption.split_whitespace().map(|w| w.chars().collect()).collect();
+    for word in query.split_whitespace() {
+        if caption_words.iter().any(|w| w.iter().collect::<String>() == word) {
+            score += 30;
+        } else if caption.contains(word) {
+            score += 20;
+        } else {
+            let word: Vec<char> = word.chars().collect();
+            let allowed = allowed_typos(word.len());
+            let best = caption_words.iter().map(|w| edit_distance(&word, w)).min()?;
+            if best > allowed {
+                return None;
             }
-        };
+            score += 10 - best as u32 * 3;
+        }
+    }
+    Some(score)
+}
 
-    let dims: Vec<u32> = String::from_utf8(ffprobe_output.stdout).unwrap_or_default().trim()
-        .split('x').filter_map(|s| s.parse().ok()).collect();
-    let (width, height) = if dims.len() == 2 { (dims[0], dims[1]) } else { (0,0) };
-    if width == 0 || height == 0 {
-        bot.edit_message_text_inline(&inline_message_id, "❌ Error: Could not determine video dimensions.").await.ok();
-        return;
+/// How many typos `fuzzy_caption_score` forgives in a query word of `len` chars.
+fn allowed_typos(len: usize) -> usize {
+    match len { 0..=3 => 0, 4..=6 => 1, _ => 2 }
+}
+
+/// Splits a query word into one more piece than it's allowed typos. Each typo touches at most
+/// one piece, so any caption word close enough to match still contains one of them verbatim.
+fn fuzzy_word_fragments(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let pieces = allowed_typos(chars.len()) + 1;
+    (0..pieces).map(|i| chars[i * chars.len() / pieces..(i + 1) * chars.len() / pieces].iter().collect()).collect()
+}
+
+/// An SQL condition that every word of `query` has a fragment in `column`, plus its LIKE patterns.
+fn fuzzy_prefilter_sql(column: &str, query: &str) -> (String, Vec<String>) {
+    let mut patterns = vec![];
+    let words: Vec<String> = query.split_whitespace().map(|word| {
+        let fragments = fuzzy_word_fragments(word);
+        patterns.extend(fragments.iter().map(|fragment| format!("%{}%", escape_like_pattern(fragment))));
+        let alternatives = vec![format!("{} LIKE ? ESCAPE '\\'", column); fragments.len()];
+        format!("({})", alternatives.join(" OR "))
+    }).collect();
+    if words.is_empty() { ("1".to_string(), patterns) } else { (format!("({})", words.join(" AND ")), patterns) }
+}
+
+/// Ranks the videos `owner` can see against `query` best-first and returns one page of them plus
+/// one extra row, so callers can tell whether there's a next page with `take_page`. SQL first
+/// narrows the videos down to those that can match at all, then they're scored off the runtime.
+async fn fuzzy_search_videos(pool: &SharedState, query: &str, owner: Option<i64>, offset: i64, config: &Config) -> Vec<VideoData> {
+    let folded_query = fold_accents(&normalize_caption(query));
+    let translit_query = config.transliteration.then(|| transliterate(&folded_query));
+
+    let (mut condition, mut patterns) = fuzzy_prefilter_sql("caption_folded", &folded_query);
+    if let Some(translit_query) = &translit_query {
+        let (translit_condition, translit_patterns) = fuzzy_prefilter_sql("caption_translit", translit_query);
+        condition = format!("({} OR {})", condition, translit_condition);
+        patterns.extend(translit_patterns);
+    }
+    let sql = format!(
+        "SELECT file_id, caption, kind, tags, caption_translit FROM videos WHERE (? IS NULL OR user_id = ?) AND {} ORDER BY rowid DESC LIMIT ?",
+        condition);
+    let mut candidates_query = sqlx::query_as::<_, (String, String, String, Option<String>, Option<String>)>(&sql).bind(owner).bind(owner);
+    for pattern in patterns {
+        candidates_query = candidates_query.bind(pattern);
     }
+    let candidates = candidates_query.bind(config.fuzzy_candidate_limit).fetch_all(pool).await.unwrap_or_default();
+
+    tokio::task::spawn_blocking(move || {
+        let mut ranked: Vec<(u32, VideoData)> = candidates.into_iter().filter_map(|(file_id, caption, kind, tags, translit)| {
+            let direct = fuzzy_caption_score(&folded_query, &fold_accents(&normalize_caption(&caption)));
+            let via_translit = translit_query.as_deref().zip(translit.as_deref())
+                .and_then(|(query, translit)| fuzzy_caption_score(query, &fold_accents(translit)));
+            let score = direct.max(via_translit)?;
+            Some((score, VideoData { caption, file_id, kind, tags }))
+        }).collect();
+        // Stable, so equally good matches stay newest-first.
+        ranked.sort_by_key(|(score, _)| Reverse(*score));
+        ranked.into_iter().skip(offset as usize).take(INLINE_PAGE_SIZE as usize + 1).map(|(_, video)| video).collect()
+    }).await.unwrap_or_default()
+}
 
-    let frame_extraction_status = tokio::process::Command::new("ffmpeg")
-        .arg("-i").arg(&processed_video_path).arg("-vframes").arg("1").arg("-y").arg(&frame_path).status().await.ok();
-    if frame_extraction_status.is_none() || !frame_extraction_status.unwrap().success() {
-        bot.edit_message_text_inline(&inline_message_id, "❌ Error: Failed to extract frame.").await.ok();
-        return;
+/// Escapes `%`, `_` and `\` so user text matches literally in a `LIKE ? ESCAPE '\'` pattern.
+fn escape_like_pattern(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
+}
 
-    let detected_boxes = detect_white_or_black_boxes(&frame_path);
-    let messages: Vec<&str> = text_parts.split("///").collect();
-    let font_path_str = std::env::var("UNIVERSAL_FONT_PATH").expect("UNIVERSAL_FONT_PATH must be set in .env");
-    let font_path = PathBuf::from(&font_path_str);
-    let font_name = font_path.file_stem().and_then(|s| s.to_str()).unwrap_or("Noto Sans");
+/// Telegram's media caption limit, counted in UTF-16 code units.
+const MAX_CAPTION_LEN: usize = 1024;
 
-    let ass_content: String;
-    let mut preliminary_filters: Vec<String> = vec![];
-    let mut final_map_tag = "[0:v]".to_string();
+/// Cuts a caption down to Telegram's limit on a char boundary, ending it with an ellipsis.
+/// Sends with a longer caption are rejected outright.
+fn truncate_caption(caption: &str) -> String {
+    if caption.encode_utf16().count() <= MAX_CAPTION_LEN {
+        return caption.to_string();
+    }
+    let mut truncated = String::new();
+    let mut len = 0;
+    for c in caption.chars() {
+        // Leave room for the one-unit ellipsis.
+        if len + c.len_utf16() > MAX_CAPTION_LEN - 1 {
+            break;
+        }
+        len += c.len_utf16();
+        truncated.push(c);
+    }
+    log::info!("Truncated a {}-char caption to Telegram's {}-char limit.", caption.chars().count(), MAX_CAPTION_LEN);
+    truncated.push('…');
+    truncated
+}
 
-    let is_timed_edit = messages.len() == 3 && messages[1].parse::<f64>().is_ok();
+/// The user's own `/captiontemplate` if they set one, otherwise the global `CAPTION_TEMPLATE`.
+async fn caption_template_for(pool: &SharedState, user_id: UserId, config: &Config) -> Option<String> {
+    get_user_setting(pool, user_id, SETTING_CAPTION_TEMPLATE).await
+        .filter(|t| !t.trim().is_empty())
+        .or_else(|| config.caption_template.clone())
+}
 
-    if is_timed_edit {
-        let text1 = messages[0].trim();
-        let time_s = messages[1].parse::<f64>().unwrap_or(0.0);
-        let text2 = messages[2].trim();
+/// Applies a caption template for display. Supports `{caption}`, `{date}` and `{user}`.
+/// Only what is sent is templated; the stored caption stays raw.
+fn render_caption_template(template: Option<&str>, caption: &str, user_name: &str) -> String {
+    match template {
+        Some(template) => template
+            .replace("{caption}", caption)
+            .replace("{date}", &today_utc())
+            .replace("{user}", user_name),
+        None => caption.to_string(),
+    }
+}
 
-        let end_time1_str = format_ass_time(time_s);
-        let start_time2_str = format_ass_time(time_s);
-        let ass_safe_text1 = text1.replace('{', "\\{").replace('}', "\\}");
-        let ass_safe_text2 = text2.replace('{', "\\{").replace('}', "\\}");
+/// "@username" if the user has one, otherwise their full name.
+fn user_display_name(user: &User) -> String {
+    match &user.username {
+        Some(username) => format!("@{}", username),
+        None => user.full_name(),
+    }
+}
 
-        if let Some(bbox) = detected_boxes.get(0) {
-            let current_tag = "[v_box]".to_string();
-            let filter = format!(
-                "{last_tag}drawbox=x={x}:y={y}:w={w}:h={h}:color=white:t=fill{out}",
-                last_tag = &final_map_tag,
-                x = bbox.x, y = bbox.y, w = bbox.w, h = bbox.h, out = &current_tag
-            );
-            preliminary_filters.push(filter);
-            final_map_tag = current_tag;
+/// Today's UTC date as YYYY-MM-DD.
+fn today_utc() -> String {
+    let days = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400).unwrap_or(0) as i64;
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
 
-            let font_size = (bbox.h as f32 * 0.3).max(20.0) as u32;
-            let margin_l = bbox.x;
-            let margin_r = width as i32 - (bbox.x + bbox.w as i32);
-            let margin_v = bbox.y + (bbox.h as f32 * 0.1) as i32;
+/// Helper function to format seconds into H:MM:SS.cs for ASS subtitles.
+fn format_ass_time(seconds: f64) -> String {
+    let hours = (seconds / 3600.0).floor();
+    let minutes = ((seconds % 3600.0) / 60.0).floor();
+    let secs = (seconds % 60.0).floor();
+    let centiseconds = ((seconds.fract() * 100.0).round() as i32).min(99);
+    format!("{}:{:02}:{:02}.{:02}", hours, minutes, secs, centiseconds)
+}
 
-            let event1 = format!(
-                r#"Dialogue: 0,0:00:00.00,{end_time},BoxStyle,,{ml},{mr},{mv},,{{\fs{fs}}}{text}"#,
-                end_time = end_time1_str, ml = margin_l, mr = margin_r, mv = margin_v, fs = font_size, text = ass_safe_text1
-            );
-            let event2 = format!(
-                r#"Dialogue: 0,{start_time},9:59:59.99,BoxStyle,,{ml},{mr},{mv},,{{\fs{fs}}}{text}"#,
-                start_time = start_time2_str, ml = margin_l, mr = margin_r, mv = margin_v, fs = font_size, text = ass_safe_text2
-            );
+/// One timed segment returned by the speech-to-text endpoint.
+#[derive(Debug, serde::Deserialize)]
+struct TranscriptSegment { start: f64, end: f64, text: String }
 
-            ass_content = format!(
-                r#"[Script Info]
-PlayResX: {width}
-PlayResY: {height}
-[V4+ Styles]
-Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
-Style: BoxStyle,{font_name},100,&H00000000,&H000000FF,&H00FFFFFF,&H00FFFFFF,0,0,0,0,100,100,0,0,1,0,0,8,10,10,10,1
-[Events]
-Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
-{event1}
-{event2}"#,
-                width = width, height = height, font_name = font_name, event1 = event1, event2 = event2
-            );
-        } else {
-            let pad_height = (height as f32 * 0.15).max(100.0) as u32;
-            let font_size = (pad_height as f32 * 0.4).max(30.0) as u32;
-            let v_margin = (pad_height as f32 * 0.25) as u32;
-            let padded_tag = "[padded_v]".to_string();
-            preliminary_filters.push(format!("[0:v]pad=width=in_w:height=in_h+{pad}:x=0:y={pad}:color=black{out}", pad = pad_height, out = &padded_tag));
-            final_map_tag = padded_tag;
+#[derive(Debug, serde::Deserialize)]
+struct TranscriptResponse { segments: Vec<TranscriptSegment> }
 
-            ass_content = format!(
-                r#"[Script Info]
-PlayResX: {width}
-PlayResY: {height}
-[V4+ Styles]
-Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
-Style: Caption,{font_name},{font_size},&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,1,8,10,10,{v_margin},1
-[Events]
-Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
-Dialogue: 0,0:00:00.00,{end_time1},Caption,,0,0,0,,{text1}
-Dialogue: 0,{start_time2},9:59:59.99,Caption,,0,0,0,,{text2}"#,
-                width = width, height = height + pad_height, font_name = font_name, font_size = font_size, v_margin = v_margin,
-                end_time1 = end_time1_str, start_time2 = start_time2_str, text1 = ass_safe_text1, text2 = ass_safe_text2
-            );
+/// Sends the clip's audio to `TRANSCRIBE_URL` as a multipart `file` upload and returns the
+/// timed segments. The endpoint is expected to answer with `{"segments": [{"start", "end", "text"}]}`.
+async fn transcribe_audio(input_path: &Path, temp_dir_path: &Path, config: &Config) -> Result<Vec<TranscriptSegment>, String> {
+    let Some(url) = &config.transcribe_url else {
+        return Err("❌ Error: Transcription is not enabled on this bot.".to_string());
+    };
+    let audio_path = temp_dir_path.join("speech.wav");
+    let extract_status = tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(input_path).arg("-vn").arg("-ac").arg("1").arg("-ar").arg("16000")
+        .arg("-y").arg(&audio_path)
+        .status().await;
+    if !extract_status.is_ok_and(|s| s.success()) {
+        return Err("❌ Error: This video has no audio to transcribe.".to_string());
+    }
+
+    let audio = fs::read(&audio_path).await
+        .map_err(|e| { log::error!("Failed to read extracted audio: {}", e); "❌ Error: Transcription request failed.".to_string() })?;
+    let form = reqwest::multipart::Form::new()
+        .part("file", reqwest::multipart::Part::bytes(audio).file_name("speech.wav"));
+    let reply = reqwest::Client::new().post(url).timeout(config.transcribe_timeout).multipart(form).send().await
+        .and_then(|reply| reply.error_for_status())
+        .map_err(|e| { log::error!("Transcription request failed: {}", e); "❌ Error: Transcription request failed.".to_string() })?;
+    let response: TranscriptResponse = reply.json().await
+        .map_err(|e| { log::error!("Unexpected transcription response: {}", e); "❌ Error: Transcription returned an invalid response.".to_string() })?;
+    if response.segments.is_empty() {
+        return Err("❌ Error: No speech was found in this video.".to_string());
+    }
+    Ok(response.segments)
+}
+
+/// Whether the `/edit` font at `font_path` has a glyph for the word joiner `ass_escape` uses.
+fn font_has_word_joiner(font_path: &Path) -> bool {
+    let supported = std::fs::read(font_path).ok()
+        .and_then(|data| font_covers(&data, '\u{2060}'))
+        .unwrap_or(false);
+    if !supported {
+        log::info!("The /edit font has no word joiner glyph; literal \\N, \\n and \\h get a space instead.");
+    }
+    supported
+}
+
+/// Whether a TrueType/OpenType font's `cmap` has a range covering `c`. Only the first font of
+/// a collection is checked. `None` if the font can't be parsed.
+fn font_covers(data: &[u8], c: char) -> Option<bool> {
+    let code = c as usize;
+    let u16_at = |pos: usize| data.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize);
+    let u32_at = |pos: usize| data.get(pos..pos + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let font = if data.get(0..4)? == b"ttcf" { u32_at(12)? } else { 0 };
+    let cmap = (0..u16_at(font + 4)?)
+        .map(|i| font + 12 + i * 16)
+        .find(|&record| data.get(record..record + 4) == Some(b"cmap".as_slice()))
+        .and_then(|record| u32_at(record + 8))?;
+    for i in 0..u16_at(cmap + 2)? {
+        let subtable = cmap + u32_at(cmap + 4 + i * 8 + 4)?;
+        let covered = match u16_at(subtable)? {
+            4 => {
+                let segments = u16_at(subtable + 6)? / 2;
+                let ends = subtable + 14;
+                let starts = ends + segments * 2 + 2;
+                (0..segments).map(|s| Some((u16_at(starts + s * 2)?, u16_at(ends + s * 2)?)))
+                    .collect::<Option<Vec<_>>>()?.iter().any(|&(start, end)| (start..=end).contains(&code))
+            }
+            12 => (0..u32_at(subtable + 12)?).map(|g| Some((u32_at(subtable + 16 + g * 12)?, u32_at(subtable + 20 + g * 12)?)))
+                .collect::<Option<Vec<_>>>()?.iter().any(|&(start, end)| (start..=end).contains(&code)),
+            _ => false,
+        };
+        if covered {
+            return Some(true);
         }
-    } else if detected_boxes.is_empty() {
-        let full_text = messages.join("\\N").trim().to_string();
-        if full_text.is_empty() {
-             bot.edit_message_text_inline(&inline_message_id, "❌ Error: No text provided to add to video.").await.ok();
-             return;
+    }
+    Some(false)
+}
+
+/// Makes user text safe as the text of an ASS `Dialogue` line, for the configured font.
+fn ass_escape(text: &str, config: &Config) -> String {
+    escape_ass_text(text, config.font_has_word_joiner)
+}
+
+/// Braces are escaped so they can't open override blocks and newlines become `\N` breaks.
+/// ASS has no escape for a backslash, so one before `N`, `n` or `h` is followed by a word
+/// joiner, or a space for fonts without one, to keep it from forming `\N`, `\n` or `\h`.
+fn escape_ass_text(text: &str, word_joiner: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('N' | 'n' | 'h')) => {
+                escaped.push('\\');
+                escaped.push(if word_joiner { '\u{2060}' } else { ' ' });
+            }
+            '{' => escaped.push_str("\\{"),
+            '}' => escaped.push_str("\\}"),
+            '\n' => escaped.push_str("\\N"),
+            '\r' => {}
+            _ => escaped.push(c),
         }
-        let pad_height = (height as f32 * 0.15).max(100.0) as u32;
-        let font_size = (pad_height as f32 * 0.4).max(30.0) as u32;
-        let v_margin = (pad_height as f32 * 0.25) as u32;
-        let padded_tag = "[padded_v]".to_string();
-        preliminary_filters.push(format!("[0:v]pad=width=in_w:height=in_h+{pad}:x=0:y={pad}:color=black{out}", pad = pad_height, out = &padded_tag));
-        final_map_tag = padded_tag;
+    }
+    escaped
+}
 
-        ass_content = format!(
-            r#"[Script Info]
+/// Builds an ASS script with one bottom-centred `Dialogue` event per transcript segment.
+fn build_transcript_ass(segments: &[TranscriptSegment], width: u32, height: u32, font_name: &str, text_colour: &str, config: &Config) -> String {
+    let font_size = (height as f32 * 0.06).max(18.0) as u32;
+    let v_margin = (height as f32 * 0.05) as u32;
+    let events: Vec<String> = segments.iter()
+        .filter(|segment| !segment.text.trim().is_empty() && segment.end > segment.start)
+        .map(|segment| format!(
+            "Dialogue: 0,{start},{end},Caption,,0,0,0,,{text}",
+            start = format_ass_time(segment.start), end = format_ass_time(segment.end),
+            text = ass_escape(segment.text.trim(), config),
+        ))
+        .collect();
+    format!(
+        r#"[Script Info]
 PlayResX: {width}
 PlayResY: {height}
 [V4+ Styles]
 Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
-Style: Caption,{font_name},{font_size},&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,1,8,10,10,{v_margin},1
+Style: Caption,{font_name},{font_size},{text_colour},&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,1,2,10,10,{v_margin},1
 [Events]
 Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
-Dialogue: 0,0:00:00.00,9:59:59.99,Caption,,0,0,0,,{text}"#,
-            width = width, height = height + pad_height, font_name = font_name, font_size = font_size,
-            v_margin = v_margin, text = full_text.replace('{', "\\{").replace('}', "\\}")
-        );
-    } else {
-        let mut last_tag = final_map_tag;
-        let mut event_lines = String::new();
-        for (i, bbox) in detected_boxes.iter().take(messages.len()).enumerate() {
-            let current_tag = format!("[v{}]", i);
-            let filter = format!(
-                "{last_tag}drawbox=x={x}:y={y}:w={w}:h={h}:color=white:t=fill{out}",
-                last_tag = &last_tag, x = bbox.x, y = bbox.y, w = bbox.w, h = bbox.h, out = &current_tag
-            );
-            preliminary_filters.push(filter);
-            last_tag = current_tag;
+{events}"#,
+        width = width, height = height, font_name = font_name, font_size = font_size, text_colour = text_colour, v_margin = v_margin, events = events.join("\n")
+    )
+}
 
-            let text_to_draw = messages.get(i).unwrap_or(&messages[0]).trim();
-            let font_size = (bbox.h as f32 * 0.15).max(11.0) as u32;
-            let ass_safe_text = text_to_draw.replace('{', "\\{").replace('}', "\\}");
-            let margin_l = bbox.x;
-            let margin_r = width as i32 - (bbox.x + bbox.w as i32);
-            let margin_v = bbox.y + (bbox.h as f32 * 0.1) as i32;
+/// Whether a finished clip should be uploaded with `send_animation` under the silent clip policy.
+async fn send_as_animation(path: &Path, config: &Config) -> bool {
+    config.silent_clips_as_animation && probe_audio_tracks(path).await.is_empty()
+}
 
-            let event = format!(
-                r#"Dialogue: 0,0:00:00.00,9:59:59.99,BoxStyle,,{ml},{mr},{mv},,{{\fs{fs}}}{text}"#,
-                ml = margin_l, mr = margin_r, mv = margin_v, fs = font_size, text = ass_safe_text
-            );
-            event_lines.push_str(&event);
-            event_lines.push('\n');
+/// Lists the audio streams of a file as human readable lines, e.g. "1: eng (Director's commentary)".
+async fn probe_audio_tracks(path: &Path) -> Vec<String> {
+    let output = tokio::process::Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("a")
+        .arg("-show_entries").arg("stream=index:stream_tags=language,title")
+        .arg("-of").arg("json")
+        .arg(path)
+        .output().await;
+
+    let Ok(output) = output else { return vec![] };
+    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+    parsed["streams"].as_array().map(|streams| {
+        streams.iter().enumerate().map(|(i, stream)| {
+            let language = stream["tags"]["language"].as_str().unwrap_or("unknown");
+            match stream["tags"]["title"].as_str() {
+                Some(title) => format!("{}: {} ({})", i + 1, language, title),
+                None => format!("{}: {}", i + 1, language),
+            }
+        }).collect()
+    }).unwrap_or_default()
+}
+
+/// Whether a video keyframe sits at `time` (within a frame or so), so a stream copy starting
+/// there won't begin on a partial GOP.
+async fn is_keyframe_aligned(path: &Path, time: f64) -> bool {
+    if time <= 0.0 {
+        return true;
+    }
+    let output = tokio::process::Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-skip_frame").arg("nokey")
+        .arg("-read_intervals").arg(format!("{}%{}", (time - 1.0).max(0.0), time + 1.0))
+        .arg("-show_entries").arg("frame=pts_time")
+        .arg("-of").arg("csv=p=0")
+        .arg(path)
+        .output().await;
+    let Ok(output) = output else { return false };
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| line.trim().trim_end_matches(',').parse::<f64>().ok())
+        .any(|keyframe| (keyframe - time).abs() < 0.04)
+}
+
+/// Cuts `start..end` out of a clip. When `start` lands on a keyframe a stream copy is tried
+/// first, since it is near-instant; if the start isn't aligned or the copy comes out broken or
+/// the wrong length, the range is re-encoded instead.
+async fn trim_clip(input_path: &Path, temp_dir_path: &Path, start: f64, end: f64, encoder: &EncoderChoice) -> Option<PathBuf> {
+    let output_path = temp_dir_path.join("trimmed.mp4");
+    let length = end - start;
+
+    if is_keyframe_aligned(input_path, start).await {
+        let copied = run_encode(tokio::process::Command::new("ffmpeg")
+            .arg("-ss").arg(start.to_string()).arg("-i").arg(input_path)
+            .arg("-t").arg(length.to_string())
+            .arg("-map").arg("0:v:0").arg("-map").arg("0:a?")
+            .arg("-c").arg("copy").arg("-avoid_negative_ts").arg("make_zero")
+            .arg("-movflags").arg("+faststart").arg("-y").arg(&output_path)).await;
+        if copied
+            && is_valid_video(&output_path).await
+            && (probe_duration(&output_path).await - length).abs() < 0.5
+        {
+            log::info!("Trimmed {:.2}-{:.2}s with a stream copy.", start, end);
+            return Some(output_path);
         }
-        final_map_tag = last_tag;
-        ass_content = format!(
-            r#"[Script Info]
-PlayResX: {width}
-PlayResY: {height}
-[V4+ Styles]
-Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
-Style: BoxStyle,{font_name},100,&H00000000,&H000000FF,&H00FFFFFF,&H00FFFFFF,0,0,0,0,100,100,0,0,1,0,0,8,10,10,10,1
-[Events]
-Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
-{event_lines}"#,
-            width = width, height = height, font_name = font_name, event_lines = event_lines
-        );
+        log::info!("Stream copy trim was unusable. Re-encoding instead.");
     }
 
-    let ass_path = temp_dir_path.join("subs.ass");
-    if tokio::fs::write(&ass_path, ass_content).await.is_err() {
-        bot.edit_message_text_inline(&inline_message_id, "❌ Error: Could not write temporary subtitle file.").await.ok();
-        return;
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command.arg("-ss").arg(start.to_string()).arg("-i").arg(input_path)
+        .arg("-t").arg(length.to_string())
+        .arg("-map").arg("0:v:0").arg("-map").arg("0:a?");
+    configure_ffmpeg_encoder(&mut command, encoder);
+    command.arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg("-y").arg(&output_path);
+    if run_encode(&mut command).await {
+        Some(output_path)
+    } else {
+        log::error!("ffmpeg trim of {:.2}-{:.2}s failed.", start, end);
+        None
     }
+}
 
-    let escaped_ass_path = ass_path.to_string_lossy().replace('\\', "/");
+/// Container duration in seconds, or 0 if it can't be probed.
+async fn probe_duration(path: &Path) -> f64 {
+    match tokio::process::Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-show_entries").arg("format=duration")
+        .arg("-of").arg("default=noprint_wrappers=1:nokey=1")
+        .arg(path)
+        .output().await {
+            Ok(out) => String::from_utf8(out.stdout).unwrap_or_default().trim().parse().unwrap_or(0.0),
+            Err(_) => 0.0,
+        }
+}
 
-    let final_filter_chain = if preliminary_filters.is_empty() {
-        format!("[0:v]subtitles=filename='{subs_path}', format=yuv420p[v_out]", subs_path = escaped_ass_path)
+/// Checks with ffprobe that a file has a video stream and a nonzero duration.
+async fn is_valid_video(path: &Path) -> bool {
+    let output = tokio::process::Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=codec_type:format=duration")
+        .arg("-of").arg("json")
+        .arg(path)
+        .output().await;
+
+    let Ok(output) = output else { return false };
+    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+    let has_video_stream = parsed["streams"].as_array().is_some_and(|streams| !streams.is_empty());
+    let duration: f64 = parsed["format"]["duration"].as_str().and_then(|d| d.parse().ok()).unwrap_or(0.0);
+    has_video_stream && duration > 0.0
+}
+
+/// Returns the ffmpeg `-map` spec for the requested 1-based audio track, or an error message
+/// listing the available tracks when the index is out of range.
+async fn resolve_audio_map(path: &Path, audio_track: Option<usize>) -> Result<String, String> {
+    let Some(track) = audio_track else { return Ok("0:a?".to_string()) };
+    let tracks = probe_audio_tracks(path).await;
+    if (1..=tracks.len()).contains(&track) {
+        return Ok(format!("0:a:{}", track - 1));
+    }
+    if tracks.is_empty() {
+        Err("❌ Error: This video has no audio tracks.".to_string())
     } else {
-        let prelim_chain = preliminary_filters.join(";");
-        format!("{prelim_chain}; {final_video_stream}subtitles=filename='{subs_path}', format=yuv420p[v_out]",
-            prelim_chain = prelim_chain,
-            final_video_stream = &final_map_tag,
-            subs_path = escaped_ass_path)
-    };
+        Err(format!("❌ Error: Audio track {} doesn't exist. Available tracks:\n{}", track, tracks.join("\n")))
+    }
+}
 
-    let mut command = tokio::process::Command::new("ffmpeg");
-    command.arg("-i").arg(&processed_video_path).arg("-filter_complex").arg(&final_filter_chain)
-        .arg("-map").arg("[v_out]").arg("-map").arg("0:a?").arg("-c:a").arg("copy");
+/// Returns true when the given env var is set to "1".
+fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|v| v == "1")
+}
+
+/// Reads and parses an env var, falling back to `default` when it is unset or invalid.
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(raw) => raw.trim().parse().unwrap_or_else(|_| {
+            log::warn!("Invalid value '{}' for {}. Using the default.", raw, name);
+            default
+        }),
+        Err(_) => default,
+    }
+}
 
-    configure_ffmpeg_encoder(&mut command);
+/// Encoder settings applied by `configure_ffmpeg_encoder`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+enum EncoderChoice {
+    /// CPU-optimized libx264 settings for weak hardware.
+    CpuFast,
+    /// An encoder named in `FFMPEG_ENCODER`, used with ffmpeg's defaults, except that
+    /// `libvpx-vp9`, `libaom-av1` and `libsvtav1` get constant-quality settings.
+    Custom(String),
+    /// NVIDIA hardware encoding.
+    Nvenc,
+    /// Plain libx264 with the ultrafast preset.
+    Cpu,
+}
 
-    command.arg("-flags").arg("+global_header").arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg(&output_path);
+/// Hardware facts probed once at startup by `detect_encoder_support`.
+#[derive(Debug)]
+struct EncoderSupport {
+    nvenc_usable: bool,
+    weak_cpu: bool,
+}
+
+static ENCODER_SUPPORT: OnceCell<EncoderSupport> = OnceCell::new();
+
+/// Checks whether `h264_nvenc` can actually encode (a tiny test encode, since the encoder can be
+/// compiled in without a usable GPU) and whether the CPU is weak (2 threads or fewer).
+async fn detect_encoder_support() {
+    let nvenc_usable = tokio::process::Command::new("ffmpeg")
+        .arg("-hide_banner").arg("-loglevel").arg("error")
+        .arg("-f").arg("lavfi").arg("-i").arg("color=c=black:s=256x256:d=0.1")
+        .arg("-c:v").arg("h264_nvenc").arg("-f").arg("null").arg("-")
+        .stdout(Stdio::null()).stderr(Stdio::null())
+        .status().await.is_ok_and(|s| s.success());
+    let weak_cpu = std::thread::available_parallelism().map(|n| n.get() <= 2).unwrap_or(false);
+    let support = EncoderSupport { nvenc_usable, weak_cpu };
+    log::info!("Detected encoder support: {:?}", support);
+    if env::var("CUDA_ENABLED").is_ok() && !nvenc_usable {
+        log::warn!("CUDA_ENABLED is set but h264_nvenc is not usable. Falling back to CPU encoding.");
+    }
+    ENCODER_SUPPORT.set(support).ok();
+}
+
+impl EncoderChoice {
+    /// The default encoder. `BAD_HARDWARE` and `FFMPEG_ENCODER` always win; otherwise nvenc is
+    /// used when it works (and `CUDA_ENABLED`, if set, only takes effect when it does), and
+    /// weak CPUs get the fast CPU settings.
+    fn from_env() -> Self {
+        if env_flag("BAD_HARDWARE") {
+            return EncoderChoice::CpuFast;
+        }
+        let encoder = env::var("FFMPEG_ENCODER").unwrap_or_default();
+        if !encoder.is_empty() {
+            return EncoderChoice::Custom(encoder);
+        }
+        match ENCODER_SUPPORT.get() {
+            Some(support) if support.nvenc_usable => EncoderChoice::Nvenc,
+            Some(support) if support.weak_cpu => EncoderChoice::CpuFast,
+            Some(_) => EncoderChoice::Cpu,
+            // Before detection has run, trust the env flag as before.
+            None if env::var("CUDA_ENABLED").is_ok() => EncoderChoice::Nvenc,
+            None => EncoderChoice::Cpu,
+        }
+    }
+
+    /// Parses the per-request `/cpu` and `/gpu` overrides.
+    fn take_override(text: &mut String) -> Option<Self> {
+        if take_flag(text, "/cpu") {
+            Some(EncoderChoice::CpuFast)
+        } else if take_flag(text, "/gpu") {
+            Some(EncoderChoice::Nvenc)
+        } else {
+            None
+        }
+    }
+}
 
-    if command.status().await.is_ok_and(|s| s.success()) {
-        let temp_message = match bot.send_video(user_id, InputFile::file(&output_path)).await {
-            Ok(msg) => msg,
-            Err(_) => { bot.edit_message_text_inline(&inline_message_id, "❌ Error: Could not pre-upload video.").await.ok(); return; }
+fn configure_ffmpeg_encoder(command: &mut tokio::process::Command, encoder: &EncoderChoice) {
+    match encoder {
+        EncoderChoice::CpuFast => {
+            log::info!("Using CPU-optimized FFMPEG settings.");
+            command.arg("-c:v").arg("libx264")
+                   .arg("-preset").arg("ultrafast")
+                   .arg("-crf").arg("26")
+                   .arg("-threads").arg("4");
+        }
+        EncoderChoice::Custom(name) => {
+            command.arg("-c:v").arg(name);
+            // ffmpeg's defaults for these target a tiny fixed bitrate (VP9) or are painfully slow
+            // (AV1), so they get constant-quality settings instead. The output stays MP4, which
+            // holds both codecs: `send_video` only plays MP4, a webm would arrive as a document.
+            match name.as_str() {
+                "libvpx-vp9" => {
+                    command.arg("-crf").arg("33").arg("-b:v").arg("0")
+                           .arg("-deadline").arg("good").arg("-cpu-used").arg("4").arg("-row-mt").arg("1");
+                }
+                "libaom-av1" => {
+                    command.arg("-crf").arg("32").arg("-b:v").arg("0")
+                           .arg("-cpu-used").arg("6").arg("-row-mt").arg("1");
+                }
+                "libsvtav1" => {
+                    command.arg("-crf").arg("35").arg("-preset").arg("8");
+                }
+                _ => {}
+            }
+        }
+        EncoderChoice::Nvenc => {
+            command.arg("-c:v").arg("h264_nvenc")
+                   .arg("-preset").arg("p7")
+                   .arg("-rc").arg("vbr")
+                   .arg("-gpu").arg("0");
+        }
+        EncoderChoice::Cpu => {
+            command.arg("-c:v").arg("libx264")
+                   .arg("-preset").arg("ultrafast");
+        }
+    }
+}
+
+/// Parses the `crop=W:H:X:Y` suggestion from one line of `cropdetect` output.
+fn parse_cropdetect_line(line: &str) -> Option<CropRect> {
+    let (_, values) = line.split_once("crop=")?;
+    let values: Vec<u32> = values.split_whitespace().next()?.split(':').map(|v| v.parse().ok()).collect::<Option<_>>()?;
+    match values[..] {
+        [w, h, x, y] => Some(CropRect { w, h, x, y }),
+        _ => None,
+    }
+}
+
+/// The crop `cropdetect` suggested most often. The last line alone can be wrong for clips that
+/// fade in or out; ties go to the later suggestion, once detection has settled.
+fn most_common_crop(suggestions: &[CropRect]) -> Option<CropRect> {
+    let mut counts: HashMap<CropRect, (usize, usize)> = HashMap::new();
+    for (i, crop) in suggestions.iter().enumerate() {
+        let entry = counts.entry(*crop).or_insert((0, i));
+        entry.0 += 1;
+        entry.1 = i;
+    }
+    counts.into_iter().max_by_key(|(_, (count, last_seen))| (*count, *last_seen)).map(|(crop, _)| crop)
+}
+
+/// Runs ffmpeg's `cropdetect` over the whole clip and returns the crop it suggested most often.
+async fn run_cropdetect(input_path: &Path, config: &Config) -> Option<CropRect> {
+    let mut crop_command_output = match tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(input_path)
+        .arg("-vf").arg(&config.cropdetect_filter)
+        .arg("-f").arg("null")
+        .arg("-")
+        .stderr(Stdio::piped())
+        .spawn() {
+            Ok(child) => child,
+            Err(e) => { log::error!("Failed to spawn ffmpeg cropdetect: {}", e); return None; }
         };
-        let new_video_file_id = match temp_message.video() { Some(vid) => vid.file.id.clone(), None => return };
-        bot.delete_message(user_id, temp_message.id).await.ok();
-        let media = InputMedia::Video(InputMediaVideo::new(InputFile::file_id(new_video_file_id)));
-        if bot.edit_message_media_inline(&inline_message_id, media).await.is_err() {
-            log::warn!("Failed to edit inline message.");
+
+    let mut suggestions = vec![];
+    if let Some(stderr) = crop_command_output.stderr.take() {
+        let mut reader = tokio::io::BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = reader.next_line().await {
+            suggestions.extend(parse_cropdetect_line(&line));
+        }
+    }
+    crop_command_output.wait().await.ok();
+    most_common_crop(&suggestions)
+}
+
+/// Reads the clockwise display rotation of the first video stream: 0, 90, 180 or 270.
+/// Older files carry it as a `rotate` tag, newer ones as display matrix side data.
+async fn probe_rotation(path: &Path) -> u32 {
+    let output = tokio::process::Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream_tags=rotate:stream_side_data=rotation")
+        .arg("-of").arg("json")
+        .arg(path)
+        .output().await;
+
+    let Ok(output) = output else { return 0 };
+    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+    let stream = &parsed["streams"][0];
+    let clockwise = if let Some(tag) = stream["tags"]["rotate"].as_str().and_then(|r| r.parse::<f64>().ok()) {
+        tag
+    } else if let Some(side_data) = stream["side_data_list"].as_array()
+        .and_then(|list| list.iter().find_map(|d| d["rotation"].as_f64())) {
+        // The display matrix rotation is counterclockwise.
+        -side_data
+    } else {
+        0.0
+    };
+    let quarter_turns = (clockwise / 90.0).round() as i64;
+    (quarter_turns.rem_euclid(4) * 90) as u32
+}
+
+/// The filter that turns frames upright for a clockwise `rotation`, or `None` when there's nothing to undo.
+fn rotation_filter(rotation: u32) -> Option<&'static str> {
+    match rotation {
+        90 => Some("transpose=clock"),
+        180 => Some("hflip,vflip"),
+        270 => Some("transpose=cclock"),
+        _ => None,
+    }
+}
+
+/// Bakes rotation metadata into the pixels, so frame extraction, crop detection and the crop
+/// filter all see the same orientation and the output carries no conflicting rotation flag.
+/// Every audio track is copied untouched, so `/edit`'s audio track numbers still apply.
+/// Returns the path to use from here on, which is the input itself if nothing was rotated.
+async fn apply_rotation(input_path: &Path, temp_dir_path: &Path, encoder: &EncoderChoice) -> PathBuf {
+    let rotation = probe_rotation(input_path).await;
+    let Some(transpose_filter) = rotation_filter(rotation) else { return input_path.to_path_buf() };
+    log::info!("Video has {} degree rotation metadata. Normalizing orientation.", rotation);
+
+    let rotated_path = temp_dir_path.join("rotated.mp4");
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command.arg("-noautorotate").arg("-i").arg(input_path)
+        .arg("-vf").arg(transpose_filter)
+        .arg("-map").arg("0:v:0").arg("-map").arg("0:a?").arg("-c:a").arg("copy");
+    configure_ffmpeg_encoder(&mut command, encoder);
+    command.arg("-metadata:s:v:0").arg("rotate=0")
+        .arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg("-y").arg(&rotated_path);
+
+    if run_encode(&mut command).await {
+        rotated_path
+    } else {
+        log::warn!("ffmpeg rotation normalization failed. Continuing with the original file.");
+        input_path.to_path_buf()
+    }
+}
+
+/// Reads the width and height of the first video stream with ffprobe.
+async fn probe_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let output = tokio::process::Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=width,height")
+        .arg("-of").arg("csv=p=0:s=x")
+        .arg(path)
+        .output().await.ok()?;
+    let dims: Vec<u32> = String::from_utf8(output.stdout).unwrap_or_default().trim()
+        .split('x').filter_map(|s| s.parse().ok()).collect();
+    if dims.len() == 2 && dims[0] > 0 && dims[1] > 0 { Some((dims[0], dims[1])) } else { None }
+}
+
+/// `variants.kind` of edits made with `/mute`, which `/unmute` reverses.
+const VARIANT_MUTED: &str = "mute";
+
+/// Links a derived video (an edit, a muted copy, ...) to the saved video it was made from,
+/// so `/original` and `/unmute` can reverse the derivation. Keyed by `file_unique_id`, which unlike
+/// `file_id` is stable across re-sends.
+async fn record_variant(pool: &SharedState, file_unique_id: &str, file_id: &str, source_file_id: &str, kind: &str) {
+    let result = sqlx::query("INSERT OR REPLACE INTO variants (file_unique_id, file_id, source_file_id, kind) VALUES (?, ?, ?, ?)")
+        .bind(file_unique_id).bind(file_id).bind(source_file_id).bind(kind).execute(pool).await;
+    if let Err(e) = result {
+        log::error!("Failed to record {} variant: {}", kind, e);
+    }
+}
+
+/// Total size of the files under `path`.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else { return 0 };
+    entries.flatten().map(|entry| match entry.metadata() {
+        Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }).sum()
+}
+
+/// Runs an encode while a watchdog polls the size of the job's temp dir, killing ffmpeg if it
+/// grows past `MAX_JOB_DISK_MB` so one bad input can't fill the disk. Returns whether the
+/// command succeeded, or a user-facing message if the watchdog aborted it.
+async fn run_with_disk_guard(command: &mut tokio::process::Command, temp_dir_path: &Path, config: &Config) -> Result<bool, String> {
+    match command.spawn() {
+        Ok(child) => guard_disk_usage(child, temp_dir_path, config.max_job_disk_bytes).await,
+        Err(e) => { log::error!("{}Failed to spawn ffmpeg: {}", job_tag(), e); Ok(false) }
+    }
+}
+
+/// Like `run_with_disk_guard`, for an encode started with `-progress pipe:1`: shows how far
+/// ffmpeg is through `total_secs` of input on the job's status while it runs.
+async fn run_with_progress(command: &mut tokio::process::Command, temp_dir_path: &Path, bot: &Bot, status: &JobStatus, total_secs: f64, config: &Config) -> Result<bool, String> {
+    let mut child = match command.stdout(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => { log::error!("{}Failed to spawn ffmpeg: {}", job_tag(), e); return Ok(false); }
+    };
+    let stdout = child.stdout.take();
+    let report_progress = async {
+        let Some(stdout) = stdout else { return };
+        let interval = config.progress_update_interval;
+        let mut last_update = Instant::now();
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let Some(out_us) = line.strip_prefix("out_time_us=").and_then(|v| v.trim().parse::<f64>().ok()) else { continue };
+            if last_update.elapsed() < interval {
+                continue;
+            }
+            last_update = Instant::now();
+            let percent = (out_us / 1_000_000.0 / total_secs * 100.0).clamp(0.0, 99.0);
+            status.show(bot, &format!("⚙️ Processing your video... {:.0}%", percent)).await;
+        }
+    };
+    // The reader finishes once ffmpeg exits or is killed and its stdout closes.
+    let (result, _) = tokio::join!(guard_disk_usage(child, temp_dir_path, config.max_job_disk_bytes), report_progress);
+    result
+}
+
+/// Waits for an encode while polling the size of the job's temp dir, see `run_with_disk_guard`.
+async fn guard_disk_usage(mut child: tokio::process::Child, temp_dir_path: &Path, limit: u64) -> Result<bool, String> {
+
+    let started = Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_secs(2));
+    loop {
+        tokio::select! {
+            status = child.wait() => {
+                record_encode_duration(started.elapsed());
+                return Ok(status.is_ok_and(|s| s.success()));
+            }
+            _ = ticker.tick() => {
+                // Walking the directory is blocking file system work.
+                let dir = temp_dir_path.to_path_buf();
+                let size = tokio::task::spawn_blocking(move || dir_size(&dir)).await.unwrap_or(0);
+                if size > limit {
+                    log::error!("{}Job temp dir {} grew to {} bytes (cap {}). Killing ffmpeg.", job_tag(), temp_dir_path.display(), size, limit);
+                    child.kill().await.ok();
+                    return Err(format!("❌ Error: Processing was aborted because the output grew past {} MB.", limit / 1024 / 1024));
+                }
+            }
+        }
+    }
+}
+
+/// Downloads a Telegram file to `dest_path`, returning whether it succeeded.
+async fn download_telegram_file(bot: &Bot, file_id: &str, dest_path: &Path) -> bool {
+    let Ok(file) = bot.get_file(file_id).await else { return false };
+    let Ok(mut dest) = fs::File::create(dest_path).await else { return false };
+    bot.download_file(&file.path, &mut dest).await.is_ok()
+}
+
+// --- Background Video Editing Task ---
+
+/// Renders a "processing" placeholder from a video frame: the frame dimmed, with three
+/// loading dots on top.
+fn build_processing_placeholder(frame_path: &Path, output_path: &Path) -> bool {
+    let Some(img) = ImageReader::open(frame_path).ok().and_then(|r| r.decode().ok()) else { return false; };
+    let mut canvas = img.to_rgb8();
+    for pixel in canvas.pixels_mut() {
+        for channel in pixel.0.iter_mut() {
+            *channel = (*channel as f32 * 0.35) as u8;
+        }
+    }
+
+    let (width, height) = canvas.dimensions();
+    let radius = (width.min(height) / 24).max(4) as i32;
+    let center_y = (height / 2) as i32;
+    for i in -1..=1 {
+        let center_x = (width / 2) as i32 + i * radius * 3;
+        imageproc::drawing::draw_filled_circle_mut(&mut canvas, (center_x, center_y), radius, image::Rgb([255, 255, 255]));
+    }
+    canvas.save(output_path).is_ok()
+}
+
+/// Best-effort: swaps the inline message to a placeholder thumbnail of the clip being edited.
+/// Inline messages can only show media that is already on Telegram, so the placeholder is
+/// pre-uploaded to the user's DM first.
+async fn show_processing_thumbnail(bot: &Bot, user_id: UserId, inline_message_id: &str, frame_path: &Path, temp_dir_path: &Path) {
+    let placeholder_path = temp_dir_path.join("placeholder.jpg");
+    if !build_processing_placeholder(frame_path, &placeholder_path) {
+        log::warn!("Failed to render processing placeholder.");
+        return;
+    }
+
+    let Ok(temp_message) = bot.send_photo(user_id, InputFile::file(&placeholder_path)).await else { return };
+    let photo_file_id = temp_message.photo().and_then(|sizes| sizes.last()).map(|p| p.file.id.clone());
+    bot.delete_message(user_id, temp_message.id).await.ok();
+    let Some(photo_file_id) = photo_file_id else { return };
+
+    let media = InputMedia::Photo(InputMediaPhoto::new(InputFile::file_id(photo_file_id)).caption("⚙️ Processing your video..."));
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("⚙️ Processing...", "ignore")]]);
+    if bot.edit_message_media_inline(inline_message_id, media).reply_markup(keyboard).await.is_err() {
+        log::warn!("Failed to show processing thumbnail on inline message.");
+    }
+}
+
+/// Draws a rectangle `thickness` pixels wide, growing inwards.
+fn draw_thick_rect(canvas: &mut image::RgbImage, x: i32, y: i32, w: u32, h: u32, thickness: u32, color: image::Rgb<u8>) {
+    for i in 0..thickness.min(w / 2).min(h / 2) {
+        let rect = Rect::at(x + i as i32, y + i as i32).of_size(w - 2 * i, h - 2 * i);
+        imageproc::drawing::draw_hollow_rect_mut(canvas, rect, color);
+    }
+}
+
+/// For `/edit ... /preview`: marks the cropdetect result in red and the text boxes `/edit` would
+/// fill in green on the first frame, and shows that image in the inline message.
+async fn show_detection_preview(bot: &Bot, user_id: UserId, inline_message_id: &str, input_path: &Path, crop: Option<CropRect>, temp_dir_path: &Path, config: &Config) -> bool {
+    let frame_path = temp_dir_path.join("preview_frame.png");
+    let cropped_frame_path = temp_dir_path.join("preview_cropped.png");
+    let preview_path = temp_dir_path.join("preview.jpg");
+    let frame_status = tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(input_path).arg("-vframes").arg("1").arg("-y").arg(&frame_path).status().await;
+    let Some(frame) = frame_status.ok().filter(|s| s.success())
+        .and_then(|_| ImageReader::open(&frame_path).ok()?.decode().ok()) else {
+        report_inline_error(bot, inline_message_id, "❌ Error: Failed to extract frame.").await;
+        return false;
+    };
+
+    // Boxes are detected on the cropped frame, like the real edit does, then shifted back.
+    let crop = crop.unwrap_or(CropRect { x: 0, y: 0, w: frame.width(), h: frame.height() });
+    let boxes = if frame.crop_imm(crop.x, crop.y, crop.w, crop.h).save(&cropped_frame_path).is_ok() {
+        detect_boxes_off_runtime(&cropped_frame_path, config).await
+
+    } else {
+        vec![]
+    };
+    let mut canvas = frame.to_rgb8();
+    let thickness = (frame.width().max(frame.height()) / 200).max(2);
+    draw_thick_rect(&mut canvas, crop.x as i32, crop.y as i32, crop.w, crop.h, thickness, image::Rgb([255, 0, 0]));
+    for bbox in &boxes {
+        draw_thick_rect(&mut canvas, crop.x as i32 + bbox.x, crop.y as i32 + bbox.y, bbox.w, bbox.h, thickness, image::Rgb([0, 255, 0]));
+    }
+    if canvas.save(&preview_path).is_err() {
+        report_inline_error(bot, inline_message_id, "❌ Error: Failed to render the preview.").await;
+        return false;
+    }
+
+    let Ok(temp_message) = bot.send_photo(user_id, InputFile::file(&preview_path)).await else {
+        report_inline_error(bot, inline_message_id, "❌ Error: Could not pre-upload the preview.").await;
+        return false;
+    };
+    let photo_file_id = temp_message.photo().and_then(|sizes| sizes.last()).map(|p| p.file.id.clone());
+    bot.delete_message(user_id, temp_message.id).await.ok();
+    let Some(photo_file_id) = photo_file_id else { return false };
+
+    let summary = format!("🔍 Crop (red): {}x{} at {},{} | Text boxes (green): {}", crop.w, crop.h, crop.x, crop.y, boxes.len());
+    let media = InputMedia::Photo(InputMediaPhoto::new(InputFile::file_id(photo_file_id)).caption(summary));
+    if bot.edit_message_media_inline(inline_message_id, media).await.is_err() {
+        log::warn!("Failed to show detection preview on inline message.");
+        return false;
+    }
+    true
+}
+
+/// Shows an error on an inline message, whether it currently holds text or the original media.
+async fn report_inline_error(bot: &Bot, inline_message_id: &str, text: &str) {
+    if bot.edit_message_text_inline(inline_message_id, text).await.is_err() {
+        bot.edit_message_caption_inline(inline_message_id).caption(text).await.ok();
+    }
+}
+
+/// The font `/edit` renders text with, from `UNIVERSAL_FONT_PATH`, checked to be a readable file.
+fn universal_font_path() -> Result<PathBuf, String> {
+    let raw = env::var("UNIVERSAL_FONT_PATH").map_err(|_| "UNIVERSAL_FONT_PATH is not set.".to_string())?;
+    let path = PathBuf::from(raw.trim());
+    if !path.is_file() {
+        return Err(format!("UNIVERSAL_FONT_PATH '{}' is not a file.", path.display()));
+    }
+    std::fs::File::open(&path).map_err(|e| format!("UNIVERSAL_FONT_PATH '{}' is not readable: {}", path.display(), e))?;
+    Ok(path)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn perform_video_edit(bot: Bot, user_id: UserId, inline_message_id: String, file_id: String, spec: EditSpec, options: EditOptions, pool: SharedState, config: &Config) -> JobOutcome {
+    // Checked at startup too, but the file can go away while the bot runs.
+    let font_path = &config.font_path;
+    if !font_path.is_file() || std::fs::File::open(font_path).is_err() {
+        log::error!("{}The /edit font {} is missing or unreadable.", job_tag(), font_path.display());
+        report_inline_error(&bot, &inline_message_id, "❌ Error: The server is misconfigured (missing font). Please tell the bot admin.").await;
+        return JobOutcome::Rejected;
+    }
+    let temp_dir = match Builder::new().prefix("video_edit").tempdir() {
+        Ok(dir) => dir,
+        Err(e) => { log::error!("{}Failed to create temp dir: {}", job_tag(), e); return JobOutcome::Failed; }
+    };
+    let temp_dir_path = temp_dir.path();
+    let input_path = temp_dir_path.join("input.mp4");
+    let cropped_path = temp_dir_path.join("cropped.mp4");
+    let output_path = temp_dir_path.join("output.mp4");
+    let frame_path = temp_dir_path.join("frame.png");
+
+    if !download_telegram_file(&bot, &file_id, &input_path).await {
+        report_inline_error(&bot, &inline_message_id, "❌ Error: Failed to download the video.").await;
+        return JobOutcome::Failed;
+    }
+
+    let duration = probe_duration(&input_path).await;
+    let max_duration = config.max_edit_duration_secs;
+    if duration > max_duration {
+        let message = format!("❌ Error: This video is {:.0}s long. Edits are limited to videos up to {:.0}s.", duration, max_duration);
+        report_inline_error(&bot, &inline_message_id, &message).await;
+        return JobOutcome::Rejected;
+    }
+    if let EditSpec::Timed { at, .. } = &spec {
+        if duration > 0.0 && *at > duration {
+            let message = format!("❌ Error: The text can't switch at {}, the video is only {:.1}s long.", format_ass_time(*at), duration);
+            report_inline_error(&bot, &inline_message_id, &message).await;
+            return JobOutcome::Rejected;
+        }
+    }
+
+    let encoder = options.encoder.clone().unwrap_or_else(|| config.encoder.clone());
+    let input_path = apply_rotation(&input_path, temp_dir_path, &encoder).await;
+
+    let mut audio_map = match resolve_audio_map(&input_path, options.audio_track).await {
+        Ok(map) => map,
+        Err(message) => { report_inline_error(&bot, &inline_message_id, &message).await; return JobOutcome::Rejected; }
+    };
+
+    // --- Start of Crop Detection and Cropping ---
+    let crop_rect = run_cropdetect(&input_path, config).await;
+    if options.preview {
+        return show_detection_preview(&bot, user_id, &inline_message_id, &input_path, crop_rect, temp_dir_path, config).await.into();
+    }
+
+    let mut processed_video_path = input_path.clone();
+
+    if let Some(crop) = crop_rect {
+        let crop_filter = format!("crop={}:{}:{}:{}", crop.w, crop.h, crop.x, crop.y);
+        let mut crop_command = tokio::process::Command::new("ffmpeg");
+        crop_command.arg("-i").arg(&input_path).arg("-vf").arg(crop_filter);
+        if options.audio_track.is_some() {
+            // Keep only the chosen track so the final encode can map whatever audio is left.
+            crop_command.arg("-map").arg("0:v:0").arg("-map").arg(&audio_map);
+        }
+        if run_encode(crop_command.arg("-c:a").arg("copy").arg(&cropped_path)).await {
+            processed_video_path = cropped_path;
+            audio_map = "0:a?".to_string();
+        }
+    }
+    // --- End of Crop Detection and Cropping ---
+
+    let ffprobe_output = match tokio::process::Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=width,height")
+        .arg("-of").arg("csv=p=0:s=x")
+        .arg(&processed_video_path)
+        .output().await {
+            Ok(out) => out,
+            Err(e) => {
+                log::error!("{}ffprobe failed: {}", job_tag(), e);
+                report_inline_error(&bot, &inline_message_id, "❌ Error: Could not analyze video dimensions.").await;
+                return JobOutcome::Failed;
+            }
+        };
+
+    let dims: Vec<u32> = String::from_utf8(ffprobe_output.stdout).unwrap_or_default().trim()
+        .split('x').filter_map(|s| s.parse().ok()).collect();
+    let (width, height) = if dims.len() == 2 { (dims[0], dims[1]) } else { (0,0) };
+    if width == 0 || height == 0 {
+        report_inline_error(&bot, &inline_message_id, "❌ Error: Could not determine video dimensions.").await;
+        return JobOutcome::Failed;
+    }
+
+    let frame_extraction_status = tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(&processed_video_path).arg("-vframes").arg("1").arg("-y").arg(&frame_path).status().await.ok();
+    if frame_extraction_status.is_none() || !frame_extraction_status.unwrap().success() {
+        report_inline_error(&bot, &inline_message_id, "❌ Error: Failed to extract frame.").await;
+        return JobOutcome::Failed;
+    }
+
+    if get_user_flag(&pool, user_id, SETTING_PROGRESS_THUMB).await {
+        show_processing_thumbnail(&bot, user_id, &inline_message_id, &frame_path, temp_dir_path).await;
+    }
+
+    let transcript = if options.transcribe {
+        match transcribe_audio(&processed_video_path, temp_dir_path, config).await {
+            Ok(segments) => Some(segments),
+            Err(message) => { report_inline_error(&bot, &inline_message_id, &message).await; return JobOutcome::Failed; }
+        }
+    } else {
+        None
+    };
+
+    let detected_boxes = detect_boxes_off_runtime(&frame_path, config).await;
+    let messages = spec.texts();
+    let font_name = font_path.file_stem().and_then(|s| s.to_str()).unwrap_or("Noto Sans");
+
+    let ass_content: String;
+    let mut preliminary_filters: Vec<String> = vec![];
+    let mut final_map_tag = "[0:v]".to_string();
+
+    // Without `/color` boxes are filled white with black text, and padded captions are white.
+    let color = options.color.as_deref().and_then(edit_color);
+    let box_fill = color.map_or("white", |(name, _, _)| name);
+    let box_text_colour = if color.is_some_and(|(_, _, dark)| dark) { "&H00FFFFFF" } else { "&H00000000" };
+    let caption_colour = color.map_or("&H00FFFFFF", |(_, ass, _)| ass);
+    let pad_color = options.pad_color.as_deref().unwrap_or("black");
+
+    if let Some(segments) = &transcript {
+        ass_content = build_transcript_ass(segments, width, height, font_name, caption_colour, config);
+    } else if let EditSpec::Timed { first, at, second } = &spec {
+        let text1 = first.trim();
+        let time_s = *at;
+        let text2 = second.trim();
+
+        let end_time1_str = format_ass_time(time_s);
+        let start_time2_str = format_ass_time(time_s);
+        let ass_safe_text1 = ass_escape(text1, config);
+        let ass_safe_text2 = ass_escape(text2, config);
+
+        if let Some(bbox) = detected_boxes.get(0) {
+            let current_tag = "[v_box]".to_string();
+            let filter = format!(
+                "{last_tag}drawbox=x={x}:y={y}:w={w}:h={h}:color={box_fill}:t=fill{out}",
+                last_tag = &final_map_tag,
+                x = bbox.x, y = bbox.y, w = bbox.w, h = bbox.h, box_fill = box_fill, out = &current_tag
+            );
+            preliminary_filters.push(filter);
+            final_map_tag = current_tag;
+
+            let font_size = (bbox.h as f32 * 0.3).max(20.0) as u32;
+            let margin_l = bbox.x;
+            let margin_r = width as i32 - (bbox.x + bbox.w as i32);
+            let margin_v = bbox.y + (bbox.h as f32 * 0.1) as i32;
+
+            let event1 = format!(
+                r#"Dialogue: 0,0:00:00.00,{end_time},BoxStyle,,{ml},{mr},{mv},,{{\fs{fs}}}{text}"#,
+                end_time = end_time1_str, ml = margin_l, mr = margin_r, mv = margin_v, fs = font_size, text = ass_safe_text1
+            );
+            let event2 = format!(
+                r#"Dialogue: 0,{start_time},9:59:59.99,BoxStyle,,{ml},{mr},{mv},,{{\fs{fs}}}{text}"#,
+                start_time = start_time2_str, ml = margin_l, mr = margin_r, mv = margin_v, fs = font_size, text = ass_safe_text2
+            );
+
+            ass_content = format!(
+                r#"[Script Info]
+PlayResX: {width}
+PlayResY: {height}
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: BoxStyle,{font_name},100,{box_text_colour},&H000000FF,&H00FFFFFF,&H00FFFFFF,0,0,0,0,100,100,0,0,1,0,0,8,10,10,10,1
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+{event1}
+{event2}"#,
+                width = width, height = height, font_name = font_name, box_text_colour = box_text_colour, event1 = event1, event2 = event2
+            );
+        } else {
+            let pad_height = (height as f32 * 0.15).max(100.0) as u32;
+            let font_size = options.font_size.unwrap_or((pad_height as f32 * 0.4).max(30.0) as u32);
+            let v_margin = (pad_height as f32 * 0.25) as u32;
+            let padded_tag = "[padded_v]".to_string();
+            preliminary_filters.push(format!("[0:v]pad=width=in_w:height=in_h+{pad}:x=0:y={pad}:color={pad_color}{out}", pad = pad_height, pad_color = pad_color, out = &padded_tag));
+            final_map_tag = padded_tag;
+
+            ass_content = format!(
+                r#"[Script Info]
+PlayResX: {width}
+PlayResY: {height}
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Caption,{font_name},{font_size},{caption_colour},&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,1,8,10,10,{v_margin},1
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+Dialogue: 0,0:00:00.00,{end_time1},Caption,,0,0,0,,{text1}
+Dialogue: 0,{start_time2},9:59:59.99,Caption,,0,0,0,,{text2}"#,
+                width = width, height = height + pad_height, font_name = font_name, font_size = font_size, caption_colour = caption_colour, v_margin = v_margin,
+                end_time1 = end_time1_str, start_time2 = start_time2_str, text1 = ass_safe_text1, text2 = ass_safe_text2
+            );
+        }
+    } else if detected_boxes.is_empty() {
+        let full_text = messages.iter().map(|m| ass_escape(m.trim(), config)).collect::<Vec<_>>().join("\\N");
+        if messages.iter().all(|m| m.trim().is_empty()) {
+             report_inline_error(&bot, &inline_message_id, "❌ Error: No text provided to add to video.").await;
+             return JobOutcome::Rejected;
+        }
+        let pad_height = (height as f32 * 0.15).max(100.0) as u32;
+        let font_size = options.font_size.unwrap_or((pad_height as f32 * 0.4).max(30.0) as u32);
+        let v_margin = (pad_height as f32 * 0.25) as u32;
+        // The bar goes above the clip (top-centre alignment 8, the clip shifted down by the bar)
+        // or below it (bottom-centre alignment 2, the clip left at y=0). PlayResY is the padded
+        // height, so MarginV is in output pixels and measured from whichever edge the bar is on.
+        let (clip_y, alignment) = if options.caption_bottom { (0, 2) } else { (pad_height, 8) };
+        let padded_tag = "[padded_v]".to_string();
+        preliminary_filters.push(format!("[0:v]pad=width=in_w:height=in_h+{pad}:x=0:y={y}:color={pad_color}{out}", pad = pad_height, y = clip_y, pad_color = pad_color, out = &padded_tag));
+        final_map_tag = padded_tag;
+
+        ass_content = format!(
+            r#"[Script Info]
+PlayResX: {width}
+PlayResY: {height}
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Caption,{font_name},{font_size},{caption_colour},&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,1,{alignment},10,10,{v_margin},1
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+Dialogue: 0,0:00:00.00,9:59:59.99,Caption,,0,0,0,,{text}"#,
+            width = width, height = height + pad_height, font_name = font_name, font_size = font_size,
+            caption_colour = caption_colour, alignment = alignment, v_margin = v_margin, text = full_text
+        );
+    } else {
+        let mut last_tag = final_map_tag;
+        let mut event_lines = String::new();
+        for (i, bbox) in detected_boxes.iter().take(messages.len()).enumerate() {
+            let current_tag = format!("[v{}]", i);
+            let filter = format!(
+                "{last_tag}drawbox=x={x}:y={y}:w={w}:h={h}:color={box_fill}:t=fill{out}",
+                last_tag = &last_tag, x = bbox.x, y = bbox.y, w = bbox.w, h = bbox.h, box_fill = box_fill, out = &current_tag
+            );
+            preliminary_filters.push(filter);
+            last_tag = current_tag;
+
+            let text_to_draw = messages.get(i).unwrap_or(&messages[0]).trim();
+            let font_size = (bbox.h as f32 * 0.15).max(11.0) as u32;
+            let ass_safe_text = ass_escape(text_to_draw, config);
+            let margin_l = bbox.x;
+            let margin_r = width as i32 - (bbox.x + bbox.w as i32);
+            let margin_v = bbox.y + (bbox.h as f32 * 0.1) as i32;
+
+            let event = format!(
+                r#"Dialogue: 0,0:00:00.00,9:59:59.99,BoxStyle,,{ml},{mr},{mv},,{{\fs{fs}}}{text}"#,
+                ml = margin_l, mr = margin_r, mv = margin_v, fs = font_size, text = ass_safe_text
+            );
+            event_lines.push_str(&event);
+            event_lines.push('\n');
+        }
+        final_map_tag = last_tag;
+        ass_content = format!(
+            r#"[Script Info]
+PlayResX: {width}
+PlayResY: {height}
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: BoxStyle,{font_name},100,{box_text_colour},&H000000FF,&H00FFFFFF,&H00FFFFFF,0,0,0,0,100,100,0,0,1,0,0,8,10,10,10,1
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+{event_lines}"#,
+            width = width, height = height, font_name = font_name, box_text_colour = box_text_colour, event_lines = event_lines
+        );
+    }
+
+    let ass_path = temp_dir_path.join("subs.ass");
+    if tokio::fs::write(&ass_path, ass_content).await.is_err() {
+        report_inline_error(&bot, &inline_message_id, "❌ Error: Could not write temporary subtitle file.").await;
+        return JobOutcome::Failed;
+    }
+
+    let escaped_ass_path = ass_path.to_string_lossy().replace('\\', "/");
+
+    // Soft subtitles are muxed as a separate track below, so the video chain skips the burn-in.
+    // A GIF can't carry a subtitle track, so `/gif` always burns the text in.
+    let mut final_filter_chain = if options.soft_subs && !options.gif {
+        if preliminary_filters.is_empty() {
+            "[0:v]format=yuv420p[v_out]".to_string()
+        } else {
+            format!("{}; {}format=yuv420p[v_out]", preliminary_filters.join(";"), &final_map_tag)
+        }
+    } else if preliminary_filters.is_empty() {
+        format!("[0:v]subtitles=filename='{subs_path}', format=yuv420p[v_out]", subs_path = escaped_ass_path)
+    } else {
+        let prelim_chain = preliminary_filters.join(";");
+        format!("{prelim_chain}; {final_video_stream}subtitles=filename='{subs_path}', format=yuv420p[v_out]",
+            prelim_chain = prelim_chain,
+            final_video_stream = &final_map_tag,
+            subs_path = escaped_ass_path)
+    };
+    if let Some(speed) = options.speed {
+        // After the subtitles, so timed text switches at the time written in the original clip.
+        final_filter_chain = format!("{}; [v_speed]setpts=PTS/{}[v_out]", final_filter_chain.replace("[v_out]", "[v_speed]"), speed);
+    }
+
+    let dub_path = temp_dir_path.join("dub_audio");
+    if options.dub && !options.mute {
+        let dub_file_id = get_user_setting(&pool, user_id, SETTING_DUB_AUDIO).await;
+        let downloaded = match dub_file_id {
+            Some(dub_file_id) => download_telegram_file(&bot, &dub_file_id, &dub_path).await,
+            None => {
+                report_inline_error(&bot, &inline_message_id, "❌ Error: Send me an audio file or voice message in private chat first to use /dub.").await;
+                return JobOutcome::Rejected;
+            }
+        };
+        if !downloaded {
+            report_inline_error(&bot, &inline_message_id, "❌ Error: Failed to download your dub audio.").await;
+            return JobOutcome::Failed;
+        }
+    }
+
+    if options.gif {
+        return encode_and_deliver_gif(&bot, &pool, user_id, &inline_message_id, &file_id, &processed_video_path, &final_filter_chain, temp_dir_path, config).await.into();
+    }
+
+    // Progress is reported in output time, which `/speed` stretches or shrinks.
+    let total_secs = probe_duration(&processed_video_path).await / options.speed.unwrap_or(1.0);
+    let mut command = tokio::process::Command::new("ffmpeg");
+    if total_secs > 0.0 {
+        command.arg("-progress").arg("pipe:1").arg("-nostats");
+    }
+    command.arg("-i").arg(&processed_video_path);
+    if options.soft_subs {
+        if let Some(speed) = options.speed {
+            command.arg("-itsscale").arg((1.0 / speed).to_string());
+        }
+        command.arg("-i").arg(&ass_path);
+    }
+    let dubbed = options.dub && !options.mute;
+    if dubbed {
+        command.arg("-i").arg(&dub_path);
+    }
+    command.arg("-filter_complex").arg(&final_filter_chain).arg("-map").arg("[v_out]");
+    if options.mute {
+        command.arg("-an");
+    } else if dubbed {
+        // The dub may be a voice note or any other codec, so it's re-encoded and cut to the shorter stream.
+        let dub_input = if options.soft_subs { 2 } else { 1 };
+        command.arg("-map").arg(format!("{}:a:0", dub_input)).arg("-c:a").arg("aac").arg("-shortest");
+    } else if let Some(speed) = options.speed {
+        command.arg("-map").arg(&audio_map).arg("-filter:a").arg(atempo_chain(speed)).arg("-c:a").arg("aac");
+    } else {
+        command.arg("-map").arg(&audio_map).arg("-c:a").arg("copy");
+    }
+    if options.soft_subs {
+        // Telegram won't render a subtitle track inline, but players can toggle it in the downloaded file.
+        command.arg("-map").arg("1:s").arg("-c:s").arg("mov_text");
+    }
+
+    configure_ffmpeg_encoder(&mut command, &encoder);
+
+    command.arg("-flags").arg("+global_header").arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg(&output_path);
+
+    let encode = if total_secs > 0.0 {
+        let status = JobStatus::Inline(inline_message_id.clone());
+        run_with_progress(&mut command, temp_dir_path, &bot, &status, total_secs, config).await
+    } else {
+        run_with_disk_guard(&mut command, temp_dir_path, config).await
+    };
+    let encode_succeeded = match encode {
+        Ok(succeeded) => succeeded,
+        Err(message) => { report_inline_error(&bot, &inline_message_id, &message).await; return JobOutcome::Failed; }
+    };
+    if encode_succeeded {
+        let variant_kind = if options.mute { VARIANT_MUTED } else { "edit" };
+        deliver_inline_result(&bot, &pool, user_id, &inline_message_id, &output_path, &file_id, variant_kind, config).await.into()
+    } else {
+        let stderr = command.output().await.map(|o| String::from_utf8_lossy(&o.stderr).to_string()).unwrap_or_else(|e| e.to_string());
+        log::error!("{}FFMPEG failed. Filter: '{}'. Stderr: {}", job_tag(), final_filter_chain, stderr);
+        let message = match current_job_id() {
+            Some(id) => format!("❌ An error occurred during video processing (job {}).", id),
+            None => "❌ An error occurred during video processing.".to_string(),
+        };
+        report_inline_error(&bot, &inline_message_id, &message).await;
+        JobOutcome::Failed
+    }
+}
+
+/// Encodes the first [`MAX_GIF_SECS`] of an `/edit` as a looping palette GIF, appending the
+/// palettegen/paletteuse steps to the edit's `[v_out]` filter chain, and delivers it as an animation.
+#[allow(clippy::too_many_arguments)]
+async fn encode_and_deliver_gif(bot: &Bot, pool: &SharedState, user_id: UserId, inline_message_id: &str, file_id: &str, input_path: &Path, filter_chain: &str, temp_dir_path: &Path, config: &Config) -> bool {
+    let output_path = temp_dir_path.join("output.gif");
+    let gif_chain = format!(
+        "{}; [v_gif]fps=15,scale='min(480,iw)':-2:flags=lanczos,split[g1][g2]; [g1]palettegen[pal]; [g2][pal]paletteuse[v_out]",
+        filter_chain.replace("[v_out]", "[v_gif]")
+    );
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command.arg("-t").arg(MAX_GIF_SECS.to_string()).arg("-i").arg(input_path)
+        .arg("-filter_complex").arg(&gif_chain).arg("-map").arg("[v_out]").arg("-an")
+        .arg("-loop").arg("0").arg("-y").arg(&output_path);
+
+    match run_with_disk_guard(&mut command, temp_dir_path, config).await {
+        Ok(true) => deliver_inline_result(bot, pool, user_id, inline_message_id, &output_path, file_id, "gif", config).await,
+        Ok(false) => {
+            log::error!("{}FFMPEG GIF encode failed. Filter: '{}'", job_tag(), gif_chain);
+            report_inline_error(bot, inline_message_id, "❌ An error occurred during video processing.").await;
+            false
+        }
+        Err(message) => { report_inline_error(bot, inline_message_id, &message).await; false }
+    }
+}
+
+/// Pre-uploads a finished clip to the user's DM, then swaps it into the inline message, since
+/// inline messages can only show media that is already on Telegram.
+#[allow(clippy::too_many_arguments)]
+async fn deliver_inline_result(bot: &Bot, pool: &SharedState, user_id: UserId, inline_message_id: &str, output_path: &Path, source_file_id: &str, kind: &str, config: &Config) -> bool {
+    let is_gif = output_path.extension().is_some_and(|ext| ext == "gif");
+    let as_animation = is_gif || send_as_animation(output_path, config).await;
+    let upload = if as_animation {
+        retry_upload(|| bot.send_animation(user_id, InputFile::file(output_path)).send()).await
+    } else {
+        retry_upload(|| bot.send_video(user_id, InputFile::file(output_path)).send()).await
+    };
+    let temp_message = match upload {
+        Ok(msg) => msg,
+        Err(_) => { report_inline_error(bot, inline_message_id, "❌ Error: Could not pre-upload video.").await; return false; }
+    };
+    let uploaded_file = if as_animation { temp_message.animation().map(|a| &a.file) } else { temp_message.video().map(|v| &v.file) };
+    let Some(uploaded_file) = uploaded_file else { return false };
+    let new_video_file_id = uploaded_file.id.clone();
+    record_variant(pool, &uploaded_file.unique_id, &new_video_file_id, source_file_id, kind).await;
+    // The pre-upload doubles as the DM copy for users who asked to keep one.
+    if !get_user_flag(pool, user_id, SETTING_DM_COPY).await {
+        bot.delete_message(user_id, temp_message.id).await.ok();
+    }
+    let media = if as_animation {
+        InputMedia::Animation(InputMediaAnimation::new(InputFile::file_id(new_video_file_id)))
+    } else {
+        InputMedia::Video(InputMediaVideo::new(InputFile::file_id(new_video_file_id)))
+    };
+    if bot.edit_message_media_inline(inline_message_id, media).await.is_err() {
+        log::warn!("Failed to edit inline message.");
+        return false;
+    }
+    true
+}
+
+/// Default length of each fade for `/fade`, in seconds.
+const DEFAULT_FADE_SECS: f64 = 0.5;
+
+/// Parses the optional fade length after `/fade`. Returns `None` for anything but a positive number.
+fn parse_fade_duration(raw: &str) -> Option<f64> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Some(DEFAULT_FADE_SECS);
+    }
+    raw.trim_end_matches('s').parse::<f64>().ok().filter(|secs| *secs > 0.0 && secs.is_finite())
+}
+
+/// Adds a fade in from black at the start and a fade out at the end of a saved clip, cropping
+/// borders first like `/edit` does, and swaps the result into the inline message.
+async fn perform_fade(bot: Bot, user_id: UserId, inline_message_id: String, file_id: String, fade_secs: f64, pool: SharedState, config: &Config) -> JobOutcome {
+    let temp_dir = match Builder::new().prefix("video_fade").tempdir() {
+        Ok(dir) => dir,
+        Err(e) => { log::error!("Failed to create temp dir: {}", e); return JobOutcome::Failed; }
+    };
+    let temp_dir_path = temp_dir.path();
+    let input_path = temp_dir_path.join("input.mp4");
+    let output_path = temp_dir_path.join("output.mp4");
+
+    if !download_telegram_file(&bot, &file_id, &input_path).await {
+        report_inline_error(&bot, &inline_message_id, "❌ Error: Failed to download the video.").await;
+        return JobOutcome::Failed;
+    }
+
+    let encoder = &config.encoder;
+    let input_path = apply_rotation(&input_path, temp_dir_path, encoder).await;
+
+    let duration = probe_duration(&input_path).await;
+    if duration <= 0.0 {
+        report_inline_error(&bot, &inline_message_id, "❌ Error: Could not determine the video length.").await;
+        return JobOutcome::Rejected;
+    }
+    if fade_secs * 2.0 > duration {
+        let message = format!("❌ Error: A {}s fade in and out doesn't fit in this {:.1}s clip.", fade_secs, duration);
+        report_inline_error(&bot, &inline_message_id, &message).await;
+        return JobOutcome::Rejected;
+    }
+
+    let fade_out_start = duration - fade_secs;
+    let mut video_filters = vec![];
+    if let Some(crop) = run_cropdetect(&input_path, config).await {
+        video_filters.push(format!("crop={}:{}:{}:{}", crop.w, crop.h, crop.x, crop.y));
+    }
+    video_filters.push(format!("fade=t=in:st=0:d={}", fade_secs));
+    video_filters.push(format!("fade=t=out:st={:.3}:d={}", fade_out_start, fade_secs));
+    let audio_filter = format!("afade=t=in:st=0:d={d},afade=t=out:st={st:.3}:d={d}", d = fade_secs, st = fade_out_start);
+
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command.arg("-i").arg(&input_path).arg("-vf").arg(video_filters.join(","))
+        .arg("-map").arg("0:v:0").arg("-map").arg("0:a?").arg("-af").arg(&audio_filter);
+    configure_ffmpeg_encoder(&mut command, encoder);
+    command.arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg("-y").arg(&output_path);
+
+    match run_with_disk_guard(&mut command, temp_dir_path, config).await {
+        Ok(true) => deliver_inline_result(&bot, &pool, user_id, &inline_message_id, &output_path, &file_id, "fade", config).await.into(),
+        Ok(false) => { report_inline_error(&bot, &inline_message_id, "❌ An error occurred during video processing.").await; JobOutcome::Failed }
+        Err(message) => { report_inline_error(&bot, &inline_message_id, &message).await; JobOutcome::Failed }
+    }
+}
+
+/// How many videos one `/croptest` run covers.
+const CROP_TEST_LIMIT: i64 = 20;
+
+/// Crop detection results for one clip, without cropping anything.
+async fn crop_test_line(name: &str, input_path: &Path, temp_dir_path: &Path, config: &Config) -> String {
+    let Some((width, height)) = probe_dimensions(input_path).await else {
+        return format!("{} | unreadable", name);
+    };
+    let show = |crop: Option<CropRect>| match crop {
+        Some(c) if (c.w, c.h) != (width, height) => format!("{}x{}+{}+{}", c.w, c.h, c.x, c.y),
+        _ => "none".to_string(),
+    };
+    let motion = detect_two_frame_crop(input_path, temp_dir_path, config.motion_thresholds).await;
+    let cropdetect = run_cropdetect(input_path, config).await;
+
+    let verdict = if motion.is_some_and(|c| (c.w, c.h) != (width, height)) { "CROP" } else { "keep" };
+    format!("{} | {}x{} | motion {} | cropdetect {} | {}", name, width, height, show(motion), show(cropdetect), verdict)
+}
+
+/// Telegram's limit on one message's text, counted in UTF-16 units.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Joins lines into as few messages as fit under `max_len` UTF-16 units each. A line that is
+/// too long on its own is cut across messages.
+fn split_message_lines(lines: &[String], max_len: usize) -> Vec<String> {
+    let mut messages = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+    for line in lines {
+        let line_len = line.encode_utf16().count();
+        let separator = usize::from(!current.is_empty());
+        if current_len + separator + line_len <= max_len {
+            if separator == 1 {
+                current.push('\n');
+            }
+            current.push_str(line);
+            current_len += separator + line_len;
+            continue;
+        }
+        if !current.is_empty() {
+            messages.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        for c in line.chars() {
+            if current_len + c.len_utf16() > max_len {
+                messages.push(std::mem::take(&mut current));
+                current_len = 0;
+            }
+            current.push(c);
+            current_len += c.len_utf16();
+        }
+    }
+    if !current.is_empty() {
+        messages.push(current);
+    }
+    messages
+}
+
+/// `/croptest`: reports what crop detection finds for every video in a server folder, or for the
+/// saved videos matching a search, so threshold changes can be checked across the library.
+async fn run_crop_test(bot: Bot, chat_id: ChatId, pool: SharedState, config: Arc<Config>, target: String) {
+    let temp_dir = match Builder::new().prefix("video_croptest").tempdir() {
+        Ok(dir) => dir,
+        Err(e) => { log::error!("Failed to create temp dir: {}", e); return; }
+    };
+    let temp_dir_path = temp_dir.path();
+    let mut lines = vec!["name | size | motion | cropdetect | verdict".to_string()];
+
+    let folder = Path::new(&target);
+    if !target.is_empty() && folder.is_dir() {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(folder).map(|entries| entries.flatten().map(|e| e.path())
+            .filter(|p| p.extension().and_then(|e| e.to_str()).is_some_and(|e| matches!(e.to_lowercase().as_str(), "mp4" | "mov" | "webm" | "mkv")))
+            .collect()).unwrap_or_default();
+        files.sort();
+        for path in files.iter().take(CROP_TEST_LIMIT as usize) {
+            let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            lines.push(crop_test_line(&name, path, temp_dir_path, &config).await);
+        }
+    } else {
+        let videos: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption FROM videos WHERE caption_norm LIKE ? ESCAPE '\\' ORDER BY rowid DESC LIMIT ?")
+            .bind(format!("%{}%", escape_like_pattern(&normalize_caption(&target)))).bind(CROP_TEST_LIMIT).fetch_all(&pool).await.unwrap_or_default();
+        let input_path = temp_dir_path.join("input.mp4");
+        for video in videos {
+            if download_telegram_file(&bot, &video.file_id, &input_path).await {
+                lines.push(crop_test_line(&video.caption, &input_path, temp_dir_path, &config).await);
+            } else {
+                lines.push(format!("{} | download failed", video.caption));
+            }
+        }
+    }
+
+    if lines.len() == 1 {
+        bot.send_message(chat_id, "No videos found to test.").await.ok();
+        return;
+    }
+    for message in split_message_lines(&lines, MAX_MESSAGE_LEN) {
+        bot.send_message(chat_id, message).await.ok();
+    }
+}
+
+/// Runs box detection on the first frame of the best matching video and replies with the raw
+/// boxes and frame dimensions as JSON, for `/boxes`.
+async fn dump_detected_boxes(bot: Bot, chat_id: ChatId, pool: SharedState, config: Arc<Config>, search: String) {
+    let pattern = format!("%{}%", escape_like_pattern(&normalize_caption(&search)));
+    let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption FROM videos WHERE caption_norm LIKE ? ESCAPE '\\' LIMIT 1")
+        .bind(pattern).fetch_optional(&pool).await.unwrap_or_default() else {
+        bot.send_message(chat_id, "No video matches that search.").await.ok();
+        return;
+    };
+
+    let temp_dir = match Builder::new().prefix("video_boxes").tempdir() {
+        Ok(dir) => dir,
+        Err(e) => { log::error!("Failed to create temp dir: {}", e); return; }
+    };
+    let input_path = temp_dir.path().join("input.mp4");
+    let frame_path = temp_dir.path().join("frame.png");
+
+    if !download_telegram_file(&bot, &video.file_id, &input_path).await {
+        bot.send_message(chat_id, "❌ Error: Failed to download the video.").await.ok();
+        return;
+    }
+    let frame_status = tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(&input_path).arg("-vframes").arg("1").arg("-y").arg(&frame_path).status().await;
+    if !frame_status.is_ok_and(|s| s.success()) {
+        bot.send_message(chat_id, "❌ Error: Failed to extract frame.").await.ok();
+        return;
+    }
+
+    let (width, height) = image::image_dimensions(&frame_path).unwrap_or((0, 0));
+    let report = serde_json::json!({
+        "caption": video.caption,
+        "frame": { "width": width, "height": height },
+        "boxes": detect_boxes_off_runtime(&frame_path, &config).await,
+    });
+    let json = serde_json::to_string_pretty(&report).unwrap_or_default();
+    bot.send_message(chat_id, json).await.ok();
+}
+
+/// Extracts a single frame at `time` seconds, scaled down to Telegram's 320px thumbnail limit.
+async fn extract_thumbnail_frame(input_path: &Path, time: f64, output_path: &Path) -> bool {
+    tokio::process::Command::new("ffmpeg")
+        .arg("-ss").arg(time.to_string()).arg("-i").arg(input_path)
+        .arg("-vframes").arg("1").arg("-vf").arg("scale='min(320,iw)':-2")
+        .arg("-q:v").arg("4").arg("-y").arg(output_path)
+        .status().await.is_ok_and(|s| s.success())
+}
+
+/// Re-uploads one of the user's saved videos with the frame at `time` as its thumbnail.
+/// Telegram ignores custom thumbnails when re-sending by file id and cached inline results can't
+/// carry one, so the video is uploaded again to the user's DM and the library entry, along with
+/// its edited variants, is pointed at the new file id whose own thumbnail is the chosen frame.
+async fn set_custom_thumbnail(bot: Bot, chat_id: ChatId, user_id: UserId, pool: SharedState, search: String, time: f64) {
+    let search_norm = normalize_caption(&search);
+    let Some(video) = sqlx::query_as::<_, VideoData>(
+        "SELECT file_id, caption FROM videos WHERE user_id = ? AND caption_norm LIKE ? ESCAPE '\\' \
+         ORDER BY caption_norm = ? DESC, length(caption) ASC LIMIT 1")
+        .bind(user_id.0 as i64).bind(format!("%{}%", escape_like_pattern(&search_norm))).bind(&search_norm)
+        .fetch_optional(&pool).await.unwrap_or_default() else {
+        bot.send_message(chat_id, "None of your saved videos match that search.").await.ok();
+        return;
+    };
+
+    let temp_dir = match Builder::new().prefix("video_thumb").tempdir() {
+        Ok(dir) => dir,
+        Err(e) => { log::error!("Failed to create temp dir: {}", e); return; }
+    };
+    let input_path = temp_dir.path().join("input.mp4");
+    let thumb_path = temp_dir.path().join("thumb.jpg");
+
+    if !download_telegram_file(&bot, &video.file_id, &input_path).await {
+        bot.send_message(chat_id, "❌ Error: Failed to download the video.").await.ok();
+        return;
+    }
+    if !extract_thumbnail_frame(&input_path, time, &thumb_path).await {
+        bot.send_message(chat_id, "❌ Error: Failed to extract a frame at that time.").await.ok();
+        return;
+    }
+
+    let sent = bot.send_video(ChatId(user_id.0 as i64), InputFile::file(&input_path))
+        .thumb(InputFile::file(&thumb_path))
+        .caption(truncate_caption(&video.caption))
+        .await;
+    let Some(new_video) = sent.ok().as_ref().and_then(|m| m.video()).cloned() else {
+        bot.send_message(chat_id, "❌ Error: Failed to upload the video with the new thumbnail. Make sure you've started a DM with me.").await.ok();
+        return;
+    };
+    let Some(thumb) = new_video.thumb else {
+        bot.send_message(chat_id, "❌ Error: Telegram didn't accept the new thumbnail.").await.ok();
+        return;
+    };
+
+    let result = async {
+        let mut tx = pool.begin().await?;
+        let updated = sqlx::query("UPDATE videos SET file_id = ?, thumb_file_id = ? WHERE file_id = ? AND user_id = ?")
+            .bind(&new_video.file.id).bind(&thumb.file.id).bind(&video.file_id).bind(user_id.0 as i64)
+            .execute(&mut *tx).await?.rows_affected();
+        sqlx::query("UPDATE variants SET source_file_id = ? WHERE source_file_id = ?")
+            .bind(&new_video.file.id).bind(&video.file_id).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(updated)
+    }.await;
+    match result {
+        Ok(0) => { bot.send_message(chat_id, format!("❌ Error: \"{}\" was deleted in the meantime.", video.caption)).await.ok(); }
+        Ok(_) => { bot.send_message(chat_id, format!("✅ Thumbnail updated for \"{}\".", video.caption)).await.ok(); }
+        Err(e) => {
+            log::error!("Failed to store custom thumbnail: {}", e);
+            bot.send_message(chat_id, "❌ Error saving the new thumbnail.").await.ok();
+        }
+    }
+}
+
+/// Side of the square Telegram renders video notes at.
+const VIDEO_NOTE_SIZE: u32 = 384;
+
+/// Telegram's limit on a video note's length.
+const MAX_VIDEO_NOTE_SECS: f64 = 60.0;
+
+/// Center-crops a saved video to a square and sends it back as a round video note, reporting
+/// on the `/note` dialog message.
+async fn send_as_video_note(bot: Bot, chat_id: ChatId, status_id: MessageId, file_id: String, config: Arc<Config>) {
+    let temp_dir = match Builder::new().prefix("video_note").tempdir() {
+        Ok(dir) => dir,
+        Err(e) => { log::error!("{}Failed to create temp dir: {}", job_tag(), e); return; }
+    };
+    let input_path = temp_dir.path().join("input.mp4");
+    let output_path = temp_dir.path().join("note.mp4");
+
+    if !download_telegram_file(&bot, &file_id, &input_path).await {
+        bot.edit_message_text(chat_id, status_id, "❌ Error: Failed to download the video.").await.ok();
+        return;
+    }
+    let duration = probe_duration(&input_path).await;
+    if duration > MAX_VIDEO_NOTE_SECS {
+        let message = format!("❌ Error: Video notes can be at most {:.0} seconds long, this video is {:.0}.", MAX_VIDEO_NOTE_SECS, duration);
+        bot.edit_message_text(chat_id, status_id, message).await.ok();
+        return;
+    }
+
+    let filter = format!("crop='min(iw,ih)':'min(iw,ih)',scale={0}:{0},setsar=1", VIDEO_NOTE_SIZE);
+    let encoded = run_with_disk_guard(tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(&input_path).arg("-map").arg("0:v:0").arg("-map").arg("0:a?")
+        .arg("-vf").arg(&filter)
+        .arg("-c:v").arg("libx264").arg("-preset").arg("veryfast").arg("-pix_fmt").arg("yuv420p")
+        .arg("-c:a").arg("aac").arg("-movflags").arg("+faststart").arg("-y").arg(&output_path),
+        temp_dir.path(), &config).await;
+    match encoded {
+        Ok(true) => {}
+        Ok(false) => {
+            bot.edit_message_text(chat_id, status_id, "❌ Error: Failed to convert the video to a video note.").await.ok();
+            return;
+        }
+        Err(message) => {
+            bot.edit_message_text(chat_id, status_id, message).await.ok();
+            return;
+        }
+    }
+
+    let upload = retry_upload(|| bot.send_video_note(chat_id, InputFile::file(&output_path))
+        .length(VIDEO_NOTE_SIZE).duration(duration.ceil() as u32).send()).await;
+    match upload {
+        Ok(_) => { bot.delete_message(chat_id, status_id).await.ok(); }
+        Err(e) => {
+            log::error!("{}Failed to upload video note: {}", job_tag(), e);
+            bot.edit_message_text(chat_id, status_id, "❌ Error: Failed to upload the video note.").await.ok();
+        }
+    }
+}
+
+
+// --- Retryable Jobs ---
+
+/// Everything needed to re-run a background job from a "🔁 Retry" button, stored as JSON in `failed_jobs`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RetryableJob {
+    Edit { inline_message_id: String, file_id: String, spec: EditSpec, options: EditOptions },
+    Fade { inline_message_id: String, file_id: String, fade_secs: f64 },
+    Download { chat_id: i64, user_message_id: i32, status_message_id: i32, url: String, caption: String, options: SaveOptions },
+}
+
+impl RetryableJob {
+    fn kind(&self) -> &'static str {
+        match self {
+            RetryableJob::Edit { .. } => "edit",
+            RetryableJob::Fade { .. } => "fade",
+            RetryableJob::Download { .. } => "download",
+        }
+    }
+}
+
+/// How a background job ended. Only `Failed` jobs get a retry button; `Rejected` ones, like a
+/// private video or a clip that's too long, would fail the same way again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JobOutcome {
+    Done,
+    Failed,
+    Rejected,
+}
+
+impl From<bool> for JobOutcome {
+    fn from(succeeded: bool) -> Self {
+        if succeeded { JobOutcome::Done } else { JobOutcome::Failed }
+    }
+}
+
+/// How long a failed job can still be retried from its button.
+const FAILED_JOB_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn spawn_retryable_job(bot: Bot, pool: SharedState, config: Arc<Config>, user_id: UserId, job: RetryableJob) {
+    let status = match &job {
+        RetryableJob::Edit { inline_message_id, .. } | RetryableJob::Fade { inline_message_id, .. } => JobStatus::Inline(inline_message_id.clone()),
+        RetryableJob::Download { chat_id, status_message_id, .. } => JobStatus::Chat(ChatId(*chat_id), MessageId(*status_message_id)),
+    };
+    tokio::spawn(run_queued_job(bot.clone(), pool.clone(), config.clone(), user_id, job.kind(), status, run_retryable_job(bot, pool, config, user_id, job)));
+}
+
+/// Runs a job; if it fails for a reason that might go away, its parameters are kept and a retry
+/// button is added to the message that shows the error.
+async fn run_retryable_job(bot: Bot, pool: SharedState, config: Arc<Config>, user_id: UserId, job: RetryableJob) {
+    let outcome = match job.clone() {
+        RetryableJob::Edit { inline_message_id, file_id, spec, options } => {
+            let outcome = perform_video_edit(bot.clone(), user_id, inline_message_id, file_id, spec, options, pool.clone(), &config).await;
+            if outcome == JobOutcome::Done {
+                metrics::counter!("edits_total").increment(1);
+            }
+            outcome
+        }
+        RetryableJob::Fade { inline_message_id, file_id, fade_secs } =>
+            perform_fade(bot.clone(), user_id, inline_message_id, file_id, fade_secs, pool.clone(), &config).await,
+        RetryableJob::Download { chat_id, user_message_id, status_message_id, url, caption, options } =>
+            download_and_process_video(
+                bot.clone(), ChatId(chat_id), MessageId(user_message_id), MessageId(status_message_id),
+                url, caption, pool.clone(), user_id, options, &config,
+            ).await,
+    };
+    if outcome == JobOutcome::Failed {
+        offer_retry(&bot, &pool, user_id, &job).await;
+    }
+}
+
+async fn offer_retry(bot: &Bot, pool: &SharedState, user_id: UserId, job: &RetryableJob) {
+    let Ok(job_json) = serde_json::to_string(job) else { return };
+    let expired_before = unix_now() - FAILED_JOB_TTL.as_secs() as i64;
+    sqlx::query("DELETE FROM failed_jobs WHERE created_at < ?").bind(expired_before).execute(pool).await.ok();
+    let job_id = match sqlx::query("INSERT INTO failed_jobs (user_id, job, created_at) VALUES (?, ?, ?)")
+        .bind(user_id.0 as i64).bind(job_json).bind(unix_now()).execute(pool).await {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) => { log::error!("Failed to store failed {} job: {}", job.kind(), e); return; }
+    };
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("🔁 Retry", format!("retry_{}", job_id))]]);
+    let result = match job {
+        RetryableJob::Edit { inline_message_id, .. } | RetryableJob::Fade { inline_message_id, .. } =>
+            bot.edit_message_reply_markup_inline(inline_message_id).reply_markup(keyboard).await.map(|_| ()),
+        RetryableJob::Download { chat_id, status_message_id, .. } =>
+            bot.edit_message_reply_markup(ChatId(*chat_id), MessageId(*status_message_id)).reply_markup(keyboard).await.map(|_| ()),
+    };
+    if let Err(e) = result {
+        log::warn!("Failed to attach retry button: {}", e);
+    }
+}
+
+/// Re-runs a stored failed job for the user who pressed its retry button. The stored copy is
+/// claimed by deleting it, so a double tap runs the job once and a second failure gets a fresh
+/// button. Returns false when the job is gone or older than `FAILED_JOB_TTL`.
+async fn retry_failed_job(bot: Bot, pool: SharedState, config: Arc<Config>, user_id: UserId, job_id: i64) -> bool {
+    let expired_before = unix_now() - FAILED_JOB_TTL.as_secs() as i64;
+    let claimed: Option<String> = sqlx::query_scalar("DELETE FROM failed_jobs WHERE id = ? AND user_id = ? AND created_at >= ? RETURNING job")
+        .bind(job_id).bind(user_id.0 as i64).bind(expired_before).fetch_optional(&pool).await.unwrap_or_default();
+    let Some(job) = claimed.and_then(|json| serde_json::from_str::<RetryableJob>(&json).ok()) else { return false };
+
+    match &job {
+        RetryableJob::Edit { inline_message_id, .. } | RetryableJob::Fade { inline_message_id, .. } => {
+            report_inline_error(&bot, inline_message_id, "⚙️ Retrying...").await;
+        }
+        RetryableJob::Download { chat_id, status_message_id, .. } => {
+            bot.edit_message_text(ChatId(*chat_id), MessageId(*status_message_id), "⏳ Retrying download...").await.ok();
+        }
+    }
+    spawn_retryable_job(bot, pool, config, user_id, job);
+    true
+}
+
+// --- Configuration ---
+
+/// Settings read from the environment once in `main`. Handlers get it through the dispatcher's
+/// dependencies and hand it to the jobs they start, so a job sees the same values throughout.
+#[derive(Debug)]
+struct Config {
+    /// The operator's user id from `ADMIN_USER_ID`, if configured.
+    admin_user_id: Option<UserId>,
+    /// Chat ids from the comma-separated `ALLOWED_CHATS`.
+    allowed_chats: Vec<ChatId>,
+    /// The encoder for jobs without a `/cpu` or `/gpu` override, see `EncoderChoice::from_env`.
+    encoder: EncoderChoice,
+    /// The font `/edit` renders text with, see `universal_font_path`.
+    font_path: PathBuf,
+    /// Whether that font has a word joiner glyph, see `font_has_word_joiner`.
+    font_has_word_joiner: bool,
+    /// How many text boxes `/edit` fills at most, from `MAX_EDIT_BOXES` (default 4).
+    max_edit_boxes: usize,
+    /// Longest video `/edit` accepts, from `MAX_EDIT_DURATION_SECS`, so one long clip can't hold a
+    /// job slot for ages.
+    max_edit_duration_secs: f64,
+    /// Cap on the size of a job's temp dir while encoding, from `MAX_JOB_DISK_MB`.
+    max_job_disk_bytes: u64,
+    /// Telegram's upload limit for bots, from `MAX_UPLOAD_MB` (default 50).
+    max_upload_bytes: u64,
+    /// Time between `-progress` percentage updates on a job's status, from `PROGRESS_UPDATE_SECS`.
+    /// Kept to at least 3s to stay clear of Telegram's edit rate limits.
+    progress_update_interval: Duration,
+    /// How many processing jobs run at once, from `MAX_CONCURRENT_JOBS` (default 2).
+    max_concurrent_jobs: usize,
+    /// How long shutdown waits for running jobs, from `SHUTDOWN_TIMEOUT_SECS`.
+    shutdown_timeout: Duration,
+    /// `SILENT_CLIP_POLICY=animation` sends clips without sound as animations, which some clients
+    /// autoplay and loop. The default, `video`, always sends regular videos.
+    silent_clips_as_animation: bool,
+    /// The `cropdetect` filter built from `CROPDETECT_LIMIT` (black threshold, default 24),
+    /// `CROPDETECT_ROUND` (default 16) and `CROPDETECT_RESET` (default 0). Raise the limit if dark
+    /// scenes get cropped away.
+    cropdetect_filter: String,
+    /// Clips up to `CROP_MIN_DURATION` seconds are too short for motion crop detection.
+    crop_min_duration: f64,
+    /// `CROP_SHORT_CLIP_CROPDETECT=1` runs cropdetect on those short clips instead.
+    crop_short_clip_cropdetect: bool,
+    /// `CROP_SCENE_SAMPLING=1` compares frames from different scenes instead of the first two.
+    crop_scene_sampling: bool,
+    /// `CROP_PAD_TO_ASPECT=1` pads cropped videos back to their original aspect ratio.
+    crop_pad_to_aspect: bool,
+    /// Tuning for `detect_motion_crop`, see `MotionThresholds::from_env`.
+    motion_thresholds: MotionThresholds,
+    /// Size limits for detected text boxes, see `BoxRatios::from_env`.
+    box_ratios: BoxRatios,
+    /// How many videos `/recent` lists, from `RECENT_LIMIT`.
+    recent_limit: i64,
+    /// `PRIVATE_LIBRARIES=1` limits every search to the searching user's own videos.
+    private_libraries: bool,
+    /// `CAPTION_TRANSLITERATION=1` indexes captions in Latin script too, so Latin queries find
+    /// Cyrillic captions and the other way round.
+    transliteration: bool,
+    /// How many of the newest prefiltered videos the fuzzy search ranks, from `FUZZY_CANDIDATE_LIMIT`.
+    fuzzy_candidate_limit: i64,
+    /// The global `CAPTION_TEMPLATE`, e.g. "{caption} — via @mychannel". Unset or empty means
+    /// captions are shown as saved.
+    caption_template: Option<String>,
+    /// Download tokens per user, from `DOWNLOAD_RATE_LIMIT` (default 5).
+    download_rate_limit: u32,
+    /// How long an empty bucket takes to refill, from `DOWNLOAD_RATE_WINDOW_SECS` (default 60).
+    download_rate_window_secs: u64,
+    /// Cookie files for yt-dlp by domain, from `COOKIE_FILES`: comma-separated `domain=path`
+    /// pairs, `instagram.com=./instacookie` by default.
+    cookie_files: Vec<(String, PathBuf)>,
+    /// The speech-to-text endpoint for `/transcribe`, from `TRANSCRIBE_URL`.
+    transcribe_url: Option<String>,
+    /// How long one speech-to-text request may take, from `TRANSCRIBE_TIMEOUT_SECS`.
+    transcribe_timeout: Duration,
+}
+
+impl Config {
+    /// Reads the configuration. Fails only if the `/edit` font is missing; everything else falls
+    /// back to its default. Needs `detect_encoder_support` to have run for the default encoder.
+    fn from_env() -> Result<Self, String> {
+        let cropdetect_limit: f64 = env_parse("CROPDETECT_LIMIT", 24.0);
+        let cropdetect_round: u32 = env_parse("CROPDETECT_ROUND", 16);
+        let cropdetect_reset: u32 = env_parse("CROPDETECT_RESET", 0);
+        let cookie_files = env::var("COOKIE_FILES").unwrap_or_else(|_| "instagram.com=./instacookie".to_string())
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(domain, path)| (domain.trim().to_lowercase(), PathBuf::from(path.trim())))
+            .collect();
+        let font_path = universal_font_path()?;
+        Ok(Config {
+            admin_user_id: env::var("ADMIN_USER_ID").ok().and_then(|v| v.trim().parse::<u64>().ok()).map(UserId),
+            allowed_chats: env::var("ALLOWED_CHATS").unwrap_or_default()
+                .split(',')
+                .filter_map(|s| s.trim().parse::<i64>().ok())
+                .map(ChatId)
+                .collect(),
+            encoder: EncoderChoice::from_env(),
+            font_has_word_joiner: font_has_word_joiner(&font_path),
+            font_path,
+            max_edit_boxes: env_parse("MAX_EDIT_BOXES", 4usize).max(1),
+            max_edit_duration_secs: env_parse("MAX_EDIT_DURATION_SECS", 180.0),
+            max_job_disk_bytes: env_parse("MAX_JOB_DISK_MB", 2048u64) * 1024 * 1024,
+            max_upload_bytes: env_parse("MAX_UPLOAD_MB", 50u64) * 1024 * 1024,
+            progress_update_interval: Duration::from_secs(env_parse("PROGRESS_UPDATE_SECS", 5u64).max(3)),
+            max_concurrent_jobs: env_parse("MAX_CONCURRENT_JOBS", 2usize).max(1),
+            shutdown_timeout: Duration::from_secs(env_parse("SHUTDOWN_TIMEOUT_SECS", 60u64)),
+            silent_clips_as_animation: env::var("SILENT_CLIP_POLICY").is_ok_and(|policy| policy.eq_ignore_ascii_case("animation")),
+            cropdetect_filter: format!("cropdetect={}:{}:{}", cropdetect_limit, cropdetect_round, cropdetect_reset),
+            crop_min_duration: env_parse("CROP_MIN_DURATION", 1.5),
+            crop_short_clip_cropdetect: env_flag("CROP_SHORT_CLIP_CROPDETECT"),
+            crop_scene_sampling: env_flag("CROP_SCENE_SAMPLING"),
+            crop_pad_to_aspect: env_flag("CROP_PAD_TO_ASPECT"),
+            motion_thresholds: MotionThresholds::from_env(),
+            box_ratios: BoxRatios::from_env(),
+            recent_limit: env_parse("RECENT_LIMIT", 10),
+            private_libraries: env_flag("PRIVATE_LIBRARIES"),
+            transliteration: env_flag("CAPTION_TRANSLITERATION"),
+            fuzzy_candidate_limit: env_parse("FUZZY_CANDIDATE_LIMIT", 5000),
+            caption_template: env::var("CAPTION_TEMPLATE").ok().filter(|t| !t.trim().is_empty()),
+            download_rate_limit: env_parse("DOWNLOAD_RATE_LIMIT", 5u32).max(1),
+            download_rate_window_secs: env_parse("DOWNLOAD_RATE_WINDOW_SECS", 60u64).max(1),
+            cookie_files,
+            transcribe_url: env::var("TRANSCRIBE_URL").ok().filter(|url| !url.trim().is_empty()),
+            transcribe_timeout: Duration::from_secs(env_parse("TRANSCRIBE_TIMEOUT_SECS", 120u64)),
+        })
+    }
+
+    fn is_admin(&self, user_id: UserId) -> bool {
+        self.admin_user_id == Some(user_id)
+    }
+
+    /// With `PRIVATE_LIBRARIES=1`, the user whose videos a search is limited to. `None` means
+    /// the shared pool, matched in SQL with `(? IS NULL OR user_id = ?)`.
+    fn library_owner(&self, user_id: UserId) -> Option<i64> {
+        self.private_libraries.then_some(user_id.0 as i64)
+    }
+}
+
+/// Shows an env var for `/config`: the value, `(unset)`, or `(set, redacted)` for secrets.
+fn config_value(name: &str, secret: bool) -> String {
+    match env::var(name) {
+        Ok(value) if value.is_empty() => "(empty)".to_string(),
+        Ok(_) if secret => "(set, redacted)".to_string(),
+        Ok(value) => value,
+        Err(_) => "(unset)".to_string(),
+    }
+}
+
+/// The resolved configuration as plain text, for `/config`. Values are shown after defaults
+/// are applied, so this reflects what the bot is actually doing.
+async fn describe_config(pool: &SharedState, config: &Config) -> String {
+    let autocrop_disabled = get_global_flag(pool, SETTING_AUTOCROP_DISABLED).await;
+    let blocklist_len = CAPTION_BLOCKLIST.read().unwrap().len();
+    let or_unset = |values: Vec<String>| if values.is_empty() { "(unset)".to_string() } else { values.join(", ") };
+    let lines = [
+        "⚙️ Active configuration".to_string(),
+        String::new(),
+        "Encoding".to_string(),
+        format!("• encoder: {:?}", config.encoder),
+        format!("• detected hardware: {}", ENCODER_SUPPORT.get().map(|s| format!("{:?}", s)).unwrap_or_else(|| "(not probed)".to_string())),
+        format!("• silent clips sent as: {}", if config.silent_clips_as_animation { "animation" } else { "video" }),
+        format!("• job disk cap: {} MB", config.max_job_disk_bytes / 1024 / 1024),
+        format!("• upload limit: {} MB", config.max_upload_bytes / 1024 / 1024),
+        format!("• max edit duration: {}s", config.max_edit_duration_secs),
+        format!("• max edit boxes: {}", config.max_edit_boxes),
+        format!("• concurrent jobs: {}", config.max_concurrent_jobs),
+        format!("• progress updates: every {}s", config.progress_update_interval.as_secs()),
+        format!("• font: {}", config.font_path.display()),
+        String::new(),
+        "Cropping".to_string(),
+        format!("• auto-crop: {}", if autocrop_disabled { "disabled" } else { "enabled" }),
+        format!("• scene sampling: {}", config.crop_scene_sampling),
+        format!("• min duration: {}s", config.crop_min_duration),
+        format!("• short clip cropdetect: {}", config.crop_short_clip_cropdetect),
+        format!("• cropdetect filter: {}", config.cropdetect_filter),
+        format!("• pad to original aspect: {}", config.crop_pad_to_aspect),
+        format!("• motion thresholds: pixel {}, line {}", config.motion_thresholds.pixel_change, config.motion_thresholds.line_motion_percent),
+        format!("• min text box size: {} x {} of the frame", config.box_ratios.min_width, config.box_ratios.min_height),
+        String::new(),
+        "Library".to_string(),
+        format!("• inline page size: {}", INLINE_PAGE_SIZE),
+        format!("• remove page size: {}", REMOVE_PAGE_SIZE),
+        format!("• recent limit: {}", config.recent_limit),
+        format!("• private libraries: {}", config.private_libraries),
+        format!("• transliterated search: {}", config.transliteration),
+        format!("• fuzzy search candidates: {}", config.fuzzy_candidate_limit),
+        format!("• caption template: {}", config.caption_template.as_deref().unwrap_or("(unset)")),
+        format!("• blocklist: {} entries (file: {})", blocklist_len, config_value("CAPTION_BLOCKLIST_FILE", false)),
+        String::new(),
+        "Access".to_string(),
+        format!("• admin user id: {}", config.admin_user_id.map(|id| id.to_string()).unwrap_or_else(|| "(unset)".to_string())),
+        format!("• allowed chats (env): {}", or_unset(config.allowed_chats.iter().map(|c| c.to_string()).collect())),
+        format!("• download rate limit: {} per {}s", config.download_rate_limit, config.download_rate_window_secs),
+        String::new(),
+        "Services".to_string(),
+        format!("• bot token: {}", config_value("TELOXIDE_TOKEN", true)),
+        format!("• database: {}", config_value("DATABASE_URL", true)),
+        format!("• transcription endpoint: {}", if config.transcribe_url.is_some() { "(set, redacted)" } else { "(unset)" }),
+        format!("• transcription timeout: {}s", config.transcribe_timeout.as_secs()),
+        format!("• cookie files: {}", or_unset(config.cookie_files.iter().map(|(domain, path)| format!("{}={}", domain, path.display())).collect())),
+        format!("• healthcheck port: {}", config_value("HEALTHCHECK_PORT", false)),
+        format!("• metrics port: {}", config_value("METRICS_PORT", false)),
+        format!("• webhook: {} (port {})", config_value("WEBHOOK_URL", true), env_parse("WEBHOOK_PORT", 8443u16)),
+    ];
+    lines.join("\n")
+}
+
+// --- Library Export ---
+
+/// One saved video in an `/export` backup.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ExportedVideo {
+    file_id: String,
+    caption: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+    /// Only written for animations and audio, so `/import` stores them with the right inline result type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+}
+
+/// Rows fetched per query while building an export.
+const EXPORT_CHUNK_SIZE: i64 = 500;
+
+/// Serializes the user's own videos as a JSON array, fetching them in chunks so large
+/// libraries aren't loaded into memory twice. Returns the document and the number of videos.
+async fn export_user_videos(pool: &SharedState, user_id: UserId) -> Result<(Vec<u8>, usize), sqlx::Error> {
+    let mut document = b"[".to_vec();
+    let mut count = 0;
+    let mut last_rowid = 0i64;
+    loop {
+        let rows: Vec<(i64, String, String, Option<String>, String)> = sqlx::query_as(
+            "SELECT rowid, file_id, caption, tags, kind FROM videos WHERE user_id = ? AND rowid > ? ORDER BY rowid LIMIT ?")
+            .bind(user_id.0 as i64).bind(last_rowid).bind(EXPORT_CHUNK_SIZE).fetch_all(pool).await?;
+        let Some((rowid, ..)) = rows.last() else { break };
+        last_rowid = *rowid;
+        for (_, file_id, caption, tags, kind) in rows {
+            let kind = (kind == KIND_ANIMATION || kind == KIND_AUDIO).then_some(kind);
+            if count > 0 {
+                document.push(b',');
+            }
+            document.extend_from_slice(b"\n  ");
+            serde_json::to_writer(&mut document, &ExportedVideo { file_id, caption, tags, kind }).expect("serializing strings can't fail");
+            count += 1;
+        }
+    }
+    document.extend_from_slice(b"\n]\n");
+    Ok((document, count))
+}
+
+/// Largest `/import` document accepted, in bytes.
+const MAX_IMPORT_BYTES: u32 = 10 * 1024 * 1024;
+
+/// Telegram file ids are URL-safe base64 and a few dozen characters long.
+fn looks_like_file_id(file_id: &str) -> bool {
+    (20..=200).contains(&file_id.len()) && file_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Restores an `/export` document into the user's library. Entries that already exist are
+/// skipped. Returns how many were imported and how many were skipped.
+async fn import_user_videos(bot: &Bot, pool: &SharedState, config: &Config, user_id: UserId, document: &Document) -> Result<(usize, usize), String> {
+    if document.file.size > MAX_IMPORT_BYTES {
+        return Err(format!("❌ Error: The file is too large to import (the limit is {}).", format_size(MAX_IMPORT_BYTES as i64)));
+    }
+    let file = bot.get_file(&document.file.id).await.map_err(|_| "❌ Error: Failed to download the file.".to_string())?;
+    let mut contents = Vec::new();
+    bot.download_file(&file.path, &mut contents).await.map_err(|_| "❌ Error: Failed to download the file.".to_string())?;
+    let videos: Vec<ExportedVideo> = serde_json::from_slice(&contents)
+        .map_err(|e| format!("❌ Error: This isn't a valid /export file ({}).", e))?;
+    if let Some(bad) = videos.iter().find(|v| !looks_like_file_id(&v.file_id) || v.caption.trim().is_empty()) {
+        return Err(format!("❌ Error: The entry for \"{}\" has a missing caption or malformed file id. Nothing was imported.", bad.caption));
+    }
+
+    let (mut imported, mut skipped) = (0, 0);
+    for video in videos {
+        let kind = video.kind.filter(|k| k == KIND_ANIMATION || k == KIND_AUDIO).unwrap_or_else(|| "video".to_string());
+        let result = sqlx::query("INSERT OR IGNORE INTO videos (file_id, caption, user_id, caption_norm, kind, caption_translit, tags, caption_folded) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(&video.file_id).bind(&video.caption).bind(user_id.0 as i64).bind(normalize_caption(&video.caption))
+            .bind(kind).bind(caption_translit(&video.caption, config)).bind(&video.tags).bind(caption_folded(&video.caption))
+            .execute(pool).await.map_err(|_| "❌ DB error while importing videos.".to_string())?;
+        if result.rows_affected() > 0 { imported += 1 } else { skipped += 1 }
+    }
+    Ok((imported, skipped))
+}
+
+// --- Metrics ---
+
+/// Upper bounds, in seconds, of the ffmpeg encode duration histogram buckets.
+const ENCODE_BUCKETS: [f64; 8] = [1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Installs the Prometheus recorder for `METRICS_PORT`. Until it is installed the `metrics`
+/// macros are no-ops, so an unset port costs nothing.
+fn install_metrics_recorder() -> Option<PrometheusHandle> {
+    let recorder = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full("ffmpeg_encode_duration_seconds".to_string()), &ENCODE_BUCKETS)
+        .and_then(|builder| builder.install_recorder());
+    let handle = match recorder {
+        Ok(handle) => handle,
+        Err(e) => { log::error!("Failed to install the metrics recorder: {}", e); return None; }
+    };
+    metrics::describe_counter!("videos_saved_total", "Videos saved to the library.");
+    metrics::describe_counter!("edits_total", "Finished /edit jobs.");
+    metrics::describe_counter!("download_failures_total", "Link downloads that failed.");
+    metrics::describe_histogram!("ffmpeg_encode_duration_seconds", metrics::Unit::Seconds, "Wall-clock time of ffmpeg encodes.");
+    Some(handle)
+}
+
+fn record_encode_duration(duration: Duration) {
+    metrics::histogram!("ffmpeg_encode_duration_seconds").record(duration.as_secs_f64());
+}
+
+/// Runs an ffmpeg encode to completion and records how long it took.
+async fn run_encode(command: &mut tokio::process::Command) -> bool {
+    let started = Instant::now();
+    let succeeded = command.status().await.is_ok_and(|s| s.success());
+    record_encode_duration(started.elapsed());
+    succeeded
+}
+
+/// Backoff between failed `accept` calls, e.g. while the process is out of file descriptors.
+/// Listeners never give up, so `max_attempts` is unused.
+const ACCEPT_BACKOFF: BackoffConfig = BackoffConfig {
+    base: Duration::from_millis(50), max: Duration::from_secs(5), factor: 2.0, jitter: 0.2, max_attempts: u32::MAX,
+};
+
+/// Accepts the next connection, sleeping after errors instead of spinning on them.
+async fn accept_with_backoff(listener: &tokio::net::TcpListener) -> tokio::net::TcpStream {
+    let mut failures = 0;
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => return stream,
+            Err(e) => {
+                log::warn!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF.delay(failures, jitter_sample())).await;
+                failures = failures.saturating_add(1);
+            }
+        }
+    }
+}
+
+/// What an HTTP listener answers: the healthcheck, the metrics, or both when `HEALTHCHECK_PORT`
+/// and `METRICS_PORT` are the same port.
+#[derive(Clone, Default)]
+struct HttpRoutes {
+    /// The database to probe and whether Telegram's `get_me` succeeded at startup.
+    health: Option<(SharedState, bool)>,
+    metrics: Option<PrometheusHandle>,
+}
+
+impl HttpRoutes {
+    /// The status line, content type and body for a request to `path`. A port serving both
+    /// answers `/metrics` with the metrics and everything else with the healthcheck.
+    async fn respond(&self, path: &str) -> (&'static str, &'static str, String) {
+        match (&self.health, &self.metrics) {
+            (_, Some(handle)) if self.health.is_none() || path.starts_with("/metrics") => {
+                handle.run_upkeep();
+                ("200 OK", "text/plain; version=0.0.4", handle.render())
+            }
+            (Some((pool, telegram_ready)), _) => {
+                // 200 while the database answers a `SELECT 1` and `get_me` succeeded at startup.
+                let database_ok = sqlx::query("SELECT 1").execute(pool).await.is_ok();
+                if database_ok && *telegram_ready {
+                    ("200 OK", "text/plain", "ok".to_string())
+                } else {
+                    ("503 Service Unavailable", "text/plain", "unavailable".to_string())
+                }
+            }
+            (None, _) => ("404 Not Found", "text/plain", "not found".to_string()),
+        }
+    }
+}
+
+/// The path of an HTTP request from its "GET /path HTTP/1.1" line, `/` if it can't be read.
+fn request_path(request: &[u8]) -> &str {
+    std::str::from_utf8(request).ok().and_then(|text| text.split_whitespace().nth(1)).unwrap_or("/")
+}
+
+/// Serves `routes` on `port`. Unset ports mean no listener at all.
+async fn serve_http(port: u16, routes: HttpRoutes) {
+    let listener = match tokio::net::TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => { log::error!("Failed to bind HTTP port {}: {}", port, e); return; }
+    };
+    let served: Vec<&str> = [(routes.health.is_some(), "healthcheck"), (routes.metrics.is_some(), "metrics")]
+        .into_iter().filter_map(|(on, name)| on.then_some(name)).collect();
+    log::info!("Serving {} on port {}.", served.join(" and "), port);
+    loop {
+        let mut stream = accept_with_backoff(&listener).await;
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let mut request = [0u8; 1024];
+            let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut request)).await;
+            let read = read.ok().and_then(Result::ok).unwrap_or(0);
+            let (status, content_type, body) = routes.respond(request_path(&request[..read])).await;
+            let response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body);
+            stream.write_all(response.as_bytes()).await.ok();
+        });
+    }
+}
+
+// --- Deduplication ---
+
+/// Counts the user's videos that `dedup_user_videos` would remove.
+async fn count_duplicate_videos(pool: &SharedState, user_id: UserId) -> Result<i64, sqlx::Error> {
+    let user_id = user_id.0 as i64;
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM videos WHERE user_id = ? AND rowid NOT IN \
+         (SELECT MAX(rowid) FROM videos WHERE user_id = ? GROUP BY caption_norm, kind)")
+        .bind(user_id).bind(user_id).fetch_one(pool).await
+}
+
+/// Deletes the user's videos whose normalized caption and kind match a newer video of theirs,
+/// keeping the most recently saved one of each, so a clip and its `/audio` save both stay.
+/// Returns how many were removed.
+async fn dedup_user_videos(pool: &SharedState, user_id: UserId) -> Result<u64, sqlx::Error> {
+    let user_id = user_id.0 as i64;
+    let mut tx = pool.begin().await?;
+    let removed = sqlx::query(
+        "DELETE FROM videos WHERE user_id = ? AND rowid NOT IN \
+         (SELECT MAX(rowid) FROM videos WHERE user_id = ? GROUP BY caption_norm, kind)")
+        .bind(user_id).bind(user_id).execute(&mut *tx).await?.rows_affected();
+    tx.commit().await?;
+    Ok(removed)
+}
+
+// --- Broadcast ---
+
+/// Gap between broadcast messages, keeping under Telegram's ~30 messages per second limit.
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Sends `text` to every user with at least one saved video and reports how many sends worked.
+async fn broadcast_message(bot: Bot, chat_id: ChatId, pool: SharedState, text: String) {
+    let user_ids: Vec<i64> = match sqlx::query_scalar("SELECT DISTINCT user_id FROM videos").fetch_all(&pool).await {
+        Ok(ids) => ids,
+        Err(e) => {
+            log::error!("Failed to read broadcast recipients: {}", e);
+            bot.send_message(chat_id, "❌ Error: Could not read the list of users.").await.ok();
+            return;
+        }
+    };
+    let (mut sent, mut failed) = (0, 0);
+    for user_id in user_ids {
+        match bot.send_message(ChatId(user_id), &text).await {
+            Ok(_) => sent += 1,
+            Err(e) => { log::warn!("Broadcast to {} failed: {}", user_id, e); failed += 1; }
+        }
+        tokio::time::sleep(BROADCAST_INTERVAL).await;
+    }
+    bot.send_message(chat_id, format!("✅ Broadcast finished: {} sent, {} failed.", sent, failed)).await.ok();
+}
+
+// --- Job Statistics ---
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// Where a queued job reports that it is waiting for a slot.
+#[derive(Clone)]
+enum JobStatus {
+    Chat(ChatId, MessageId),
+    Inline(String),
+}
+
+impl JobStatus {
+    async fn show(&self, bot: &Bot, text: &str) {
+        match self {
+            JobStatus::Chat(chat_id, message_id) => { bot.edit_message_text(*chat_id, *message_id, text).await.ok(); }
+            JobStatus::Inline(inline_message_id) => report_inline_error(bot, inline_message_id, text).await,
+        }
+    }
+}
+
+/// Limits how many processing jobs run at once, sized by the first job from `Config::max_concurrent_jobs`.
+static JOB_SLOTS: OnceCell<tokio::sync::Semaphore> = OnceCell::new();
+
+/// Jobs currently waiting for a slot, for the "N jobs ahead" status.
+static QUEUED_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Source of the short ids that tag each job's log lines.
+static NEXT_JOB_ID: AtomicUsize = AtomicUsize::new(1);
+
+tokio::task_local! {
+    /// Id of the job the current task is running, set by `run_queued_job`.
+    static JOB_ID: usize;
+}
+
+/// Id of the job the current task is running, if any.
+fn current_job_id() -> Option<usize> {
+    JOB_ID.try_with(|id| *id).ok()
+}
+
+/// The current job's log prefix, e.g. "[job 17] ", or nothing outside a job.
+fn job_tag() -> String {
+    current_job_id().map(|id| format!("[job {}] ", id)).unwrap_or_default()
+}
+
+/// Waits for a job slot, telling the user how many jobs are ahead if none is free.
+async fn acquire_job_slot(bot: &Bot, status: &JobStatus, config: &Config) -> Option<tokio::sync::SemaphorePermit<'static>> {
+    let slots = JOB_SLOTS.get_or_init(|| tokio::sync::Semaphore::new(config.max_concurrent_jobs));
+    if let Ok(permit) = slots.try_acquire() {
+        return Some(permit);
+    }
+    let ahead = QUEUED_JOBS.fetch_add(1, Ordering::SeqCst) + config.max_concurrent_jobs;
+    status.show(bot, &format!("⏳ Queued, {} job{} ahead", ahead, if ahead == 1 { "" } else { "s" })).await;
+    let permit = slots.acquire().await.ok();
+    QUEUED_JOBS.fetch_sub(1, Ordering::SeqCst);
+    let resumed = match status {
+        JobStatus::Chat(..) => "⏳ Processing your video...",
+        JobStatus::Inline(_) => "⚙️ Processing your video...",
+    };
+    status.show(bot, resumed).await;
+    permit
+}
+
+/// Runs a background job once a slot is free and records its wall-clock duration, from slot
+/// acquisition to completion, in `jobs` for `/mytime`. The slot is held until the job returns,
+/// whichever path it returns by.
+async fn run_queued_job<F: Future<Output = ()>>(bot: Bot, pool: SharedState, config: Arc<Config>, user_id: UserId, kind: &'static str, status: JobStatus, job: F) {
+    let job_id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+    IN_FLIGHT_JOBS.lock().unwrap().insert(job_id, status.clone());
+    let _permit = acquire_job_slot(&bot, &status, &config).await;
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        IN_FLIGHT_JOBS.lock().unwrap().remove(&job_id);
+        status.show(&bot, INTERRUPTED_JOB_NOTICE).await;
+        return;
+    }
+    log::info!("[job {}] Starting {} job for user {}.", job_id, kind, user_id.0);
+    let started = Instant::now();
+    JOB_ID.scope(job_id, job).await;
+    IN_FLIGHT_JOBS.lock().unwrap().remove(&job_id);
+    let duration_ms = started.elapsed().as_millis() as i64;
+    log::info!("[job {}] Finished {} job in {}ms.", job_id, kind, duration_ms);
+    let result = sqlx::query("INSERT INTO jobs (user_id, kind, duration_ms, finished_at) VALUES (?, ?, ?, ?)")
+        .bind(user_id.0 as i64).bind(kind).bind(duration_ms).bind(unix_now()).execute(&pool).await;
+    if let Err(e) = result {
+        log::error!("Failed to record {} job duration: {}", kind, e);
+    }
+}
+
+/// Set once the dispatcher has stopped, so queued jobs don't start during shutdown.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Status of every job that is queued or running, by job id, for `drain_jobs`.
+static IN_FLIGHT_JOBS: Lazy<Mutex<HashMap<usize, JobStatus>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+const INTERRUPTED_JOB_NOTICE: &str = "⚠️ The bot restarted before this finished. Please try again.";
+
+/// Gives running jobs up to `timeout` to finish, then tells the users of any that are still
+/// unfinished that they were interrupted.
+async fn drain_jobs(bot: &Bot, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let pending = IN_FLIGHT_JOBS.lock().unwrap().len();
+    if pending > 0 {
+        log::info!("Waiting up to {}s for {} job(s) to finish.", timeout.as_secs(), pending);
+    }
+    while !IN_FLIGHT_JOBS.lock().unwrap().is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    let interrupted: Vec<JobStatus> = IN_FLIGHT_JOBS.lock().unwrap().values().cloned().collect();
+    if !interrupted.is_empty() {
+        log::warn!("Exiting with {} unfinished job(s).", interrupted.len());
+    }
+    for status in interrupted {
+        status.show(bot, INTERRUPTED_JOB_NOTICE).await;
+    }
+}
+
+fn format_duration_ms(ms: i64) -> String {
+    let secs = ms as f64 / 1000.0;
+    if secs >= 60.0 {
+        format!("{}m {:02}s", (secs / 60.0).floor(), (secs % 60.0).floor())
+    } else {
+        format!("{:.1}s", secs)
+    }
+}
+
+/// Total and average processing time per job kind for a user, over the last `days` days or all time.
+async fn processing_time_summary(pool: &SharedState, user_id: UserId, days: Option<i64>) -> Result<String, sqlx::Error> {
+    let since = days.map(|d| unix_now() - d * 86_400).unwrap_or(0);
+    let rows: Vec<(String, i64, i64)> = sqlx::query_as(
+        "SELECT kind, COUNT(*), SUM(duration_ms) FROM jobs WHERE user_id = ? AND finished_at >= ? GROUP BY kind ORDER BY kind")
+        .bind(user_id.0 as i64).bind(since).fetch_all(pool).await?;
+
+    let period = match days {
+        Some(d) => format!("the last {} day{}", d, if d == 1 { "" } else { "s" }),
+        None => "all time".to_string(),
+    };
+    if rows.is_empty() {
+        return Ok(format!("You haven't run any jobs in {}.", period));
+    }
+
+    let total_jobs: i64 = rows.iter().map(|(_, count, _)| count).sum();
+    let total_ms: i64 = rows.iter().map(|(_, _, ms)| ms).sum();
+    let mut summary = format!(
+        "⏱ Processing time for {}: {} total over {} jobs ({} average).\n",
+        period, format_duration_ms(total_ms), total_jobs, format_duration_ms(total_ms / total_jobs),
+    );
+    for (kind, count, ms) in rows {
+        summary.push_str(&format!("\n• {}: {} × {} average", kind, count, format_duration_ms(ms / count)));
+    }
+    Ok(summary)
+}
+
+// --- Bot Handlers ---
+
+const REMOVE_PAGE_SIZE: i64 = 8;
+const INLINE_PAGE_SIZE: i64 = 30;
+const LIST_PAGE_SIZE: i64 = 25;
+
+/// Longest caption `/list` shows in full, in chars, so a page stays under the message limit.
+const LIST_CAPTION_CHARS: usize = 100;
+
+/// The most recently saved videos in the library `owner` can see.
+async fn fetch_recent_videos(pool: &SharedState, owner: Option<i64>, limit: i64) -> Vec<VideoData> {
+    sqlx::query_as("SELECT file_id, caption, kind FROM videos WHERE (? IS NULL OR user_id = ?) ORDER BY rowid DESC LIMIT ?")
+        .bind(owner).bind(owner).bind(limit).fetch_all(pool).await.unwrap_or_default()
+}
+
+/// Per-user setting: keep the cached video visible while an inline edit is processing.
+const SETTING_KEEP_MEDIA: &str = "keep_media";
+
+/// Per-user setting: keep the pre-uploaded copy of edited videos in the user's DM.
+const SETTING_DM_COPY: &str = "dm_copy";
+
+/// Per-user setting: show a dimmed thumbnail of the clip while an inline edit is processing.
+const SETTING_PROGRESS_THUMB: &str = "progress_thumb";
+
+/// Per-user setting: file id of the audio `/dub` puts under edited clips.
+const SETTING_DUB_AUDIO: &str = "dub_audio";
+
+/// Per-user setting: the `/captiontemplate` that overrides `CAPTION_TEMPLATE` for this user.
+const SETTING_CAPTION_TEMPLATE: &str = "caption_template";
+
+async fn get_user_setting(pool: &SharedState, user_id: UserId, key: &str) -> Option<String> {
+    sqlx::query_scalar("SELECT value FROM user_settings WHERE user_id = ? AND key = ?")
+        .bind(user_id.0 as i64).bind(key).fetch_optional(pool).await.unwrap_or_default()
+}
+
+async fn set_user_setting(pool: &SharedState, user_id: UserId, key: &str, value: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO user_settings (user_id, key, value) VALUES (?, ?, ?) ON CONFLICT(user_id, key) DO UPDATE SET value = excluded.value")
+        .bind(user_id.0 as i64).bind(key).bind(value).execute(pool).await?;
+    Ok(())
+}
+
+async fn get_user_flag(pool: &SharedState, user_id: UserId, key: &str) -> bool {
+    sqlx::query_scalar::<_, String>("SELECT value FROM user_settings WHERE user_id = ? AND key = ?")
+        .bind(user_id.0 as i64).bind(key).fetch_optional(pool).await
+        .unwrap_or_default().is_some_and(|v| v == "1")
+}
+
+/// Global runtime setting: skip motion crop detection and save originals.
+const SETTING_AUTOCROP_DISABLED: &str = "autocrop_disabled";
+
+/// Global runtime setting, set once the admin manages the allowlist: only allowed chats may use
+/// the bot, even after the last one is removed.
+const SETTING_ALLOWLIST_ENABLED: &str = "allowlist_enabled";
+
+async fn get_global_flag(pool: &SharedState, key: &str) -> bool {
+    sqlx::query_scalar::<_, String>("SELECT value FROM settings WHERE key = ?")
+        .bind(key).fetch_optional(pool).await
+        .unwrap_or_default().is_some_and(|v| v == "1")
+}
+
+async fn set_global_flag(pool: &SharedState, key: &str, enabled: bool) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+        .bind(key).bind(if enabled { "1" } else { "0" }).execute(pool).await?;
+    Ok(())
+}
+
+/// Flips a boolean global setting and returns its new value.
+async fn toggle_global_flag(pool: &SharedState, key: &str) -> Result<bool, sqlx::Error> {
+    let enabled = !get_global_flag(pool, key).await;
+    set_global_flag(pool, key, enabled).await?;
+    Ok(enabled)
+}
+
+/// Flips a boolean per-user setting and returns its new value.
+async fn toggle_user_flag(pool: &SharedState, user_id: UserId, key: &str) -> Result<bool, sqlx::Error> {
+    let enabled = !get_user_flag(pool, user_id, key).await;
+    set_user_setting(pool, user_id, key, if enabled { "1" } else { "0" }).await?;
+    Ok(enabled)
+}
+
+async fn build_remove_keyboard(pool: &SharedState, user_id: UserId, page: i64) -> Result<Option<InlineKeyboardMarkup>, sqlx::Error> {
+    build_video_keyboard(pool, user_id, page, "delete", "remove_page", None).await
+}
+
+async fn build_rename_keyboard(pool: &SharedState, user_id: UserId, page: i64) -> Result<Option<InlineKeyboardMarkup>, sqlx::Error> {
+    build_video_keyboard(pool, user_id, page, "rename", "rename_page", None).await
+}
+
+async fn build_search_keyboard(pool: &SharedState, user_id: UserId, term: &str, page: i64) -> Result<Option<InlineKeyboardMarkup>, sqlx::Error> {
+    build_video_keyboard(pool, user_id, page, "send", "send_page", Some(term)).await
+}
+
+async fn build_note_keyboard(pool: &SharedState, user_id: UserId, page: i64) -> Result<Option<InlineKeyboardMarkup>, sqlx::Error> {
+    build_video_keyboard(pool, user_id, page, "note", "note_page", None).await
+}
+
+/// The `/search` results message. Callback data is too short to carry the search term, so
+/// page buttons read it back from this text with `search_term_from_prompt`.
+fn search_prompt(term: &str) -> String {
+    format!("🔎 Your videos matching \"{}\":", term)
+}
+
+fn search_term_from_prompt(text: &str) -> Option<&str> {
+    text.strip_prefix("🔎 Your videos matching \"")?.strip_suffix("\":")
+}
+
+/// Clamps a 0-based page to the last non-empty page for `total_count` items, e.g. after the
+/// only video on the last page was deleted.
+fn clamp_page(page: i64, total_count: i64, page_size: i64) -> i64 {
+    let last_page = ((total_count + page_size - 1) / page_size - 1).max(0);
+    page.clamp(0, last_page)
+}
+
+/// A paginated keyboard of the user's videos, optionally only those whose caption contains
+/// `search`. Selecting one sends `{select_prefix}_{page}_{file id prefix}`; the arrows send
+/// `{page_prefix}_{page}`.
+async fn build_video_keyboard(pool: &SharedState, user_id: UserId, page: i64, select_prefix: &str, page_prefix: &str, search: Option<&str>) -> Result<Option<InlineKeyboardMarkup>, sqlx::Error> {
+    let search_pattern = search.map(|term| format!("%{}%", escape_like_pattern(&normalize_caption(term))));
+    let total_count: i64 = sqlx::query_as::<_, Count>(
+        "SELECT COUNT(*) as count FROM videos WHERE user_id = ? AND (? IS NULL OR caption_norm LIKE ? ESCAPE '\\')")
+        .bind(user_id.0 as i64).bind(&search_pattern).bind(&search_pattern).fetch_one(pool).await?.count;
+
+    if total_count == 0 { return Ok(None); }
+
+    let total_pages = (total_count + REMOVE_PAGE_SIZE - 1) / REMOVE_PAGE_SIZE;
+    let current_page = clamp_page(page, total_count, REMOVE_PAGE_SIZE);
+    let offset = current_page * REMOVE_PAGE_SIZE;
+
+    let videos: Vec<VideoData> = sqlx::query_as(
+        "SELECT file_id, caption FROM videos WHERE user_id = ? AND (? IS NULL OR caption_norm LIKE ? ESCAPE '\\') ORDER BY rowid DESC LIMIT ? OFFSET ?")
+        .bind(user_id.0 as i64).bind(&search_pattern).bind(&search_pattern).bind(REMOVE_PAGE_SIZE).bind(offset).fetch_all(pool).await?;
+
+    let mut keyboard_buttons: Vec<Vec<_>> = videos.into_iter().map(|video| {
+        let mut short_id = video.file_id.clone();
+        short_id.truncate(50);
+        let callback_data = format!("{}_{}_{}", select_prefix, current_page, short_id);
+        vec![InlineKeyboardButton::callback(video.caption, callback_data)]
+    }).collect();
+
+    keyboard_buttons.push(page_nav_row(page_prefix, current_page, total_pages));
+
+    Ok(Some(InlineKeyboardMarkup::new(keyboard_buttons)))
+}
+
+/// Previous/next buttons around a "- 2/5 -" page counter. The arrows send `{page_prefix}_{page}`.
+fn page_nav_row(page_prefix: &str, current_page: i64, total_pages: i64) -> Vec<InlineKeyboardButton> {
+    let mut nav_row = Vec::new();
+    if current_page > 0 {
+        nav_row.push(InlineKeyboardButton::callback("⬅️ Previous", format!("{}_{}", page_prefix, current_page - 1)));
+    }
+    nav_row.push(InlineKeyboardButton::callback(format!("- {}/{} -", current_page + 1, total_pages), "ignore"));
+    if current_page < total_pages - 1 {
+        nav_row.push(InlineKeyboardButton::callback("Next ➡️", format!("{}_{}", page_prefix, current_page + 1)));
+    }
+    nav_row
+}
+
+/// A page of the `/list` caption index and its page buttons. Each line carries the video's
+/// `#rowid`, which stays the same as other videos come and go.
+async fn build_caption_list(pool: &SharedState, user_id: UserId, page: i64) -> Result<Option<(String, InlineKeyboardMarkup)>, sqlx::Error> {
+    let total_count: i64 = sqlx::query_as::<_, Count>("SELECT COUNT(*) as count FROM videos WHERE user_id = ?")
+        .bind(user_id.0 as i64).fetch_one(pool).await?.count;
+    if total_count == 0 { return Ok(None); }
+
+    let total_pages = (total_count + LIST_PAGE_SIZE - 1) / LIST_PAGE_SIZE;
+    let current_page = clamp_page(page, total_count, LIST_PAGE_SIZE);
+    let offset = current_page * LIST_PAGE_SIZE;
+    let rows: Vec<(i64, String)> = sqlx::query_as("SELECT rowid, caption FROM videos WHERE user_id = ? ORDER BY rowid DESC LIMIT ? OFFSET ?")
+        .bind(user_id.0 as i64).bind(LIST_PAGE_SIZE).bind(offset).fetch_all(pool).await?;
+
+    let lines: Vec<String> = rows.into_iter().enumerate().map(|(i, (rowid, caption))| {
+        let mut shown: String = caption.chars().take(LIST_CAPTION_CHARS).collect();
+        if shown.len() < caption.len() {
+            shown.push('…');
+        }
+        format!("{}. {} (#{})", offset + i as i64 + 1, shown, rowid)
+    }).collect();
+    let text = format!("📋 Your saved videos ({}):\n\n{}", total_count, lines.join("\n"));
+    Ok(Some((text, InlineKeyboardMarkup::new(vec![page_nav_row("list_page", current_page, total_pages)]))))
+}
+
+
+/// A video picked in the `/rename` dialog, waiting for the user's next text message.
+struct PendingRename { file_id: String, old_caption: String }
+
+/// One pending rename per user and chat, so a rename started in one chat doesn't swallow the
+/// user's next message in another. Picking another video replaces (and so cancels) the previous one.
+static PENDING_RENAMES: Lazy<Mutex<HashMap<(ChatId, UserId), PendingRename>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// An uncaptioned video kept around so `/saveall` can save it later.
+struct UnsavedVideo { message_id: MessageId, video: Video, received_at: Instant }
+
+/// How long uncaptioned videos stay eligible for `/saveall`, and how many are kept per user.
+const UNSAVED_VIDEO_TTL: Duration = Duration::from_secs(10 * 60);
+const MAX_UNSAVED_VIDEOS: usize = 20;
+
+/// Recently received uncaptioned videos, per chat and sender.
+type UnsavedVideos = HashMap<(ChatId, UserId), Vec<UnsavedVideo>>;
+static UNSAVED_VIDEOS: Lazy<Mutex<UnsavedVideos>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn remember_unsaved_video(chat_id: ChatId, user_id: UserId, message_id: MessageId, video: Video) {
+    let mut buffers = UNSAVED_VIDEOS.lock().unwrap();
+    let buffer = buffers.entry((chat_id, user_id)).or_default();
+    buffer.retain(|v| v.received_at.elapsed() < UNSAVED_VIDEO_TTL);
+    buffer.push(UnsavedVideo { message_id, video, received_at: Instant::now() });
+    if buffer.len() > MAX_UNSAVED_VIDEOS {
+        buffer.remove(0);
+    }
+}
+
+/// Drops a buffered video once it has been saved on its own.
+fn forget_unsaved_video(chat_id: ChatId, user_id: UserId, message_id: MessageId) {
+    if let Some(buffer) = UNSAVED_VIDEOS.lock().unwrap().get_mut(&(chat_id, user_id)) {
+        buffer.retain(|v| v.message_id != message_id);
+    }
+}
+
+/// Takes all still-fresh buffered videos of a user, oldest first.
+fn take_unsaved_videos(chat_id: ChatId, user_id: UserId) -> Vec<UnsavedVideo> {
+    let mut buffer = UNSAVED_VIDEOS.lock().unwrap().remove(&(chat_id, user_id)).unwrap_or_default();
+    buffer.retain(|v| v.received_at.elapsed() < UNSAVED_VIDEO_TTL);
+    buffer
+}
+
+/// Lowercased words and phrases that aren't allowed in captions.
+static CAPTION_BLOCKLIST: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(load_caption_blocklist()));
+
+/// Reads the blocklist from `CAPTION_BLOCKLIST` (comma-separated) and the file at
+/// `CAPTION_BLOCKLIST_FILE` (one entry per line, `#` for comments).
+fn load_caption_blocklist() -> Vec<String> {
+    let mut entries: Vec<String> = env::var("CAPTION_BLOCKLIST").unwrap_or_default()
+        .split(',').map(normalize_caption).filter(|e| !e.is_empty()).collect();
+
+    if let Ok(path) = env::var("CAPTION_BLOCKLIST_FILE") {
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => entries.extend(contents.lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .map(normalize_caption).filter(|e| !e.is_empty())),
+            Err(e) => log::warn!("Failed to read caption blocklist file '{}': {}", path, e),
+        }
+    }
+    entries
+}
+
+/// Returns the first blocklist entry found in the caption. Single words only match whole words;
+/// phrases match anywhere in the normalized caption.
+fn find_blocked_caption_word(caption: &str) -> Option<String> {
+    let normalized = normalize_caption(caption);
+    let words: Vec<&str> = normalized.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).collect();
+    CAPTION_BLOCKLIST.read().unwrap().iter().find(|entry| {
+        if entry.chars().all(char::is_alphanumeric) { words.contains(&entry.as_str()) } else { normalized.contains(entry.as_str()) }
+    }).cloned()
+}
+
+/// Chats that were already told the bot isn't enabled there, so they're only told once.
+static NOTIFIED_BLOCKED_CHATS: Lazy<Mutex<HashSet<ChatId>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Checks the chat against `ALLOWED_CHATS` and the runtime allowlist. With neither configured
+/// and no `/allowchat` or `/disallowchat` ever run, every chat is allowed. Admin DMs are always allowed.
+async fn is_chat_allowed(pool: &SharedState, config: &Config, msg: &Message) -> bool {
+    if msg.chat.is_private() && msg.from().is_some_and(|u| config.is_admin(u.id)) {
+        return true;
+    }
+
+    let env_chats = &config.allowed_chats;
+    let db_chats: Vec<i64> = sqlx::query_scalar("SELECT chat_id FROM allowed_chats")
+        .fetch_all(pool).await.unwrap_or_default();
+
+    if env_chats.is_empty() && db_chats.is_empty() && !get_global_flag(pool, SETTING_ALLOWLIST_ENABLED).await {
+        return true;
+    }
+    env_chats.contains(&msg.chat.id) || db_chats.contains(&msg.chat.id.0)
+}
+
+/// Returns false for chats that aren't allowed, replying with a notice the first time.
+async fn ensure_chat_allowed(bot: &Bot, pool: &SharedState, config: &Config, msg: &Message) -> Result<bool, teloxide::RequestError> {
+    if is_chat_allowed(pool, config, msg).await {
+        return Ok(true);
+    }
+
+    let first_time = NOTIFIED_BLOCKED_CHATS.lock().unwrap().insert(msg.chat.id);
+    if first_time {
+        log::info!("Ignoring messages from chat {} which isn't on the allowlist.", msg.chat.id);
+        bot.send_message(msg.chat.id, "This bot isn't enabled here.").await?;
+    }
+    Ok(false)
+}
+
+/// Resolves the chat id argument of the allowlist commands, defaulting to the current chat.
+fn parse_chat_id_arg(arg: &str, current: ChatId) -> Option<ChatId> {
+    let arg = arg.trim();
+    if arg.is_empty() { Some(current) } else { arg.parse::<i64>().ok().map(ChatId) }
+}
+
+/// Handles the admin `/allowchat` and `/disallowchat` commands.
+async fn update_allowlist(bot: &Bot, pool: &SharedState, config: &Config, msg: &Message, user_id: UserId, arg: &str, allow: bool) -> Result<(), teloxide::RequestError> {
+    if !config.is_admin(user_id) {
+        bot.send_message(msg.chat.id, "This command is only available to the bot admin.").await?;
+        return Ok(());
+    }
+    let Some(chat_id) = parse_chat_id_arg(arg, msg.chat.id) else {
+        bot.send_message(msg.chat.id, "Please provide a numeric chat id, or run the command in the chat itself.").await?;
+        return Ok(());
+    };
+
+    let query = if allow {
+        sqlx::query("INSERT OR IGNORE INTO allowed_chats (chat_id) VALUES (?)")
+    } else {
+        sqlx::query("DELETE FROM allowed_chats WHERE chat_id = ?")
+    };
+    let result = async {
+        set_global_flag(pool, SETTING_ALLOWLIST_ENABLED, true).await?;
+        query.bind(chat_id.0).execute(pool).await
+    }.await;
+    let reply = match result {
+        Ok(_) => {
+            NOTIFIED_BLOCKED_CHATS.lock().unwrap().remove(&chat_id);
+            if allow { format!("✅ Chat {} is now allowed.", chat_id) } else { format!("✅ Chat {} was removed from the allowlist.", chat_id) }
+        }
+        Err(e) => {
+            log::error!("Failed to update allowed chats: {}", e);
+            "❌ Error updating the allowlist.".to_string()
+        }
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
+async fn handle_command(bot: Bot, msg: Message, cmd: Command, pool: SharedState, config: Arc<Config>) -> Result<(), teloxide::RequestError> {
+    let Some(user) = msg.from() else { return Ok(()); };
+    let user_id = user.id;
+
+    if !config.is_admin(user_id) && !ensure_chat_allowed(&bot, &pool, &config, &msg).await? {
+        return Ok(());
+    }
+
+    match cmd {
+        Command::Help => {
+            let command_descriptions = markdown::escape(&Command::descriptions().to_string());
+            let help_text = format!(
+                "{}\n\n*Inline Usage Guide*\n\n\
+                To use the bot in any chat, type its username, search for your video, then use a command like `/edit` or `/caption`\\.\n\n\
+                *Commands & Examples:*\n\n\
+                *1\\. Add a New Caption \\(`/caption`\\):*\n\
+                Sends the video instantly with your new text as the Telegram caption\\. The video itself is not modified\\.\n\
+                `@bot_username cat video /caption A cool new caption`\n\
+                Use `/caption=` or quote the search to target exactly one clip by its full caption\\.\n\
+                `@bot_username \"cat video\" /caption A cool new caption`\n\
+                Add `/title` to show a title above the caption\\.\n\
+                `@bot_username cat video /caption A cool new caption /title Monday mood`\n\n\
+                *2\\. Edit Video Text \\(`/edit`\\):*\n\
+                Burns new text *into* the video file\\. This takes time to process\\.\n\
+                `@bot_username cat video /edit New funny text`\n\n\
+                *Advanced Editing Formats \\(for /edit\\):*\n\n\
+                *a\\) Multi\\-Box Edit:*\n\
+                Provide text for the largest detected boxes using `/box2`, `/box3` and `/box4`\\.\n\
+                `@bot_username cat video /edit Top Text /box2 Bottom Text`\n\n\
+                *b\\) Timed Text Edit:*\n\
+                Change text at a specific time, in seconds or as `0:05`, `1:02:03` or `1m2s`\\.\n\
+                `@bot_username cat video /edit Text Before /5.5 Text After`\n\n\
+                *c\\) Audio Track:*\n\
+                Keep a specific audio track of multi\\-language videos with `/audiotrack N`\\. This also works in save captions\\.\n\
+                `@bot_username cat video /edit New text /audiotrack 2`\n\n\
+                *d\\) Soft Subtitles:*\n\
+                Add `/softsub` to store the text as a subtitle track players can toggle instead of burning it in\\.\n\
+                `@bot_username cat video /edit New text /softsub`\n\n\
+                *e\\) Encoder:*\n\
+                Add `/cpu` for fast CPU encoding or `/gpu` for NVIDIA encoding on a single edit or save\\.\n\n\
+                *f\\) Transcribe:*\n\
+                Add `/transcribe` to caption the clip with its own speech, when the bot has transcription enabled\\.\n\
+                `@bot_username cat video /edit /transcribe`\n\n\
+                *g\\) Color:*\n\
+                Add `/color NAME` to fill the boxes and color the text, e\\.g\\. yellow, red, blue, green or black\\.\n\
+                `@bot_username cat video /edit New text /color yellow`\n\n\
+                *h\\) GIF:*\n\
+                Add `/gif` to get a looping GIF of the first 10 seconds instead of a video\\.\n\
+                `@bot_username cat video /edit New text /gif`\n\n\
+                *i\\) Mute or Dub:*\n\
+                Add `/mute` to drop the sound, or send me an audio file or voice message privately and add `/dub` to use it as the sound\\.\n\
+                `@bot_username cat video /edit New text /dub`\n\n\
+                *j\\) Caption Bar:*\n\
+                When there are no boxes, `/padcolor NAME` colors the bar above the clip and `/fontsize N` \\(10\\-200\\) sets the text size\\. Add `/bottom` to put the bar below the clip instead\\.\n\
+                `@bot_username cat video /edit New text /padcolor white /color black /fontsize 40`\n\n\
+                *k\\) Preview:*\n\
+                Add `/preview` to see the detected crop and text boxes on a frame without editing the video\\.\n\
+                `@bot_username cat video /edit /preview`\n\n\
+                *l\\) Speed:*\n\
+                Add `/speed N` to play the clip N times as fast, from 0\\.25 to 4\\.\n\
+                `@bot_username cat video /edit New text /speed 0.5`\n\n\
+                *3\\. Fade In and Out \\(`/fade`\\):*\n\
+                Fades the clip in from black and out at the end, optionally with a fade length in seconds\\.\n\
+                `@bot_username cat video /fade 1`\n\n\
+                *4\\. Rename a Video \\(`/rename`\\):*\n\
+                Changes the caption of your best matching video\\.\n\
+                `@bot_username cat video /rename Cat falls off table`\n\n\
+                *5\\. Tags:*\n\
+                Add `#words` to a save caption to tag the video, then start a search with a tag to browse it\\.\n\
+                `@bot_username #funny cat`\n\n\
+                *6\\. Random Video \\(`/random`\\):*\n\
+                Sends one random saved video, optionally from those matching a search\\.\n\
+                `@bot_username cat /random`\n\n\
+                *7\\. Most Used \\(`/top`\\):*\n\
+                Lists the videos sent most often through the bot\\.\n\
+                `@bot_username /top`",
+                command_descriptions
+            );
+
+            bot.send_message(msg.chat.id, help_text).parse_mode(ParseMode::MarkdownV2).await?;
+        }
+        Command::Remove => {
+            match build_remove_keyboard(&pool, user_id, 0).await {
+                Ok(Some(keyboard)) => {
+                    bot.send_message(msg.chat.id, "Select a video to remove:").reply_markup(keyboard).await?;
+                }
+                Ok(None) => {
+                    bot.send_message(msg.chat.id, "You have no saved videos to remove.").await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to build remove keyboard: {}", e);
+                    bot.send_message(msg.chat.id, "Error fetching your videos.").await?;
+                }
+            }
+        }
+        Command::List => {
+            match build_caption_list(&pool, user_id, 0).await {
+                Ok(Some((text, keyboard))) => {
+                    bot.send_message(msg.chat.id, text).reply_markup(keyboard).await?;
+                }
+                Ok(None) => {
+                    bot.send_message(msg.chat.id, "You have no saved videos yet.").await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to build caption list: {}", e);
+                    bot.send_message(msg.chat.id, "Error fetching your videos.").await?;
+                }
+            }
+        }
+        Command::Note => {
+            match build_note_keyboard(&pool, user_id, 0).await {
+                Ok(Some(keyboard)) => {
+                    bot.send_message(msg.chat.id, "Select a video to send as a video note:").reply_markup(keyboard).await?;
+                }
+                Ok(None) => {
+                    bot.send_message(msg.chat.id, "You have no saved videos yet.").await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to build note keyboard: {}", e);
+                    bot.send_message(msg.chat.id, "Error fetching your videos.").await?;
+                }
+            }
+        }
+        Command::CaptionTemplate(template) => {
+            let template = template.trim();
+            if !template.is_empty() && !template.contains("{caption}") {
+                bot.send_message(msg.chat.id, "The template needs a {caption} placeholder, e.g. /captiontemplate {caption} via @me").await?;
+                return Ok(());
+            }
+            let reply = match set_user_setting(&pool, user_id, SETTING_CAPTION_TEMPLATE, template).await {
+                Ok(()) if template.is_empty() => "✅ Caption template cleared.".to_string(),
+                Ok(()) => format!("✅ Your captions will now be shown as: {}", render_caption_template(Some(template), "<caption>", &user_display_name(user))),
+                Err(e) => {
+                    log::error!("Failed to save caption template: {}", e);
+                    "❌ Error saving your caption template.".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Search(term) => {
+            let term = term.trim();
+            if term.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /search <caption words>, e.g. /search cat video").await?;
+                return Ok(());
+            }
+            match build_search_keyboard(&pool, user_id, term, 0).await {
+                Ok(Some(keyboard)) => {
+                    bot.send_message(msg.chat.id, search_prompt(term)).reply_markup(keyboard).await?;
+                }
+                Ok(None) => {
+                    bot.send_message(msg.chat.id, format!("None of your saved videos match \"{}\". Try fewer or different words.", term)).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to build search keyboard: {}", e);
+                    bot.send_message(msg.chat.id, "Error fetching your videos.").await?;
+                }
+            }
+        }
+        Command::Source(search) => {
+            let search_norm = normalize_caption(&search);
+            if search_norm.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /source <search term>").await?;
+                return Ok(());
+            }
+            let owner = config.library_owner(user_id);
+            let found: Option<(String, Option<String>)> = sqlx::query_as(
+                "SELECT caption, source_url FROM videos WHERE caption_norm LIKE ? ESCAPE '\\' AND (? IS NULL OR user_id = ?) \
+                 ORDER BY caption_norm = ? DESC, length(caption) ASC LIMIT 1")
+                .bind(format!("%{}%", escape_like_pattern(&search_norm))).bind(owner).bind(owner).bind(&search_norm)
+                .fetch_optional(&pool).await.unwrap_or_default();
+            let reply = match found {
+                Some((caption, Some(url))) => format!("🔗 \"{}\" was saved from:\n{}", caption, url),
+                Some((caption, None)) => format!("\"{}\" was uploaded directly, so there is no source link.", caption),
+                None => "No saved video matches that search.".to_string(),
+            };
+            bot.send_message(msg.chat.id, reply).disable_web_page_preview(true).await?;
+        }
+        Command::Rename => {
+            match build_rename_keyboard(&pool, user_id, 0).await {
+                Ok(Some(keyboard)) => {
+                    bot.send_message(msg.chat.id, "Select a video to rename:").reply_markup(keyboard).await?;
+                }
+                Ok(None) => {
+                    bot.send_message(msg.chat.id, "You have no saved videos to rename.").await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to build rename keyboard: {}", e);
+                    bot.send_message(msg.chat.id, "Error fetching your videos.").await?;
+                }
+            }
+        }
+        Command::KeepMedia => {
+            let reply = match toggle_user_flag(&pool, user_id, SETTING_KEEP_MEDIA).await {
+                Ok(true) => "✅ The original video will stay visible while your edits are processing.",
+                Ok(false) => "✅ Edits will show a \"Preparing your video...\" message while processing.",
+                Err(e) => {
+                    log::error!("Failed to toggle keep_media setting: {}", e);
+                    "❌ Error saving your setting."
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::DmCopy => {
+            let reply = match toggle_user_flag(&pool, user_id, SETTING_DM_COPY).await {
+                Ok(true) => "✅ A copy of every edited video will be kept in this chat.",
+                Ok(false) => "✅ Edited videos will no longer be copied to this chat.",
+                Err(e) => {
+                    log::error!("Failed to toggle dm_copy setting: {}", e);
+                    "❌ Error saving your setting."
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Boxes(search) => {
+            if !config.is_admin(user_id) {
+                bot.send_message(msg.chat.id, "This command is only available to the bot admin.").await?;
+            } else if search.trim().is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /boxes <search term>").await?;
+            } else {
+                tokio::spawn(dump_detected_boxes(bot.clone(), msg.chat.id, pool.clone(), config.clone(), search));
+            }
+        }
+        Command::SaveAll(caption) => {
+            let (caption, mut options) = match parse_save_options(&caption) {
+                Ok(parsed) => parsed,
+                Err(message) => {
+                    bot.send_message(msg.chat.id, message).await?;
+                    return Ok(());
+                }
+            };
+            options.uploader = user_display_name(user);
+            options.caption_template = caption_template_for(&pool, user_id, &config).await;
+            if caption.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /saveall <caption>").await?;
+                return Ok(());
+            }
+            if let Some(word) = find_blocked_caption_word(&caption) {
+                log::info!("Rejected /saveall caption containing blocked word '{}'.", word);
+                bot.send_message(msg.chat.id, "That caption isn't allowed.").await?;
+                return Ok(());
+            }
+            let videos = take_unsaved_videos(msg.chat.id, user_id);
+            if videos.is_empty() {
+                bot.send_message(msg.chat.id, "You have no recent uncaptioned videos to save.").await?;
+                return Ok(());
+            }
+            for unsaved in videos {
+                let status_msg = bot.send_message(msg.chat.id, "⏳ Analyzing and saving video...").reply_to_message_id(unsaved.message_id).await?;
+                let status = JobStatus::Chat(msg.chat.id, status_msg.id);
+                tokio::spawn(run_queued_job(bot.clone(), pool.clone(), config.clone(), user_id, "save", status, process_and_save_video(
+                    bot.clone(), msg.chat.id, unsaved.message_id, status_msg.id,
+                    unsaved.video.file.id, caption.clone(), pool.clone(), user_id, options.clone(), config.clone(),
+                )));
+            }
+        }
+        Command::ThumbPreview => {
+            let reply = match toggle_user_flag(&pool, user_id, SETTING_PROGRESS_THUMB).await {
+                Ok(true) => "✅ Inline edits will show a thumbnail of the clip while processing.",
+                Ok(false) => "✅ Inline edits will show a text status while processing.",
+                Err(e) => {
+                    log::error!("Failed to toggle progress_thumb setting: {}", e);
+                    "❌ Error saving your setting."
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::ReloadBlocklist => {
+            if !config.is_admin(user_id) {
+                bot.send_message(msg.chat.id, "This command is only available to the bot admin.").await?;
+                return Ok(());
+            }
+            let entries = load_caption_blocklist();
+            let count = entries.len();
+            *CAPTION_BLOCKLIST.write().unwrap() = entries;
+            bot.send_message(msg.chat.id, format!("✅ Reloaded the caption blocklist ({} entries).", count)).await?;
+        }
+        Command::MyTime(days) => {
+            let days = days.trim();
+            let days: Option<i64> = if days.is_empty() { Some(30) } else if days == "all" { None } else {
+                match days.parse::<i64>() {
+                    Ok(d) if d > 0 => Some(d),
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /mytime [days|all]").await?;
+                        return Ok(());
+                    }
+                }
+            };
+            let reply = match processing_time_summary(&pool, user_id, days).await {
+                Ok(summary) => summary,
+                Err(e) => {
+                    log::error!("Failed to load job stats: {}", e);
+                    "❌ Error loading your statistics.".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::CropTest(target) => {
+            if !config.is_admin(user_id) {
+                bot.send_message(msg.chat.id, "This command is only available to the bot admin.").await?;
+                return Ok(());
+            }
+            bot.send_message(msg.chat.id, "⏳ Running crop detection...").await?;
+            tokio::spawn(run_crop_test(bot.clone(), msg.chat.id, pool.clone(), config.clone(), target.trim().to_string()));
+
+        }
+        Command::Broadcast(text) => {
+            if !config.is_admin(user_id) {
+                bot.send_message(msg.chat.id, "Unknown command. Send /help to see what I can do.").await?;
+                return Ok(());
+            }
+            if text.trim().is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /broadcast <message>").await?;
+                return Ok(());
+            }
+            bot.send_message(msg.chat.id, "⏳ Broadcasting...").await?;
+            tokio::spawn(broadcast_message(bot.clone(), msg.chat.id, pool.clone(), text.trim().to_string()));
+        }
+        Command::Export => {
+            match export_user_videos(&pool, user_id).await {
+                Ok((_, 0)) => { bot.send_message(msg.chat.id, "You haven't saved any videos yet.").await?; }
+                Ok((document, count)) => {
+                    bot.send_document(msg.chat.id, InputFile::memory(document).file_name("videos.json"))
+                        .caption(format!("✅ Exported {} videos. Send this file back with /import as the caption to restore them.", count))
+                        .await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to export videos: {}", e);
+                    bot.send_message(msg.chat.id, "❌ Error: Could not read your videos.").await?;
+                }
+            }
+        }
+        Command::Dedup => {
+            match count_duplicate_videos(&pool, user_id).await {
+                Ok(0) => { bot.send_message(msg.chat.id, "✅ No duplicate captions found.").await?; }
+                Ok(count) => {
+                    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                        InlineKeyboardButton::callback(format!("🗑️ Remove {}", count), format!("dedup_confirm_{}", user_id.0)),
+                        InlineKeyboardButton::callback("Cancel", format!("dedup_cancel_{}", user_id.0)),
+                    ]]);
+                    let prompt = format!("Found {} older duplicate video{}. Remove them, keeping the newest of each caption?", count, if count == 1 { "" } else { "s" });
+                    bot.send_message(msg.chat.id, prompt).reply_markup(keyboard).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to count duplicate videos: {}", e);
+                    bot.send_message(msg.chat.id, "❌ Error: Could not look for duplicate videos.").await?;
+                }
+            }
+        }
+        Command::Config => {
+            if !config.is_admin(user_id) {
+                bot.send_message(msg.chat.id, "This command is only available to the bot admin.").await?;
+                return Ok(());
+            }
+            bot.send_message(msg.chat.id, describe_config(&pool, &config).await).await?;
+        }
+        Command::Vacuum => {
+            if !config.is_admin(user_id) {
+                bot.send_message(msg.chat.id, "This command is only available to the bot admin.").await?;
+                return Ok(());
+            }
+            let reply = match vacuum_database(&pool).await {
+                Ok((before, after, waited)) => {
+                    let mut reply = format!("✅ Database compacted: {} → {}.", format_size(before), format_size(after));
+                    if waited > Duration::from_secs(1) {
+                        reply.push_str(&format!(" This took {:.1}s, including waiting for other queries to finish.", waited.as_secs_f64()));
+                    }
+                    reply
+                }
+                Err(e) => {
+                    log::error!("VACUUM failed: {}", e);
+                    "❌ Error: Failed to compact the database. It may be busy, try again later.".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::SetThumb(args) => {
+            let parsed = args.trim().rsplit_once(' ')
+                .and_then(|(search, time)| Some((search.trim().to_string(), time.parse::<f64>().ok()?)))
+                .filter(|(search, time)| !search.is_empty() && *time >= 0.0);
+            match parsed {
+                Some((search, time)) => {
+                    bot.send_message(msg.chat.id, "⏳ Updating thumbnail...").await?;
+                    tokio::spawn(set_custom_thumbnail(bot.clone(), msg.chat.id, user_id, pool.clone(), search, time));
+                }
+                None => { bot.send_message(msg.chat.id, "Usage: /setthumb <search term> <seconds>").await?; }
+            }
+        }
+        Command::Original | Command::Unmute => {
+            let unmute = matches!(cmd, Command::Unmute);
+            let replied_file = msg.reply_to_message()
+                .and_then(|reply| reply.video().map(|v| &v.file).or_else(|| reply.animation().map(|a| &a.file)));
+            let Some(replied_file) = replied_file else {
+                let usage = if unmute { "Reply to a muted video with this command to get the sound back." } else { "Reply to an edited video with this command to get the original back." };
+                bot.send_message(msg.chat.id, usage).await?;
+                return Ok(());
+            };
+            let source: Option<(String, Option<String>, String)> = sqlx::query_as(
+                "SELECT variants.source_file_id, videos.kind, variants.kind FROM variants \
+                 LEFT JOIN videos ON videos.file_id = variants.source_file_id WHERE variants.file_unique_id = ?")
+                .bind(&replied_file.unique_id).fetch_optional(&pool).await.unwrap_or_default();
+            match source {
+                Some((_, _, variant_kind)) if unmute && variant_kind != VARIANT_MUTED => {
+                    bot.send_message(msg.chat.id, "That video wasn't muted with /mute. Use /original to get the unedited version back.").await?;
+                }
+                Some((source_file_id, kind, _)) if kind.as_deref() == Some(KIND_ANIMATION) => {
+                    bot.send_animation(msg.chat.id, InputFile::file_id(source_file_id)).reply_to_message_id(msg.id).await?;
+                }
+                Some((source_file_id, _, _)) => {
+                    bot.send_video(msg.chat.id, InputFile::file_id(source_file_id)).reply_to_message_id(msg.id).await?;
+                }
+                None => {
+                    bot.send_message(msg.chat.id, "I don't know the original of that video. Only edits made with this bot can be reversed.").await?;
+                }
+            }
+        }
+        Command::Recent => {
+            let videos = fetch_recent_videos(&pool, config.library_owner(user.id), config.recent_limit).await;
+            if videos.is_empty() {
+                bot.send_message(msg.chat.id, "No videos have been saved yet.").await?;
+            } else {
+                let list = videos.iter().enumerate()
+                    .map(|(i, video)| format!("{}. {}", i + 1, video.caption))
+                    .collect::<Vec<_>>().join("\n");
+                bot.send_message(msg.chat.id, format!("🆕 Recently saved videos:\n\n{}\n\nType my username followed by /recent in any chat to send one.", list)).await?;
+            }
+        }
+        Command::AllowChat(arg) => update_allowlist(&bot, &pool, &config, &msg, user_id, &arg, true).await?,
+        Command::DisallowChat(arg) => update_allowlist(&bot, &pool, &config, &msg, user_id, &arg, false).await?,
+        Command::AutoCrop => {
+            if !config.is_admin(user_id) {
+                bot.send_message(msg.chat.id, "This command is only available to the bot admin.").await?;
+                return Ok(());
+            }
+            let reply = match toggle_global_flag(&pool, SETTING_AUTOCROP_DISABLED).await {
+                Ok(true) => "⏸️ Auto-crop is now disabled. Videos will be saved as-is.",
+                Ok(false) => "▶️ Auto-crop is enabled again.",
+                Err(e) => {
+                    log::error!("Failed to toggle autocrop setting: {}", e);
+                    "❌ Error saving the setting."
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
         }
-    } else {
-        let stderr = command.output().await.map(|o| String::from_utf8_lossy(&o.stderr).to_string()).unwrap_or_else(|e| e.to_string());
-        log::error!("FFMPEG failed. Filter: '{}'. Stderr: {}", final_filter_chain, stderr);
-        bot.edit_message_text_inline(&inline_message_id, "❌ An error occurred during video processing.").await.ok();
     }
+    Ok(())
 }
 
+async fn handle_chosen_inline_result(bot: Bot, chosen: ChosenInlineResult, pool: SharedState, config: Arc<Config>) -> Result<(), teloxide::RequestError> {
+    // Renames are plain articles without a keyboard, so they never get an inline message id.
+    if let Some(result_id) = chosen.result_id.strip_prefix("rename_") {
+        let video = find_video_by_result_id(&pool, result_id).await;
+        if let (Some(video), Some((_, new_caption_raw))) = (video, chosen.query.split_once("/rename")) {
+            let new_caption = new_caption_raw.trim();
+            let result = sqlx::query("UPDATE videos SET caption = ?, caption_norm = ?, caption_translit = ?, caption_folded = ? WHERE file_id = ? AND user_id = ?")
+                .bind(new_caption).bind(normalize_caption(new_caption)).bind(caption_translit(new_caption, &config)).bind(caption_folded(new_caption))
+                .bind(&video.file_id).bind(chosen.from.id.0 as i64)
+                .execute(&pool).await;
+            if let Err(e) = result {
+                log::error!("Failed to rename video via inline query: {}", e);
+            }
+        }
+        return Ok(());
+    }
 
-// --- Bot Handlers ---
-
-const REMOVE_PAGE_SIZE: i64 = 8;
-
-async fn build_remove_keyboard(pool: &SharedState, user_id: UserId, page: i64) -> Result<Option<InlineKeyboardMarkup>, sqlx::Error> {
-    let total_count: i64 = sqlx::query_as::<_, Count>("SELECT COUNT(*) as count FROM videos WHERE user_id = ?")
-        .bind(user_id.0 as i64).fetch_one(pool).await?.count;
-
-    if total_count == 0 { return Ok(None); }
-
-    let total_pages = (total_count as f64 / REMOVE_PAGE_SIZE as f64).ceil() as i64;
-    let current_page = page.max(0).min(total_pages - 1);
-    let offset = current_page * REMOVE_PAGE_SIZE;
-
-    let videos: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption FROM videos WHERE user_id = ? ORDER BY rowid DESC LIMIT ? OFFSET ?")
-        .bind(user_id.0 as i64).bind(REMOVE_PAGE_SIZE).bind(offset).fetch_all(pool).await?;
+    // Every sent clip counts towards `/top`, whether it was sent as is or processed first.
+    let result_id = ["edit_", "fade_"].iter()
+        .find_map(|prefix| chosen.result_id.strip_prefix(prefix))
+        .unwrap_or(&chosen.result_id);
+    let Some(video) = find_video_by_result_id(&pool, result_id).await else { return Ok(()); };
+    if let Err(e) = sqlx::query("UPDATE videos SET use_count = use_count + 1 WHERE file_id = ?")
+        .bind(&video.file_id).execute(&pool).await {
+        log::warn!("Failed to count video use: {}", e);
+    }
 
-    let mut keyboard_buttons: Vec<Vec<_>> = videos.into_iter().map(|video| {
-        let mut short_id = video.file_id.clone();
-        short_id.truncate(50);
-        let callback_data = format!("delete_{}_{}", current_page, short_id);
-        vec![InlineKeyboardButton::callback(video.caption, callback_data)]
-    }).collect();
+    let Some(inline_message_id) = chosen.inline_message_id else { return Ok(()); };
 
-    let mut nav_row = Vec::new();
-    if current_page > 0 {
-        nav_row.push(InlineKeyboardButton::callback("⬅️ Previous", format!("remove_page_{}", current_page - 1)));
+    if chosen.result_id.starts_with("fade_") {
+        let fade_secs = chosen.query.split_once("/fade").and_then(|(_, raw)| parse_fade_duration(raw));
+        if let Some(fade_secs) = fade_secs {
+            let job = RetryableJob::Fade { inline_message_id, file_id: video.file_id, fade_secs };
+            spawn_retryable_job(bot.clone(), pool.clone(), config.clone(), chosen.from.id, job);
+        }
+        return Ok(());
     }
-    nav_row.push(InlineKeyboardButton::callback(format!("- {}/{} -", current_page + 1, total_pages), "ignore"));
-    if current_page < total_pages - 1 {
-        nav_row.push(InlineKeyboardButton::callback("Next ➡️", format!("remove_page_{}", current_page + 1)));
+
+    if chosen.result_id.starts_with("edit_") {
+        if let Some((_, edit_params_raw)) = chosen.query.split_once("/edit") {
+            let (edit_params, options) = parse_edit_options(edit_params_raw);
+            let spec = parse_edit_params(&edit_params, config.max_edit_boxes);
+            let user_id = chosen.from.id;
+            let job = RetryableJob::Edit { inline_message_id, file_id: video.file_id, spec, options };
+            spawn_retryable_job(bot.clone(), pool.clone(), config.clone(), user_id, job);
+        }
     }
+    Ok(())
+}
 
-    if !nav_row.is_empty() { keyboard_buttons.push(nav_row); }
+/// Builds the inline result for a background job (`/edit`, `/fade`, ...). It carries a dummy
+/// keyboard so Telegram hands back an inline message id for the finished clip to replace.
+fn processing_result(result_id: String, video: VideoData, title: String, description: String, keep_media: bool) -> InlineQueryResult {
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("⚙️ Processing...", "ignore")]]);
+    // Without an input message content the cached video itself is sent, so it stays
+    // visible until the processed version replaces it.
+    let placeholder = (!keep_media).then(|| InputMessageContent::Text(InputMessageContentText::new("⚙️ Preparing your video...")));
+    if video.kind == KIND_ANIMATION {
+        let mut result = InlineQueryResultCachedMpeg4Gif::new(result_id, video.file_id).title(title).reply_markup(keyboard);
+        if let Some(placeholder) = placeholder {
+            result = result.input_message_content(placeholder);
+        }
+        InlineQueryResult::CachedMpeg4Gif(result)
+    } else {
+        let mut result = InlineQueryResultCachedVideo::new(result_id, video.file_id, title).description(description).reply_markup(keyboard);
+        if let Some(placeholder) = placeholder {
+            result = result.input_message_content(placeholder);
+        }
+        InlineQueryResult::CachedVideo(result)
+    }
+}
 
-    Ok(Some(InlineKeyboardMarkup::new(keyboard_buttons)))
+/// 64-bit FNV-1a. Unlike `DefaultHasher` it is stable across builds, so result ids handed
+/// out before a restart still resolve after it.
+fn fnv1a_64(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
 }
 
+/// An inline result id for a saved video: `tag`, a hash of the full file id, then as much of
+/// the file id as fits in Telegram's 64 bytes. A plain truncated file id could repeat across
+/// videos, and Telegram drops results with duplicate ids.
+fn video_result_id(tag: &str, file_id: &str) -> String {
+    let mut id = format!("{}{:016x}", tag, fnv1a_64(file_id));
+    id.extend(file_id.chars().take(64usize.saturating_sub(id.len())));
+    id
+}
 
-async fn handle_command(bot: Bot, msg: Message, cmd: Command, pool: SharedState) -> Result<(), teloxide::RequestError> {
-    let Some(user) = msg.from() else { return Ok(()); };
-    let user_id = user.id;
+/// Finds the video a `video_result_id` (without its tag) was built from.
+async fn find_video_by_result_id(pool: &SharedState, result_id: &str) -> Option<VideoData> {
+    let (hash, file_id_prefix) = (result_id.get(..16)?, result_id.get(16..)?);
+    let candidates: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption, kind FROM videos WHERE file_id LIKE ? ESCAPE '\\'")
+        .bind(format!("{}%", escape_like_pattern(file_id_prefix))).fetch_all(pool).await.unwrap_or_default();
+    candidates.into_iter().find(|video| format!("{:016x}", fnv1a_64(&video.file_id)) == hash)
+}
 
-    match cmd {
-        Command::Help => {
-            let command_descriptions = Command::descriptions().to_string();
-            let help_text = format!(
-                "{}\n\n*Inline Usage Guide*\n\n\
-                To use the bot in any chat, type its username, search for your video, then use a command like `/edit` or `/caption`\\.\n\n\
-                *Commands & Examples:*\n\n\
-                *1\\. Add a New Caption \\(`/caption`\\):*\n\
-                Sends the video instantly with your new text as the Telegram caption\\. The video itself is not modified\\.\n\
-                `@bot_username cat video /caption A cool new caption`\n\n\
-                *2\\. Edit Video Text \\(`/edit`\\):*\n\
-                Burns new text *into* the video file\\. This takes time to process\\.\n\
-                `@bot_username cat video /edit New funny text`\n\n\
-                *Advanced Editing Formats \\(for /edit\\):*\n\n\
-                *a\\) Two\\-Box Edit:*\n\
-                Provide text for the top two detected boxes using `/box2`\\.\n\
-                `@bot_username cat video /edit Top Text /box2 Bottom Text`\n\n\
-                *b\\) Timed Text Edit:*\n\
-                Change text at a specific time \\(in seconds\\)\\.\n\
-                `@bot_username cat video /edit Text Before /5.5 Text After`",
-                command_descriptions
-            );
+/// Builds the inline result for a saved video, applying the caption template if one is set.
+fn cached_video_result(video: VideoData, template: Option<&str>, sender_name: &str) -> InlineQueryResult {
+    let result_id = video_result_id("", &video.file_id);
+    let caption = template.is_some().then(|| render_caption_template(template, &video.caption, sender_name));
+    cached_media_result(result_id, video, caption)
+}
 
-            bot.send_message(msg.chat.id, help_text).parse_mode(ParseMode::MarkdownV2).await?;
+/// A cached inline result of the right type for how the clip was stored.
+fn cached_media_result(result_id: String, video: VideoData, caption: Option<String>) -> InlineQueryResult {
+    let caption = caption.map(|c| truncate_caption(&c));
+    if video.kind == KIND_AUDIO {
+        // Audio results take their title from the file's metadata, which `send_audio` set
+        // to the saved caption.
+        let mut result = InlineQueryResultCachedAudio::new(result_id, video.file_id);
+        if let Some(caption) = caption {
+            result = result.caption(caption);
         }
-        Command::Remove => {
-            match build_remove_keyboard(&pool, user_id, 0).await {
-                Ok(Some(keyboard)) => {
-                    bot.send_message(msg.chat.id, "Select a video to remove:").reply_markup(keyboard).await?;
-                }
-                Ok(None) => {
-                    bot.send_message(msg.chat.id, "You have no saved videos to remove.").await?;
-                }
-                Err(e) => {
-                    log::error!("Failed to build remove keyboard: {}", e);
-                    bot.send_message(msg.chat.id, "Error fetching your videos.").await?;
-                }
-            }
+        InlineQueryResult::CachedAudio(result)
+    } else if video.kind == KIND_ANIMATION {
+        let mut result = InlineQueryResultCachedMpeg4Gif::new(result_id, video.file_id).title(video.caption);
+        if let Some(caption) = caption {
+            result = result.caption(caption);
+        }
+        InlineQueryResult::CachedMpeg4Gif(result)
+    } else {
+        let mut result = InlineQueryResultCachedVideo::new(result_id, video.file_id, video.caption);
+        if let Some(tags) = video.tags.filter(|t| !t.is_empty()) {
+            // Shown under the title, so tagged clips are easy to tell apart in search results.
+            result = result.description(tags.split_whitespace().map(|t| format!("#{}", t)).collect::<Vec<_>>().join(" "));
+        }
+        if let Some(caption) = caption {
+            result = result.caption(caption);
         }
+        InlineQueryResult::CachedVideo(result)
     }
-    Ok(())
 }
 
-async fn handle_chosen_inline_result(bot: Bot, chosen: ChosenInlineResult, pool: SharedState) -> Result<(), teloxide::RequestError> {
-    let Some(inline_message_id) = chosen.inline_message_id else { return Ok(()); };
+/// Checks that the bot can message the user privately, which every inline feature that
+/// pre-uploads its result to the user's DM relies on. Otherwise returns the "start the bot" article.
+async fn check_dm_available(bot: &Bot, user_id: UserId) -> Result<(), InlineQueryResult> {
+    if bot.send_chat_action(user_id, ChatAction::Typing).await.is_ok() {
+        return Ok(());
+    }
 
-    if chosen.query.contains("/edit") {
-        if let Some(file_id_prefix) = chosen.result_id.strip_prefix("edit_") {
-            let pattern = format!("{}%", file_id_prefix);
-            if let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption FROM videos WHERE file_id LIKE ?")
-                .bind(pattern).fetch_optional(&pool).await.unwrap_or_default()
-            {
-                if let Some((_, edit_params_raw)) = chosen.query.split_once("/edit") {
-                    let edit_params = edit_params_raw.trim();
-                    let mut final_edit_text = String::new();
-
-                    if let Some((msg1, rest)) = edit_params.rsplit_once('/') {
-                        if let Some((time_str, msg2)) = rest.trim().split_once(' ') {
-                            if time_str.parse::<f64>().is_ok() {
-                                final_edit_text = format!("{}///{}///{}", msg1.trim(), time_str.trim(), msg2.trim());
-                            }
-                        }
-                    }
+    let mut prompt = InlineQueryResultArticle::new(
+        "start_bot_prompt", "Bot Not Started",
+        InputMessageContent::Text(InputMessageContentText::new(
+            "You need to start a chat with me before I can edit and send you videos."
+        ))
+    )
+    .description("You must start the bot to use this feature.");
+
+    let start_url = bot.get_me().await.ok()
+        .and_then(|me| me.username.clone())
+        .and_then(|username| Url::parse(&format!("https://t.me/{}?start=inline", username)).ok());
+    if let Some(start_url) = start_url {
+        prompt = prompt.reply_markup(InlineKeyboardMarkup::new(vec![vec![
+            InlineKeyboardButton::url("Click here to Start Bot", start_url)
+        ]]));
+    }
+    Err(InlineQueryResult::Article(prompt))
+}
 
-                    if final_edit_text.is_empty() {
-                        if let Some((msg1, msg2)) = edit_params.split_once("/box2") {
-                            final_edit_text = format!("{}///{}", msg1.trim(), msg2.trim());
-                        } else {
-                            final_edit_text = edit_params.to_string();
-                        }
-                    }
+/// Splits an optional `/title <name>` off the end of a `/caption` query. Cached videos keep
+/// their uploaded file name and metadata, so the title only shows in the result list and is
+/// prefixed to the sent caption.
+fn parse_caption_title(caption_raw: &str) -> (&str, Option<&str>) {
+    match caption_raw.split_once("/title") {
+        Some((caption, title)) => (caption, Some(title.trim()).filter(|t| !t.is_empty())),
+        None => (caption_raw, None),
+    }
+}
 
-                    let user_id = chosen.from.id;
-                    tokio::spawn(perform_video_edit(
-                        bot.clone(), user_id, inline_message_id, video.file_id, final_edit_text,
-                    ));
-                }
-            }
-        }
+/// Detects an exact-match `/caption` query: either `term /caption= new caption` or a quoted
+/// `"term" /caption new caption`. Returns the exact term, if any, and the new caption text.
+fn parse_exact_caption_target<'a>(search_term: &'a str, caption_raw: &'a str) -> (Option<&'a str>, &'a str) {
+    if let Some(rest) = caption_raw.strip_prefix('=') {
+        return (Some(search_term.trim()), rest);
     }
-    Ok(())
+    let quoted = search_term.trim().strip_prefix('"').and_then(|t| t.strip_suffix('"'));
+    (quoted, caption_raw)
+}
+
+/// Trims a page fetched with `LIMIT page_size + 1` back to `page_size`, returning whether
+/// the extra row existed, i.e. whether another page follows.
+fn take_page<T>(rows: &mut Vec<T>, page_size: i64) -> bool {
+    let has_more = rows.len() > page_size as usize;
+    rows.truncate(page_size as usize);
+    has_more
 }
 
-async fn handle_inline_query(bot: Bot, q: InlineQuery, pool: SharedState) -> Result<(), teloxide::RequestError> {
-    const PAGE_SIZE: i64 = 30;
+async fn handle_inline_query(bot: Bot, q: InlineQuery, pool: SharedState, config: Arc<Config>) -> Result<(), teloxide::RequestError> {
     let page: i64 = q.offset.parse().unwrap_or(0);
-    let sql_offset = page * PAGE_SIZE;
+    let sql_offset = page * INLINE_PAGE_SIZE;
 
     let mut results = vec![];
+    let mut has_next_page = false;
+    let sender_name = user_display_name(&q.from);
+    let template = caption_template_for(&pool, q.from.id, &config).await;
+    let owner = config.library_owner(q.from.id);
 
     if let Some((search_term, edit_params_raw)) = q.query.split_once("/edit") {
         let user_id = q.from.id;
-        let can_send_message = bot.send_chat_action(user_id, ChatAction::Typing).await.is_ok();
+        match check_dm_available(&bot, user_id).await {
+            Err(prompt) => results.push(prompt),
+            Ok(()) => {
+                let (edit_params, edit_options) = parse_edit_options(edit_params_raw);
+                let mut display_description = parse_edit_params(&edit_params, config.max_edit_boxes).describe();
+                if edit_options.transcribe {
+                    display_description = "Click to caption the video with its own speech".to_string();
+                }
 
-        if can_send_message {
-            let edit_params = edit_params_raw.trim();
-            let mut display_description = String::new();
+                let search_pattern = format!("%{}%", escape_like_pattern(&normalize_caption(search_term)));
+                if let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption, kind FROM videos WHERE caption_norm LIKE ? ESCAPE '\\' AND kind != 'audio' AND (? IS NULL OR user_id = ?) LIMIT 1")
+                    .bind(search_pattern).bind(owner).bind(owner).fetch_optional(&pool).await.unwrap_or_default() {
 
-            if let Some((msg1, rest)) = edit_params.rsplit_once('/') {
-                if let Some((time_str, msg2)) = rest.trim().split_once(' ') {
-                    if let Ok(time) = time_str.parse::<f64>() {
-                        display_description = format!("TEXT 1: '{}' | TEXT 2: '{}' (at {}s)", msg1.trim(), msg2.trim(), time);
-                    }
+                    let result_id = video_result_id("edit_", &video.file_id);
+                    let title = format!("EDIT: {}", video.caption);
+                    let keep_media = get_user_flag(&pool, user_id, SETTING_KEEP_MEDIA).await;
+                    results.push(processing_result(result_id, video, title, display_description, keep_media));
                 }
             }
-
-            if display_description.is_empty() {
-                if let Some((msg1, msg2)) = edit_params.split_once("/box2") {
-                    display_description = format!("BOX 1: '{}' | BOX 2: '{}'", msg1.trim(), msg2.trim());
-                } else {
-                    display_description = format!("Click to replace text with: '{}'", edit_params);
+        }
+    } else if let Some((search_term, fade_raw)) = q.query.split_once("/fade") {
+        let user_id = q.from.id;
+        match check_dm_available(&bot, user_id).await {
+            Err(prompt) => results.push(prompt),
+            Ok(()) => {
+                let search_pattern = format!("%{}%", escape_like_pattern(&normalize_caption(search_term)));
+                let video = sqlx::query_as::<_, VideoData>("SELECT file_id, caption, kind FROM videos WHERE caption_norm LIKE ? ESCAPE '\\' AND kind != 'audio' AND (? IS NULL OR user_id = ?) LIMIT 1")
+                    .bind(search_pattern).bind(owner).bind(owner).fetch_optional(&pool).await.unwrap_or_default();
+                if let (Some(video), Some(fade_secs)) = (video, parse_fade_duration(fade_raw)) {
+                    let result_id = video_result_id("fade_", &video.file_id);
+                    let title = format!("FADE: {}", video.caption);
+                    let description = format!("Click to fade in and out over {}s", fade_secs);
+                    let keep_media = get_user_flag(&pool, user_id, SETTING_KEEP_MEDIA).await;
+                    results.push(processing_result(result_id, video, title, description, keep_media));
                 }
             }
-
-            let search_pattern = format!("%{}%", search_term.trim());
-            if let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption FROM videos WHERE caption LIKE ? LIMIT 1")
-                .bind(search_pattern).fetch_optional(&pool).await.unwrap_or_default() {
-
-                let mut file_id_prefix = video.file_id.clone();
-                file_id_prefix.truncate(55);
-                let result_id = format!("edit_{}", file_id_prefix);
-                let dummy_keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("⚙️ Processing...", "ignore")]]);
-
-                let result = InlineQueryResult::CachedVideo(
-                    InlineQueryResultCachedVideo::new(result_id, video.file_id, format!("EDIT: {}", video.caption))
-                    .description(display_description)
-                    .input_message_content(InputMessageContent::Text(InputMessageContentText::new("⚙️ Preparing your video...")))
-                    .reply_markup(dummy_keyboard)
-                );
-                results.push(result);
-            }
-        } else {
-            let me = bot.get_me().await?;
-            if let Some(bot_username) = &me.username {
-                 let start_url_str = format!("https://t.me/{}?start=inline", bot_username);
-                 if let Ok(start_url) = Url::parse(&start_url_str) {
-                     let keyboard = InlineKeyboardMarkup::new(vec![vec![
-                         InlineKeyboardButton::url("Click here to Start Bot", start_url)
-                     ]]);
-                     let result = InlineQueryResult::Article(
-                         InlineQueryResultArticle::new(
-                             "start_bot_prompt", "Bot Not Started",
-                             InputMessageContent::Text(InputMessageContentText::new(
-                                 "You need to start a chat with me before I can edit and send you videos."
-                             ))
-                         )
-                         .description("You must start the bot to use the edit feature.")
-                         .reply_markup(keyboard)
-                     );
-                     results.push(result.into());
-                 }
-            }
         }
     } else if let Some((search_term, caption_raw)) = q.query.split_once("/caption") {
+        let (exact_term, caption_raw) = parse_exact_caption_target(search_term, caption_raw);
+        let (caption_raw, title) = parse_caption_title(caption_raw);
         let new_caption = caption_raw.trim().to_string();
-        let search_pattern = format!("%{}%", search_term.trim());
-
-        let videos: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption FROM videos WHERE caption LIKE ? LIMIT ? OFFSET ?")
-            .bind(&search_pattern).bind(PAGE_SIZE).bind(sql_offset).fetch_all(&pool).await.unwrap_or_default();
-
-        results = videos.into_iter().map(|video| {
-            let mut result_id = video.file_id.clone();
-            result_id.truncate(60);
-            InlineQueryResult::CachedVideo(
-                InlineQueryResultCachedVideo::new(result_id, video.file_id, video.caption)
-                .caption(new_caption.clone())
-            )
+
+        let videos: Vec<VideoData> = if let Some(exact_term) = exact_term {
+            sqlx::query_as("SELECT file_id, caption, kind FROM videos WHERE caption_norm = ? AND (? IS NULL OR user_id = ?) LIMIT 1")
+                .bind(normalize_caption(exact_term)).bind(owner).bind(owner).fetch_all(&pool).await.unwrap_or_default()
+        } else {
+            let search_pattern = format!("%{}%", escape_like_pattern(&normalize_caption(search_term)));
+            let mut videos: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption, kind FROM videos WHERE caption_norm LIKE ? ESCAPE '\\' AND (? IS NULL OR user_id = ?) ORDER BY rowid DESC LIMIT ? OFFSET ?")
+                .bind(&search_pattern).bind(owner).bind(owner).bind(INLINE_PAGE_SIZE + 1).bind(sql_offset).fetch_all(&pool).await.unwrap_or_default();
+            has_next_page = take_page(&mut videos, INLINE_PAGE_SIZE);
+            videos
+        };
+
+        results = videos.into_iter().map(|mut video| {
+            let result_id = video_result_id("", &video.file_id);
+            let mut caption = render_caption_template(template.as_deref(), &new_caption, &sender_name);
+            if let Some(title) = title {
+                caption = if caption.is_empty() { title.to_string() } else { format!("{}\n{}", title, caption) };
+                video.caption = title.to_string();
+            }
+            cached_media_result(result_id, video, Some(caption))
         }).collect();
 
+    } else if let Some((search_term, new_caption_raw)) = q.query.split_once("/rename") {
+        let new_caption = new_caption_raw.trim();
+        let search_norm = normalize_caption(search_term);
+        let best_match = sqlx::query_as::<_, VideoData>(
+            "SELECT file_id, caption FROM videos WHERE user_id = ? AND caption_norm LIKE ? ESCAPE '\\' \
+             ORDER BY caption_norm = ? DESC, length(caption) ASC LIMIT 1")
+            .bind(q.from.id.0 as i64).bind(format!("%{}%", escape_like_pattern(&search_norm))).bind(&search_norm)
+            .fetch_optional(&pool).await.unwrap_or_default();
+
+        if let Some(video) = best_match.filter(|_| !new_caption.is_empty()) {
+            let confirmation = format!("✏️ Renamed \"{}\" to \"{}\"", video.caption, new_caption);
+            results.push(InlineQueryResult::Article(
+                InlineQueryResultArticle::new(
+                    video_result_id("rename_", &video.file_id),
+                    format!("Rename \"{}\"", video.caption),
+                    InputMessageContent::Text(InputMessageContentText::new(confirmation)),
+                )
+                .description(format!("New caption: {}", new_caption))
+            ));
+        }
+    } else if q.query.trim() == "/recent" {
+        results = fetch_recent_videos(&pool, owner, config.recent_limit).await.into_iter()
+            .map(|video| cached_video_result(video, template.as_deref(), &sender_name)).collect();
+    } else if q.query.trim() == "/top" {
+        let mut videos: Vec<VideoData> = sqlx::query_as("SELECT file_id, caption, kind FROM videos WHERE use_count > 0 AND (? IS NULL OR user_id = ?) ORDER BY use_count DESC, rowid DESC LIMIT ? OFFSET ?")
+            .bind(owner).bind(owner).bind(INLINE_PAGE_SIZE + 1).bind(sql_offset).fetch_all(&pool).await.unwrap_or_default();
+        has_next_page = take_page(&mut videos, INLINE_PAGE_SIZE);
+        results = videos.into_iter().map(|video| cached_video_result(video, template.as_deref(), &sender_name)).collect();
+    } else if let Some((search_term, _)) = q.query.split_once("/random") {
+        let random_video = sqlx::query_as::<_, VideoData>(
+            "SELECT file_id, caption, kind FROM videos WHERE caption_norm LIKE ? ESCAPE '\\' AND (? IS NULL OR user_id = ?) ORDER BY RANDOM() LIMIT 1")
+            .bind(format!("%{}%", escape_like_pattern(&normalize_caption(search_term)))).bind(owner).bind(owner)
+            .fetch_optional(&pool).await.unwrap_or_default();
+        results.push(match random_video {
+            Some(video) => cached_video_result(video, template.as_deref(), &sender_name),
+            None => InlineQueryResult::Article(
+                InlineQueryResultArticle::new(
+                    "random_empty",
+                    "No videos to pick from",
+                    InputMessageContent::Text(InputMessageContentText::new("There are no saved videos matching that search yet.")),
+                )
+                .description("Save some videos first, or try a different search."),
+            ),
+        });
     } else {
-        let videos: Vec<VideoData> = if q.query.is_empty() {
-            sqlx::query_as("SELECT file_id, caption FROM videos LIMIT ? OFFSET ?")
-                .bind(PAGE_SIZE).bind(sql_offset).fetch_all(&pool).await.unwrap_or_default()
+        let tag_query = q.query.trim().strip_prefix('#')
+            .and_then(|rest| rest.split_once(' ').or(Some((rest, ""))))
+            .filter(|(tag, _)| !tag.is_empty());
+        let mut videos: Vec<VideoData> = if let Some((tag, rest)) = tag_query {
+            sqlx::query_as("SELECT file_id, caption, kind, tags FROM videos WHERE tags LIKE ? ESCAPE '\\' AND caption_norm LIKE ? ESCAPE '\\' AND (? IS NULL OR user_id = ?) LIMIT ? OFFSET ?")
+                .bind(format!("%{}%", escape_like_pattern(&tag.to_lowercase()))).bind(format!("%{}%", escape_like_pattern(&normalize_caption(rest))))
+                .bind(owner).bind(owner).bind(INLINE_PAGE_SIZE + 1).bind(sql_offset).fetch_all(&pool).await.unwrap_or_default()
+        } else if q.query.is_empty() {
+            sqlx::query_as("SELECT file_id, caption, kind, tags FROM videos WHERE (? IS NULL OR user_id = ?) LIMIT ? OFFSET ?")
+                .bind(owner).bind(owner).bind(INLINE_PAGE_SIZE + 1).bind(sql_offset).fetch_all(&pool).await.unwrap_or_default()
         } else {
-            let pattern = format!("%{}%", q.query);
-            sqlx::query_as("SELECT file_id, caption FROM videos WHERE caption LIKE ? LIMIT ? OFFSET ?")
-                .bind(pattern).bind(PAGE_SIZE).bind(sql_offset).fetch_all(&pool).await.unwrap_or_default()
+            fuzzy_search_videos(&pool, &q.query, owner, sql_offset, &config).await
         };
+        has_next_page = take_page(&mut videos, INLINE_PAGE_SIZE);
 
-        results = videos.into_iter().map(|video| {
-            let mut result_id = video.file_id.clone();
-            result_id.truncate(60);
-            InlineQueryResult::CachedVideo(InlineQueryResultCachedVideo::new(result_id, video.file_id, video.caption.clone()))
-        }).collect();
+        results = videos.into_iter().map(|video| cached_video_result(video, template.as_deref(), &sender_name)).collect();
     }
 
-    let next_offset = if results.len() == PAGE_SIZE as usize { Some((page + 1).to_string()) } else { None };
+    let next_offset = if has_next_page { Some((page + 1).to_string()) } else { None };
 
     let mut answer = bot.answer_inline_query(q.id, results);
     if let Some(offset) = next_offset {
         answer = answer.next_offset(offset);
     }
 
-    if q.query.contains("/edit") || q.query.contains("/caption") {
+    if q.query.contains("/edit") || q.query.contains("/fade") || q.query.contains("/caption") || q.query.contains("/recent") || q.query.contains("/rename") || q.query.contains("/random") || q.query.contains("/top") {
         answer = answer.cache_time(0);
     }
 
@@ -805,6 +4587,130 @@ async fn handle_inline_query(bot: Bot, q: InlineQuery, pool: SharedState) -> Res
     Ok(())
 }
 
+/// Runs `detect_motion_crop` on the first frame and the frame at 2s.
+async fn detect_two_frame_crop(input_path: &Path, temp_dir_path: &Path, thresholds: MotionThresholds) -> Option<CropRect> {
+    let frame_a_path = temp_dir_path.join("frame_a.png");
+    let frame_b_path = temp_dir_path.join("frame_b.png");
+
+    let frame_a_status = tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(input_path).arg("-vf").arg("select='eq(n,0)'")
+        .arg("-vframes").arg("1").arg("-y").arg(&frame_a_path).status().await.ok();
+
+    let frame_b_status = tokio::process::Command::new("ffmpeg")
+        .arg("-ss").arg("2").arg("-i").arg(input_path)
+        .arg("-vframes").arg("1").arg("-y").arg(&frame_b_path).status().await.ok();
+
+    if frame_a_status.is_some_and(|s| s.success()) && frame_b_status.is_some_and(|s| s.success()) {
+        detect_motion_off_runtime(move || detect_motion_crop(&frame_a_path, &frame_b_path, thresholds)).await
+    } else {
+        None
+    }
+}
+
+/// Extends a crop filter so the cropped picture is centred on a canvas with the original
+/// `width:height` aspect, filled with a blurred, zoomed copy of itself instead of bars.
+/// Used when `CROP_PAD_TO_ASPECT` is set.
+fn blurred_pad_filter(crop_filter: &str, crop: &CropRect, width: u32, height: u32) -> String {
+    let target_aspect = width as f64 / height as f64;
+    let (canvas_w, canvas_h) = if (crop.w as f64 / crop.h as f64) < target_aspect {
+        ((crop.h as f64 * target_aspect).round() as u32, crop.h)
+    } else {
+        (crop.w, (crop.w as f64 / target_aspect).round() as u32)
+    };
+    // yuv420p needs even dimensions.
+    let (canvas_w, canvas_h) = (canvas_w / 2 * 2, canvas_h / 2 * 2);
+    format!(
+        "{crop_filter},split=2[fg][bg_src]; \
+         [bg_src]scale={cw}:{ch}:force_original_aspect_ratio=increase,crop={cw}:{ch},boxblur=20:2[bg]; \
+         [bg][fg]overlay=(W-w)/2:(H-h)/2,setsar=1[v_out]",
+        crop_filter = crop_filter, cw = canvas_w, ch = canvas_h
+    )
+}
+
+/// Names the sides a crop trims off the frame, classified as letterbox (top/bottom),
+/// pillarbox (left/right) or both, e.g. "Removed top and bottom bars (letterbox)".
+/// Edges within a couple of pixels of the frame count as untouched.
+fn describe_removed_bars(crop: &CropRect, width: u32, height: u32) -> Option<String> {
+    const TOLERANCE: u32 = 2;
+    let sides: Vec<&str> = [
+        ("top", crop.y > TOLERANCE),
+        ("bottom", crop.y + crop.h + TOLERANCE < height),
+        ("left", crop.x > TOLERANCE),
+        ("right", crop.x + crop.w + TOLERANCE < width),
+    ].into_iter().filter(|(_, removed)| *removed).map(|(side, _)| side).collect();
+
+    let vertical = sides.contains(&"top") || sides.contains(&"bottom");
+    let horizontal = sides.contains(&"left") || sides.contains(&"right");
+    let kind = match (vertical, horizontal) {
+        (true, true) => "letterbox and pillarbox",
+        (true, false) => "letterbox",
+        (false, true) => "pillarbox",
+        (false, false) => return None,
+    };
+    let listed = match sides.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+        None => return None,
+    };
+    let noun = if sides.len() == 1 { "bar" } else { "bars" };
+    Some(format!("Removed {} {} ({})", listed, noun, kind))
+}
+
+/// Extracts the first frame plus up to a few scene-change frames (ffmpeg `gt(scene,0.3)`)
+/// for `CROP_SCENE_SAMPLING`. Returns the frame paths in playback order.
+async fn extract_scene_frames(input_path: &Path, temp_dir_path: &Path) -> Vec<PathBuf> {
+    const MAX_SCENE_FRAMES: u32 = 6;
+    let frame_pattern = temp_dir_path.join("scene_%02d.png");
+
+    let status = tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(input_path)
+        .arg("-vf").arg("select='eq(n,0)+gt(scene,0.3)'")
+        .arg("-vsync").arg("vfr")
+        .arg("-frames:v").arg(MAX_SCENE_FRAMES.to_string())
+        .arg("-y").arg(&frame_pattern)
+        .status().await;
+
+    if !status.is_ok_and(|s| s.success()) {
+        log::warn!("ffmpeg scene frame extraction failed.");
+        return vec![];
+    }
+
+    (1..=MAX_SCENE_FRAMES)
+        .map(|i| temp_dir_path.join(format!("scene_{:02}.png", i)))
+        .take_while(|path| path.exists())
+        .collect()
+}
+
+/// Re-encodes a clip that is over the upload limit at a bitrate that should fit, leaving some
+/// headroom for the container. Returns `None` if the shrunk file is still too big.
+async fn shrink_to_upload_limit(input_path: &Path, temp_dir_path: &Path, max_bytes: u64) -> Option<PathBuf> {
+    let duration = probe_duration(input_path).await;
+    if duration <= 0.0 {
+        return None;
+    }
+    const AUDIO_KBPS: f64 = 128.0;
+    let video_kbps = (max_bytes as f64 * 8.0 * 0.9 / 1000.0 / duration - AUDIO_KBPS).floor();
+    if video_kbps < 100.0 {
+        log::warn!("A {:.1}s clip can't fit in {} even at a low bitrate.", duration, format_size(max_bytes as i64));
+        return None;
+    }
+    let output_path = temp_dir_path.join("shrunk.mp4");
+    log::info!("Re-encoding at {}k to fit the upload limit.", video_kbps);
+    let shrunk = run_encode(tokio::process::Command::new("ffmpeg")
+        .arg("-i").arg(input_path).arg("-map").arg("0:v:0").arg("-map").arg("0:a?")
+        .arg("-c:v").arg("libx264").arg("-preset").arg("veryfast")
+        .arg("-b:v").arg(format!("{}k", video_kbps)).arg("-maxrate").arg(format!("{}k", video_kbps))
+        .arg("-bufsize").arg(format!("{}k", video_kbps * 2.0))
+        .arg("-c:a").arg("aac").arg("-b:a").arg(format!("{}k", AUDIO_KBPS))
+        .arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg("-y").arg(&output_path)).await;
+    if !shrunk {
+        return None;
+    }
+    let size = fs::metadata(&output_path).await.map(|m| m.len()).unwrap_or(u64::MAX);
+    (size <= max_bytes).then_some(output_path)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn autocrop_and_upload_video(
     bot: Bot,
     chat_id: ChatId,
@@ -813,137 +4719,537 @@ async fn autocrop_and_upload_video(
     output_path: &Path,
     temp_dir_path: &Path,
     caption: &str,
-) -> (String, String) {
+    options: &SaveOptions,
+    pool: &SharedState,
+    config: &Config,
+) -> (String, &'static str, Option<String>, String) {
+    let encoder = options.encoder.clone().unwrap_or_else(|| config.encoder.clone());
+    if let Some((start, end)) = options.trim {
+        let clip_length = probe_duration(input_path).await;
+        if end > clip_length + 0.05 {
+            let message = format!("❌ Error: The trim range {}-{}s goes past the end of this {:.1}s clip.", start, end, clip_length);
+            return ("".to_string(), "video", None, message);
+        }
+    }
+    let trimmed_path;
+    let input_path = match options.trim {
+        Some((start, end)) => match trim_clip(input_path, temp_dir_path, start, end, &encoder).await {
+            Some(path) => { trimmed_path = path; trimmed_path.as_path() }
+            None => return ("".to_string(), "video", None, "❌ Error: Failed to trim the video.".to_string()),
+        },
+        None => input_path,
+    };
+    let normalized_path = apply_rotation(input_path, temp_dir_path, &encoder).await;
+    let input_path = normalized_path.as_path();
     let mut final_upload_path = input_path.to_path_buf();
     let final_message_text: String;
     let mut crop_result = None;
 
-    let duration: f64 = match tokio::process::Command::new("ffprobe")
-        .arg("-v").arg("error")
-        .arg("-show_entries").arg("format=duration")
-        .arg("-of").arg("default=noprint_wrappers=1:nokey=1")
-        .arg(input_path)
-        .output().await {
-            Ok(out) => String::from_utf8(out.stdout).unwrap_or_default().trim().parse().unwrap_or(0.0),
-            Err(_) => 0.0,
+    let audio_map = match resolve_audio_map(input_path, options.audio_track).await {
+        Ok(map) => map,
+        Err(message) => return ("".to_string(), "video", None, message),
+    };
+
+    let duration = probe_duration(input_path).await;
+
+    let min_crop_duration = config.crop_min_duration;
+    let autocrop_disabled = get_global_flag(pool, SETTING_AUTOCROP_DISABLED).await;
+    if options.no_crop {
+        log::info!("Saving with /nocrop. Skipping crop detection.");
+    } else if autocrop_disabled {
+        log::info!("Auto-crop is disabled globally. Skipping crop detection.");
+    } else if duration <= min_crop_duration {
+        // Too short for a meaningful frame pair; optionally fall back to a cropdetect pass.
+        if config.crop_short_clip_cropdetect {
+            log::info!("Clip is shorter than {}s. Falling back to cropdetect.", min_crop_duration);
+            let dimensions = probe_dimensions(input_path).await;
+            crop_result = run_cropdetect(input_path, config).await
+                .filter(|crop| crop.w > 0 && crop.h > 0 && dimensions.is_some_and(|(w, h)| (crop.w, crop.h) != (w, h)));
+        }
+    } else {
+        let mut scene_sampled = false;
+        if config.crop_scene_sampling {
+            let scene_frames = extract_scene_frames(input_path, temp_dir_path).await;
+            if scene_frames.len() >= 2 {
+                let thresholds = config.motion_thresholds;
+                crop_result = detect_motion_off_runtime(move || detect_motion_crop_across_frames(&scene_frames, thresholds)).await;
+                scene_sampled = true;
+            } else {
+                log::info!("Not enough scene changes found. Falling back to two-frame crop detection.");
+            }
+        }
+
+        if !scene_sampled {
+            crop_result = detect_two_frame_crop(input_path, temp_dir_path, config.motion_thresholds).await;
+        }
+    }
+
+    if let Some(crop) = crop_result {
+        let dimensions = probe_dimensions(input_path).await;
+        let removed_bars = dimensions.and_then(|(width, height)| describe_removed_bars(&crop, width, height));
+        if let Some(description) = &removed_bars {
+            log::info!("Crop {:?}: {}", crop, description);
+        }
+        let crop_filter = format!("[0:v]crop={w}:{h}:{x}:{y},setsar=1", w = crop.w, h = crop.h, x = crop.x, y = crop.y);
+        let filter_complex = match dimensions.filter(|_| config.crop_pad_to_aspect) {
+            Some((width, height)) => blurred_pad_filter(&crop_filter, &crop, width, height),
+            None => format!("{}[v_out]", crop_filter),
+        };
+        let mut command = tokio::process::Command::new("ffmpeg");
+        command.arg("-i").arg(input_path).arg("-filter_complex").arg(&filter_complex)
+               .arg("-map").arg("[v_out]").arg("-map").arg(&audio_map).arg("-c:a").arg("copy");
+        configure_ffmpeg_encoder(&mut command, &encoder);
+        command.arg("-flags").arg("+global_header").arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg(output_path);
+
+        match run_with_disk_guard(&mut command, temp_dir_path, config).await {
+            Ok(true) => {
+                final_upload_path = output_path.to_path_buf();
+                final_message_text = match &removed_bars {
+                    Some(description) => format!("✅ Video cropped and saved! {}.", description),
+                    None => "✅ Video cropped and saved!".to_string(),
+                };
+            }
+            Err(message) => {
+                fs::remove_file(output_path).await.ok();
+                final_message_text = format!("{} Saved the original instead.", message);
+            }
+            Ok(false) => {
+                log::warn!("ffmpeg crop failed. Saving original video.");
+                final_message_text = "⚠️ Video processing failed, saved original.".to_string();
+            }
+        }
+    } else {
+        final_message_text = if options.no_crop {
+            "✅ Video saved without autocrop!".to_string()
+        } else if autocrop_disabled {
+            "✅ Video saved! (Auto-crop is currently disabled)".to_string()
+        } else {
+            "✅ Video saved! (No removable borders were detected)".to_string()
+        };
+        if options.audio_track.is_some() {
+            // Nothing to re-encode, but the chosen audio track still has to be remuxed in.
+            let remuxed = run_encode(tokio::process::Command::new("ffmpeg")
+                .arg("-i").arg(input_path)
+                .arg("-map").arg("0:v:0").arg("-map").arg(&audio_map)
+                .arg("-c").arg("copy").arg("-movflags").arg("+faststart")
+                .arg("-y").arg(output_path)).await;
+            if remuxed {
+                final_upload_path = output_path.to_path_buf();
+            } else {
+                log::warn!("ffmpeg audio track remux failed. Saving original video.");
+            }
+        }
+    }
+
+    let max_bytes = config.max_upload_bytes;
+    let upload_size = fs::metadata(&final_upload_path).await.map(|m| m.len()).unwrap_or(0);
+    if upload_size > max_bytes {
+        match shrink_to_upload_limit(&final_upload_path, temp_dir_path, max_bytes).await {
+            Some(shrunk_path) => final_upload_path = shrunk_path,
+            None => {
+                let message = format!("❌ Error: The video is too large to upload ({}, the limit is {}).",
+                    format_size(upload_size as i64), format_size(max_bytes as i64));
+                return ("".to_string(), "video", None, message);
+            }
+        }
+    }
+
+    let display_caption = truncate_caption(&render_caption_template(options.caption_template.as_deref(), caption, &options.uploader));
+    let as_animation = send_as_animation(&final_upload_path, config).await;
+    // Best effort: without it Telegram picks the first frame, which is often black.
+    let thumb_path = temp_dir_path.join("thumb.jpg");
+    let thumb_time = (probe_duration(&final_upload_path).await / 2.0).min(1.0);
+    let thumb = extract_thumbnail_frame(&final_upload_path, thumb_time, &thumb_path).await.then(|| InputFile::file(&thumb_path));
+    let upload = if as_animation {
+        retry_upload(|| {
+            let mut request = bot.send_animation(chat_id, InputFile::file(&final_upload_path)).caption(display_caption.clone()).reply_to_message_id(user_message_id);
+            if let Some(thumb) = thumb.clone() {
+                request = request.thumb(thumb);
+            }
+            request.send()
+        }).await
+    } else {
+        retry_upload(|| {
+            let mut request = bot.send_video(chat_id, InputFile::file(&final_upload_path)).caption(display_caption.clone()).reply_to_message_id(user_message_id);
+            if let Some(thumb) = thumb.clone() {
+                request = request.thumb(thumb);
+            }
+            request.send()
+        }).await
+    };
+    let kind = if as_animation { KIND_ANIMATION } else { "video" };
+    match upload {
+        Ok(sent_message) => {
+            let uploaded = if as_animation {
+                sent_message.animation().map(|a| (&a.file, &a.thumb))
+            } else {
+                sent_message.video().map(|v| (&v.file, &v.thumb))
+            };
+            if let Some((file, thumb)) = uploaded {
+                (file.id.clone(), kind, thumb.as_ref().map(|t| t.file.id.clone()), final_message_text)
+            } else {
+                ("".to_string(), kind, None, "❌ Error: Telegram did not return video data after upload.".to_string())
+            }
+        },
+        Err(e) => {
+            log::error!("Failed to upload final video: {}", e);
+            ("".to_string(), kind, None, "❌ Error: Failed to upload video.".to_string())
+        }
+    }
+}
+
+/// Uploads an `/audio` save with `send_audio` and stores it as `KIND_AUDIO`, skipping the crop
+/// pipeline entirely.
+#[allow(clippy::too_many_arguments)]
+async fn upload_and_save_audio(
+    bot: &Bot, chat_id: ChatId, user_message_id: MessageId, status_message_id: MessageId,
+    audio_path: &Path, caption: &str, pool: &SharedState, user_id: UserId, options: &SaveOptions, config: &Config,
+) -> bool {
+    let Ok(meta) = fs::metadata(audio_path).await else {
+        bot.edit_message_text(chat_id, status_message_id, "❌ Error: Downloaded audio file not found.").await.ok();
+        return false;
+    };
+    let max_bytes = config.max_upload_bytes;
+    if meta.len() > max_bytes {
+        let message = format!("❌ Error: The audio is too large to upload ({}, the limit is {}).",
+            format_size(meta.len() as i64), format_size(max_bytes as i64));
+        bot.edit_message_text(chat_id, status_message_id, message).await.ok();
+        return false;
+    }
+
+    let display_caption = truncate_caption(&render_caption_template(options.caption_template.as_deref(), caption, &options.uploader));
+    let upload = retry_upload(|| bot.send_audio(chat_id, InputFile::file(audio_path)).title(caption).caption(display_caption.clone())
+        .reply_to_message_id(user_message_id).send()).await;
+    let file_id = match upload {
+        Ok(sent_message) => match sent_message.audio() {
+            Some(audio) => audio.file.id.clone(),
+            None => {
+                bot.edit_message_text(chat_id, status_message_id, "❌ Error: Telegram did not return audio data after upload.").await.ok();
+                return false;
+            }
+        },
+        Err(e) => {
+            log::error!("{}Failed to upload audio: {}", job_tag(), e);
+            bot.edit_message_text(chat_id, status_message_id, "❌ Error: Failed to upload audio.").await.ok();
+            return false;
+        }
+    };
+
+    finish_save(bot, chat_id, status_message_id, pool, &file_id, KIND_AUDIO, None, caption, options, user_id, "✅ Audio saved!".to_string(), config).await;
+    true
+}
+
+/// Stores a processed video and reports the result on the status message, warning when the
+/// user already has a video with the same normalized caption.
+#[allow(clippy::too_many_arguments)]
+async fn finish_save(
+    bot: &Bot, chat_id: ChatId, status_message_id: MessageId, pool: &SharedState,
+    file_id: &str, kind: &str, thumb_file_id: Option<&str>, caption: &str, options: &SaveOptions, user_id: UserId, final_message_text: String,
+    config: &Config,
+) {
+    let tags = (!options.tags.is_empty()).then(|| options.tags.join(" "));
+    let user_id_i64 = user_id.0 as i64;
+    let caption_norm = normalize_caption(caption);
+
+    let duplicates = sqlx::query_as::<_, Count>("SELECT COUNT(*) as count FROM videos WHERE user_id = ? AND caption_norm = ? AND file_id != ?")
+        .bind(user_id_i64).bind(&caption_norm).bind(file_id).fetch_one(pool).await.map(|c| c.count).unwrap_or(0);
+
+    if sqlx::query("INSERT OR IGNORE INTO videos (file_id, caption, user_id, caption_norm, kind, caption_translit, tags, thumb_file_id, source_url, caption_folded) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(file_id).bind(caption).bind(user_id_i64).bind(&caption_norm).bind(kind).bind(caption_translit(caption, config)).bind(tags).bind(thumb_file_id)
+        .bind(&options.source_url).bind(caption_folded(caption)).execute(pool).await.is_ok_and(|done| done.rows_affected() > 0)
+    {
+        metrics::counter!("videos_saved_total").increment(1);
+        let text = if duplicates > 0 {
+            format!("{}\n⚠️ You already have a video with this caption.", final_message_text)
+        } else {
+            final_message_text
         };
+        bot.edit_message_text(chat_id, status_message_id, text).await.ok();
+    } else {
+        bot.edit_message_text(chat_id, status_message_id, "❌ DB error while saving video.").await.ok();
+    }
+}
+
+/// Link fragments of the sites downloads are accepted from; anything yt-dlp supports can be added.
+const SUPPORTED_URL_PATTERNS: &[&str] = &[
+    "douyin.com", "vk.com",
+    "youtube.com/clip/", "youtube.com/shorts/",
+    "instagram.com/reel/", "bsky.app",
+    "x.com/", "twitter.com/",
+    "reddit.com/", "tiktok.com",
+    "vimeo.com/", "clips.twitch.tv/",
+];
+
+/// The cookie file yt-dlp gets for a link, from `Config::cookie_files`. A domain also covers its
+/// subdomains. `None` when the site has no cookies configured or its file is missing.
+fn cookie_file_for(url: &str, config: &Config) -> Option<PathBuf> {
+    let host = Url::parse(url).ok()?.host_str()?.to_lowercase();
+    let path = config.cookie_files.iter().find_map(|(domain, path)| {
+        let matches = host == *domain || host.ends_with(&format!(".{}", domain));
+        matches.then(|| path.clone())
+    })?;
+    if !path.is_file() {
+        log::warn!("Cookie file {} for {} is missing. Downloading without cookies.", path.display(), host);
+        return None;
+    }
+    Some(path)
+}
+
+/// Whether a message word is a link to a supported site. A pattern's domain has to be the link's
+/// host or a parent of it and its path part a prefix of the link's path, so `netflix.com` doesn't
+/// pass for `x.com/`. Twitch clips also come as `twitch.tv/<channel>/clip/<id>`, which a plain
+/// pattern can't express.
+fn is_supported_url(word: &str) -> bool {
+    let rest = word.split_once("://").map_or(word, |(_, rest)| rest);
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, ""),
+    };
+    let host = host.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+    let on_domain = |domain: &str| host == domain || host.ends_with(&format!(".{}", domain));
+    SUPPORTED_URL_PATTERNS.iter().any(|pattern| {
+        let (domain, prefix) = pattern.find('/').map_or((*pattern, ""), |slash| pattern.split_at(slash));
+        on_domain(domain) && path.starts_with(prefix)
+    }) || (on_domain("twitch.tv") && path.split('/').nth(2) == Some("clip"))
+}
+
+/// Query parameters Instagram adds for share tracking, which are safe to drop.
+const INSTAGRAM_TRACKING_PARAMS: &[&str] = &["igshid", "igsh", "utm_source", "utm_medium", "utm_campaign"];
+
+/// Brackets and quotes a link can be wrapped in.
+const LINK_OPENERS: &[char] = &['(', '[', '<', '{', '"', '\'', '«'];
+/// Closing brackets, quotes and sentence punctuation that can follow a link.
+const LINK_CLOSERS: &[char] = &[')', ']', '>', '}', '"', '\'', '»', '.', ',', ';', ':', '!', '?'];
+
+/// Cleans up a pasted link for yt-dlp: strips brackets, quotes and sentence punctuation around
+/// it and Instagram's tracking parameters, and adds `https://` to links pasted without a scheme.
+/// Returns `None` if what's left isn't a valid http(s) URL.
+fn normalize_url(word: &str) -> Option<String> {
+    let trimmed = word.trim_start_matches(LINK_OPENERS).trim_end_matches(LINK_CLOSERS);
+    let mut url = if trimmed.contains("://") {
+        Url::parse(trimmed).ok()?
+    } else {
+        Url::parse(&format!("https://{}", trimmed)).ok()?
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    if url.host_str().is_some_and(|host| host.ends_with("instagram.com")) {
+        let kept: Vec<(String, String)> = url.query_pairs()
+            .filter(|(key, _)| !INSTAGRAM_TRACKING_PARAMS.contains(&key.as_ref()))
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+        if kept.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(kept);
+        }
+    }
+    Some(url.to_string())
+}
+
+/// A per-user token bucket for link downloads.
+struct DownloadBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl DownloadBucket {
+    /// The tokens the bucket holds at `now`, counting the refill since it was last touched.
+    fn tokens_at(&self, now: Instant, capacity: f64, refill_per_sec: f64) -> f64 {
+        (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * refill_per_sec).min(capacity)
+    }
+
+    /// Takes a token, or returns how many seconds until the next one.
+    fn take(&mut self, now: Instant, capacity: f64, refill_per_sec: f64) -> Result<(), u64> {
+        self.tokens = self.tokens_at(now, capacity, refill_per_sec);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / refill_per_sec).ceil() as u64)
+        }
+    }
+}
 
-    if duration > 1.5 {
-        let frame_a_path = temp_dir_path.join("frame_a.png");
-        let frame_b_path = temp_dir_path.join("frame_b.png");
+static DOWNLOAD_BUCKETS: Lazy<DashMap<UserId, DownloadBucket>> = Lazy::new(DashMap::new);
 
-        let frame_a_status = tokio::process::Command::new("ffmpeg")
-            .arg("-i").arg(input_path).arg("-vf").arg("select='eq(n,0)'")
-            .arg("-vframes").arg("1").arg("-y").arg(&frame_a_path).status().await.ok();
+/// Takes a download token for the user. Buckets hold `DOWNLOAD_RATE_LIMIT` tokens (default 5)
+/// and refill continuously over `DOWNLOAD_RATE_WINDOW_SECS` (default 60). When empty, returns
+/// how many seconds until the next token. Buckets that have refilled completely are no
+/// different from new ones, so they are dropped to keep the map from growing with every user.
+fn take_download_token(user_id: UserId, config: &Config) -> Result<(), u64> {
+    let capacity = config.download_rate_limit as f64;
+    let window = config.download_rate_window_secs as f64;
 
-        let frame_b_status = tokio::process::Command::new("ffmpeg")
-            .arg("-ss").arg("2").arg("-i").arg(input_path)
-            .arg("-vframes").arg("1").arg("-y").arg(&frame_b_path).status().await.ok();
+    let refill_per_sec = capacity / window;
 
-        if frame_a_status.is_some_and(|s| s.success()) && frame_b_status.is_some_and(|s| s.success()) {
-            crop_result = detect_motion_crop(&frame_a_path, &frame_b_path);
-        }
-    }
+    let now = Instant::now();
+    DOWNLOAD_BUCKETS.retain(|_, bucket| bucket.tokens_at(now, capacity, refill_per_sec) < capacity);
+    DOWNLOAD_BUCKETS.entry(user_id)
+        .or_insert_with(|| DownloadBucket { tokens: capacity, refilled_at: now })
+        .take(now, capacity, refill_per_sec)
+}
 
-    if let Some(crop) = crop_result {
-        let filter_complex = format!("[0:v]crop={w}:{h}:{x}:{y},setsar=1[v_out]", w = crop.w, h = crop.h, x = crop.x, y = crop.y);
-        let mut command = tokio::process::Command::new("ffmpeg");
-        command.arg("-i").arg(input_path).arg("-filter_complex").arg(&filter_complex)
-               .arg("-map").arg("[v_out]").arg("-map").arg("0:a?").arg("-c:a").arg("copy");
-        configure_ffmpeg_encoder(&mut command);
-        command.arg("-flags").arg("+global_header").arg("-movflags").arg("+faststart").arg("-pix_fmt").arg("yuv420p").arg(output_path);
+/// Why a yt-dlp download failed, as far as its stderr tells.
+#[derive(Debug)]
+enum DownloadFailure {
+    Private,
+    /// The site wants a logged-in session, e.g. YouTube's "sign in to confirm you're not a bot".
+    AuthRequired,
+    Unavailable,
+    Unsupported,
+    NoFormat,
+    /// Anything else, e.g. a network error. Only these are retried.
+    Transient(String),
+}
 
-        if command.status().await.is_ok_and(|s| s.success()) {
-            final_upload_path = output_path.to_path_buf();
-            final_message_text = "✅ Video cropped and saved!".to_string();
+impl DownloadFailure {
+    fn from_stderr(stderr: &str) -> Self {
+        let lower = stderr.to_lowercase();
+        let has = |signatures: &[&str]| signatures.iter().any(|s| lower.contains(s));
+        if has(&["private video", "this video is private", "account is private"]) {
+            DownloadFailure::Private
+        } else if has(&["login required", "log in", "sign in to confirm"]) {
+            DownloadFailure::AuthRequired
+        } else if has(&["requested format is not available"]) {
+            DownloadFailure::NoFormat
+        } else if has(&["video unavailable", "not available", "has been removed", "http error 404", "http error 410", "no video could be found"]) {
+            DownloadFailure::Unavailable
+        } else if has(&["unsupported url", "is not a valid url"]) {
+            DownloadFailure::Unsupported
         } else {
-            log::warn!("ffmpeg crop failed. Saving original video.");
-            final_message_text = "⚠️ Video processing failed, saved original.".to_string();
+            let last_line = stderr.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("no output");
+            DownloadFailure::Transient(last_line.trim().to_string())
         }
-    } else {
-        final_message_text = "✅ Video saved! (No removable borders were detected)".to_string();
     }
 
-    match bot.send_video(chat_id, InputFile::file(&final_upload_path)).caption(caption).reply_to_message_id(user_message_id).await {
-        Ok(sent_message) => {
-            if let Some(video) = sent_message.video() {
-                (video.file.id.clone(), final_message_text)
-            } else {
-                ("".to_string(), "❌ Error: Telegram did not return video data after upload.".to_string())
-            }
-        },
-        Err(e) => {
-            log::error!("Failed to upload final video: {}", e);
-            ("".to_string(), "❌ Error: Failed to upload video.".to_string())
+    fn user_message(&self) -> &'static str {
+        match self {
+            DownloadFailure::Private => "❌ Error: This video is private.",
+            DownloadFailure::AuthRequired => "❌ Error: This site wants a login before it shows the video. Ask the bot admin to set up cookies for it.",
+            DownloadFailure::Unavailable => "❌ Error: This video is unavailable or was removed.",
+            DownloadFailure::Unsupported => "❌ Error: This link isn't supported.",
+            DownloadFailure::NoFormat => "❌ Error: No version of this video is small enough to send. Try /sd.",
+            DownloadFailure::Transient(_) => "❌ Error: Download failed after several attempts. Please try again later.",
         }
     }
 }
 
+impl std::fmt::Display for DownloadFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadFailure::Transient(detail) => write!(f, "yt-dlp failed: {}", detail),
+            other => write!(f, "yt-dlp failed: {:?}", other),
+        }
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
 async fn download_and_process_video(
     bot: Bot, chat_id: ChatId, user_message_id: MessageId, status_message_id: MessageId,
-    url: String, caption: String, pool: SharedState, user_id: UserId,
-) {
+    url: String, caption: String, pool: SharedState, user_id: UserId, mut options: SaveOptions, config: &Config,
+) -> JobOutcome {
+    options.source_url = Some(url.clone());
     let temp_dir = match Builder::new().prefix("video_dl").tempdir() {
         Ok(dir) => dir,
         Err(e) => {
-            log::error!("Failed to create temp dir: {}", e);
+            log::error!("{}Failed to create temp dir: {}", job_tag(), e);
             bot.edit_message_text(chat_id, status_message_id, "❌ Error: Server failed to create temporary directory.").await.ok();
-            return;
+            return JobOutcome::Failed;
         }
     };
     let temp_dir_path = temp_dir.path();
-    let output_template = temp_dir_path.join("video.mp4");
-
-    let ytdlp_status = tokio::process::Command::new("yt-dlp")
-        .arg("--output").arg(output_template)
-        .arg("--force-overwrite")
-        .arg("--format").arg("bv*[ext=mp4][filesize<20M]+ba[ext=m4a]/b[ext=mp4][filesize<20M]/bv*+ba/b")
-        .arg("--cookies").arg("./instacookie")
-        .arg("--remux-video").arg("mp4")
-        .arg(&url).status().await;
-
-    if !ytdlp_status.is_ok_and(|s| s.success()) {
-        log::error!("yt-dlp failed for url {}", &url);
-        bot.edit_message_text(chat_id, status_message_id, "❌ Error: Download failed. The link may be invalid or private.").await.ok();
-        return;
+    let output_template = if options.audio_only { temp_dir_path.join("audio.%(ext)s") } else { temp_dir_path.join("video.mp4") };
+
+    let format_selector = DownloadQuality::format_selector(options.quality);
+    log::info!("{}Downloading {} with quality {:?} (format {}).", job_tag(), &url, options.quality, format_selector);
+    let cookie_file = cookie_file_for(&url, config);
+
+    let is_transient = |failure: &DownloadFailure| matches!(failure, DownloadFailure::Transient(_));
+    let ytdlp_result = retry_with_backoff_if(&DOWNLOAD_BACKOFF, is_transient, || {
+        let mut command = tokio::process::Command::new("yt-dlp");
+        command.arg("--output").arg(&output_template).arg("--force-overwrite");
+        if let Some(cookie_file) = &cookie_file {
+            command.arg("--cookies").arg(cookie_file);
+        }
+        if options.audio_only {
+            command.arg("--format").arg("ba").arg("--extract-audio").arg("--audio-format").arg("mp3");
+        } else {
+            command.arg("--format").arg(format_selector).arg("--remux-video").arg("mp4");
+        }
+        command.arg(&url).stdout(Stdio::null());
+        async move {
+            match command.output().await {
+                Ok(output) if output.status.success() => Ok(()),
+                Ok(output) => Err(DownloadFailure::from_stderr(&String::from_utf8_lossy(&output.stderr))),
+                Err(e) => Err(DownloadFailure::Transient(format!("failed to spawn yt-dlp: {}", e))),
+            }
+        }
+    }).await;
+
+    if let Err(failure) = ytdlp_result {
+        metrics::counter!("download_failures_total").increment(1);
+        log::error!("{}Download of {} failed: {}", job_tag(), &url, failure);
+        bot.edit_message_text(chat_id, status_message_id, failure.user_message()).await.ok();
+        return if matches!(failure, DownloadFailure::Transient(_)) { JobOutcome::Failed } else { JobOutcome::Rejected };
+    }
+
+    if options.audio_only {
+        return upload_and_save_audio(&bot, chat_id, user_message_id, status_message_id, &temp_dir_path.join("audio.mp3"), &caption, &pool, user_id, &options, config).await.into();
     }
 
     let input_path = temp_dir_path.join("video.mp4");
     if !input_path.exists() {
         bot.edit_message_text(chat_id, status_message_id, "❌ Error: Downloaded video file not found.").await.ok();
-        return;
+        return JobOutcome::Failed;
+    }
+
+    // yt-dlp sometimes "succeeds" with an empty file or an HTML error page.
+    if !is_valid_video(&input_path).await {
+        log::error!("{}Downloaded file for url {} is not a valid video.", job_tag(), &url);
+        fs::remove_file(&input_path).await.ok();
+        bot.edit_message_text(chat_id, status_message_id, "❌ Error: Downloaded file is not a valid video.").await.ok();
+        return JobOutcome::Failed;
     }
 
     let output_path = temp_dir_path.join("output.mp4");
 
-    let (final_file_id, final_message_text) = autocrop_and_upload_video(
-        bot.clone(), chat_id, user_message_id, &input_path, &output_path, temp_dir.path(), &caption
+    let (final_file_id, kind, thumb_file_id, final_message_text) = autocrop_and_upload_video(
+        bot.clone(), chat_id, user_message_id, &input_path, &output_path, temp_dir.path(), &caption, &options, &pool, config
     ).await;
 
     if final_file_id.is_empty() {
         bot.edit_message_text(chat_id, status_message_id, final_message_text).await.ok();
-        return;
+        return JobOutcome::Failed;
     }
 
-    let user_id_i64 = user_id.0 as i64;
-    if sqlx::query("INSERT OR IGNORE INTO videos (file_id, caption, user_id) VALUES (?, ?, ?)")
-        .bind(&final_file_id).bind(&caption).bind(user_id_i64).execute(&pool).await.is_ok()
-    {
-        bot.edit_message_text(chat_id, status_message_id, final_message_text).await.ok();
-    } else {
-        bot.edit_message_text(chat_id, status_message_id, "❌ DB error while saving video.").await.ok();
+    finish_save(&bot, chat_id, status_message_id, &pool, &final_file_id, kind, thumb_file_id.as_deref(), &caption, &options, user_id, final_message_text, config).await;
+    JobOutcome::Done
+}
+
+/// The file id of a message's video, or of its document when that is an mp4 file sent
+/// uncompressed. Documents are re-uploaded with `send_video`, so they end up as regular videos.
+fn saveable_video_file_id(msg: &Message) -> Option<String> {
+    if let Some(video) = msg.video() {
+        return Some(video.file.id.clone());
     }
+    let document = msg.document()?;
+    let is_mp4 = document.mime_type.as_ref().is_some_and(|mime| mime.essence_str() == "video/mp4")
+        || document.file_name.as_deref().is_some_and(|name| name.to_lowercase().ends_with(".mp4"));
+    is_mp4.then(|| document.file.id.clone())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_and_save_video(
     bot: Bot, chat_id: ChatId, user_message_id: MessageId, status_message_id: MessageId,
-    video: Video, caption: String, pool: SharedState, user_id: UserId
+    video_file_id: String, caption: String, pool: SharedState, user_id: UserId, options: SaveOptions, config: Arc<Config>,
 ) {
     let temp_dir = match Builder::new().prefix("video_save").tempdir() {
         Ok(dir) => dir,
         Err(e) => {
-            log::error!("Failed to create temp dir: {}", e);
+            log::error!("{}Failed to create temp dir: {}", job_tag(), e);
             bot.edit_message_text(chat_id, status_message_id, "❌ Error: Server failed to create temporary directory.").await.ok();
             return;
         }
@@ -951,7 +5257,7 @@ async fn process_and_save_video(
     let temp_dir_path = temp_dir.path();
     let input_path = temp_dir_path.join("input.mp4");
 
-    let file = match bot.get_file(&video.file.id).await {
+    let file = match bot.get_file(&video_file_id).await {
         Ok(f) => f,
         Err(_) => {
             bot.edit_message_text(chat_id, status_message_id, "❌ Error: Failed to get file info.").await.ok();
@@ -972,10 +5278,23 @@ async fn process_and_save_video(
         return;
     };
 
+    if options.audio_only {
+        let audio_path = temp_dir_path.join("audio.mp3");
+        let extracted = run_encode(tokio::process::Command::new("ffmpeg")
+            .arg("-i").arg(&input_path).arg("-vn").arg("-c:a").arg("libmp3lame").arg("-q:a").arg("2").arg("-y").arg(&audio_path)).await;
+        if !extracted {
+            log::error!("{}ffmpeg audio extraction failed.", job_tag());
+            bot.edit_message_text(chat_id, status_message_id, "❌ Error: This video has no audio that could be extracted.").await.ok();
+            return;
+        }
+        upload_and_save_audio(&bot, chat_id, user_message_id, status_message_id, &audio_path, &caption, &pool, user_id, &options, &config).await;
+        return;
+    }
+
     let output_path = temp_dir_path.join("output.mp4");
 
-    let (final_file_id, final_message_text) = autocrop_and_upload_video(
-        bot.clone(), chat_id, user_message_id, &input_path, &output_path, temp_dir.path(), &caption
+    let (final_file_id, kind, thumb_file_id, final_message_text) = autocrop_and_upload_video(
+        bot.clone(), chat_id, user_message_id, &input_path, &output_path, temp_dir.path(), &caption, &options, &pool, &config
     ).await;
 
     if final_file_id.is_empty() {
@@ -983,14 +5302,7 @@ async fn process_and_save_video(
         return;
     }
 
-    let user_id_i64 = user_id.0 as i64;
-    if sqlx::query("INSERT OR IGNORE INTO videos (file_id, caption, user_id) VALUES (?, ?, ?)")
-        .bind(&final_file_id).bind(&caption).bind(user_id_i64).execute(&pool).await.is_ok()
-    {
-        bot.edit_message_text(chat_id, status_message_id, final_message_text).await.ok();
-    } else {
-        bot.edit_message_text(chat_id, status_message_id, "❌ DB error while saving video.").await.ok();
-    }
+    finish_save(&bot, chat_id, status_message_id, &pool, &final_file_id, kind, thumb_file_id.as_deref(), &caption, &options, user_id, final_message_text, &config).await;
 }
 
 // --- Background task for the green screen feature ---
@@ -1086,7 +5398,7 @@ async fn create_doakes_video(
 
     // Instead of using `shortest` in the filter, we use it as a top-level flag.
     // This is more reliable for preventing timestamp issues that create unplayable files.
-    let ffmpeg_status = tokio::process::Command::new("ffmpeg")
+    let encoded = run_encode(tokio::process::Command::new("ffmpeg")
         .arg("-i").arg(doakes_path)
         .arg("-loop").arg("1")
         .arg("-i").arg(&photo_path)
@@ -1098,12 +5410,10 @@ async fn create_doakes_video(
         .arg("-preset").arg("veryfast")     // Good speed/quality balance for a bot
         .arg("-pix_fmt").arg("yuv420p")      // Crucial for compatibility on most devices
         .arg("-shortest")                   // End encoding when the shortest input (the video) ends
-        .arg("-y").arg(&output_path)        // Overwrite output if it exists
-        .status()
-        .await;
+        .arg("-y").arg(&output_path)).await; // Overwrite output if it exists
 
     // 5. Upload result and clean up
-    if ffmpeg_status.is_ok_and(|s| s.success()) {
+    if encoded {
         if let Err(e) = bot.send_video(chat_id, InputFile::file(&output_path)).reply_to_message_id(user_message_id).await {
             log::error!("Failed to upload greenscreen video: {}", e);
             bot.edit_message_text(chat_id, status_msg.id, "❌ Error: Failed to upload the final video.").await.ok();
@@ -1133,7 +5443,44 @@ async fn create_doakes_video(
 }
 
 
-async fn handle_message(bot: Bot, msg: Message, pool: SharedState) -> Result<(), teloxide::RequestError> {
+/// Applies a rename chosen in the `/rename` dialog with the caption the user just sent.
+async fn complete_pending_rename(bot: &Bot, pool: &SharedState, config: &Config, msg: &Message, user_id: UserId, pending: PendingRename, new_caption: &str) -> Result<(), teloxide::RequestError> {
+    if new_caption.is_empty() {
+        bot.send_message(msg.chat.id, "The new caption can't be empty. Use /rename to try again.").await?;
+        return Ok(());
+    }
+    if find_blocked_caption_word(new_caption).is_some() {
+        bot.send_message(msg.chat.id, "That caption isn't allowed. Use /rename to try again.").await?;
+        return Ok(());
+    }
+    let result = sqlx::query("UPDATE videos SET caption = ?, caption_norm = ?, caption_translit = ?, caption_folded = ? WHERE file_id = ? AND user_id = ?")
+        .bind(new_caption).bind(normalize_caption(new_caption)).bind(caption_translit(new_caption, config)).bind(caption_folded(new_caption))
+        .bind(&pending.file_id).bind(user_id.0 as i64)
+        .execute(pool).await;
+    let reply = match result {
+        Ok(done) if done.rows_affected() == 0 => format!("❌ Error: '{}' is no longer saved. Use /rename to pick another video.", pending.old_caption),
+        Ok(_) => format!("✅ Renamed '{}' to '{}'.", pending.old_caption, new_caption),
+        Err(e) => {
+            log::error!("Failed to rename video: {}", e);
+            "❌ DB error while renaming video.".to_string()
+        }
+    };
+    bot.send_message(msg.chat.id, reply).reply_to_message_id(msg.id).await?;
+    Ok(())
+}
+
+async fn handle_message(bot: Bot, msg: Message, pool: SharedState, config: Arc<Config>) -> Result<(), teloxide::RequestError> {
+    if !ensure_chat_allowed(&bot, &pool, &config, &msg).await? {
+        return Ok(());
+    }
+
+    if let (Some(user), Some(text)) = (msg.from(), msg.text()) {
+        let pending = PENDING_RENAMES.lock().unwrap().remove(&(msg.chat.id, user.id));
+        if let Some(pending) = pending {
+            return complete_pending_rename(&bot, &pool, &config, &msg, user.id, pending, text.trim()).await;
+        }
+    }
+
     let mut is_photo_message = false;
 
     // --- New: Handle incoming photos for the greenscreen feature ---
@@ -1157,15 +5504,15 @@ async fn handle_message(bot: Bot, msg: Message, pool: SharedState) -> Result<(),
     }
 
     // --- Existing Logic for saving videos ---
-    let mut video_to_save: Option<&Video> = None;
+    let mut video_to_save: Option<String> = None;
     let mut caption_to_save: Option<&str> = None;
     let mut source_message_for_reply = &msg;
 
-    if let (Some(video), Some(caption)) = (msg.video(), msg.caption()) {
+    if let (Some(video), Some(caption)) = (saveable_video_file_id(&msg), msg.caption()) {
         video_to_save = Some(video);
         caption_to_save = Some(caption);
     } else if let (Some(reply), Some(caption)) = (msg.reply_to_message(), msg.text()) {
-        if let Some(video) = reply.video() {
+        if let Some(video) = saveable_video_file_id(reply) {
             video_to_save = Some(video);
             caption_to_save = Some(caption);
             source_message_for_reply = reply;
@@ -1174,33 +5521,113 @@ async fn handle_message(bot: Bot, msg: Message, pool: SharedState) -> Result<(),
 
     let Some(user) = msg.from() else { return Ok(()); };
 
+    // An `/export` document captioned with, or replied to with, `/import` restores the library.
+    let import_document = match (msg.document(), msg.caption(), msg.text(), msg.reply_to_message()) {
+        (Some(document), Some(caption), _, _) if caption.trim() == "/import" => Some(document),
+        (None, _, Some(text), Some(reply)) if text.trim() == "/import" => reply.document(),
+        _ => None,
+    };
+    if let Some(document) = import_document {
+        let is_json = document.file_name.as_deref().is_some_and(|name| name.to_lowercase().ends_with(".json"));
+        let reply = if !is_json {
+            "❌ Error: Send the .json file you got from /export.".to_string()
+        } else {
+            match import_user_videos(&bot, &pool, &config, user.id, document).await {
+                Ok((imported, skipped)) => format!("✅ Imported {} videos, skipped {} already saved.", imported, skipped),
+                Err(message) => message,
+            }
+        };
+        bot.send_message(msg.chat.id, reply).reply_to_message_id(msg.id).await?;
+        return Ok(());
+    }
+
+    // Audio sent privately becomes the user's `/dub` track.
+    if msg.chat.is_private() {
+        let audio_file_id = msg.audio().map(|a| a.file.id.clone()).or_else(|| msg.voice().map(|v| v.file.id.clone()));
+        if let Some(audio_file_id) = audio_file_id {
+            let reply = match set_user_setting(&pool, user.id, SETTING_DUB_AUDIO, &audio_file_id).await {
+                Ok(()) => "🎵 Saved as your dub track. Add /dub to an inline /edit to put it under the clip.",
+                Err(_) => "❌ Error: Could not save your dub track.",
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+            return Ok(());
+        }
+    }
+
+    // A captioned file that isn't an mp4 can't be saved.
+    if let (Some(_), Some(_), None) = (msg.document(), msg.caption(), &video_to_save) {
+        bot.send_message(msg.chat.id, "I can only save videos and .mp4 files. Send /help to see how saving works.").reply_to_message_id(msg.id).await?;
+        return Ok(());
+    }
+
+    // Uncaptioned videos (e.g. a forwarded batch) are kept for `/saveall`.
+    if let (Some(video), None) = (msg.video(), msg.caption()) {
+        remember_unsaved_video(msg.chat.id, user.id, msg.id, video.clone());
+        if msg.media_group_id().is_none() {
+            bot.send_message(msg.chat.id, "Reply to this video with a caption to save it, or send /saveall <caption> to save all of your recent videos.").await?;
+        }
+        return Ok(());
+    }
+
     if let (Some(video), Some(caption)) = (video_to_save, caption_to_save) {
+        let (caption, mut options) = match parse_save_options(caption) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                bot.send_message(msg.chat.id, message).reply_to_message_id(msg.id).await?;
+                return Ok(());
+            }
+        };
+        forget_unsaved_video(msg.chat.id, user.id, source_message_for_reply.id);
+        options.uploader = user_display_name(user);
+        options.caption_template = caption_template_for(&pool, user.id, &config).await;
+        if let Some(word) = find_blocked_caption_word(&caption) {
+            log::info!("Rejected caption containing blocked word '{}'.", word);
+            bot.send_message(msg.chat.id, "That caption isn't allowed.").reply_to_message_id(msg.id).await?;
+            return Ok(());
+        }
         let status_msg = bot.send_message(msg.chat.id, "⏳ Analyzing and saving video...").reply_to_message_id(msg.id).await?;
 
-        tokio::spawn(process_and_save_video(
+        let status = JobStatus::Chat(msg.chat.id, status_msg.id);
+        tokio::spawn(run_queued_job(bot.clone(), pool.clone(), config.clone(), user.id, "save", status, process_and_save_video(
             bot.clone(), msg.chat.id, source_message_for_reply.id, status_msg.id,
-            video.clone(), caption.to_string(), pool, user.id
-        ));
+            video, caption, pool, user.id, options, config.clone(),
+        )));
     } else if let Some(text) = msg.text() {
-        let maybe_url = text.split_whitespace().find(|s| {
-            s.contains("douyin.com") || s.contains("vk.com") ||
-            s.contains("youtube.com/clip/") || s.contains("youtube.com/shorts/") ||
-            s.contains("instagram.com/reel/") || s.contains("bsky.app") ||
-            s.contains("x.com/") || s.contains("twitter.com/") ||
-            s.contains("reddit.com/") || s.contains("tiktok.com")
-        });
+        let maybe_url = text.split_whitespace().find(|s| is_supported_url(s));
 
         if let Some(url) = maybe_url {
-            let caption = text.replace(url, "").trim().to_string();
+            let (caption, mut options) = match parse_save_options(&text.replace(url, "")) {
+                Ok(parsed) => parsed,
+                Err(message) => {
+                    bot.send_message(msg.chat.id, message).reply_to_message_id(msg.id).await?;
+                    return Ok(());
+                }
+            };
+            let Some(url) = normalize_url(url) else {
+                bot.send_message(msg.chat.id, "❌ Error: That link doesn't look valid.").reply_to_message_id(msg.id).await?;
+                return Ok(());
+            };
+            options.uploader = user_display_name(user);
+            options.caption_template = caption_template_for(&pool, user.id, &config).await;
             if caption.is_empty() {
                 bot.send_message(msg.chat.id, "Please provide a caption for the video link.").await?;
                 return Ok(());
             }
+            if let Some(word) = find_blocked_caption_word(&caption) {
+                log::info!("Rejected link caption containing blocked word '{}'.", word);
+                bot.send_message(msg.chat.id, "That caption isn't allowed.").reply_to_message_id(msg.id).await?;
+                return Ok(());
+            }
+            if let Err(wait_secs) = take_download_token(user.id, &config) {
+                bot.send_message(msg.chat.id, format!("⏳ Slow down, try again in {} seconds", wait_secs)).reply_to_message_id(msg.id).await?;
+                return Ok(());
+            }
             let status_msg = bot.send_message(msg.chat.id, "⏳ Downloading and saving video...").reply_to_message_id(msg.id).await?;
-            tokio::spawn(download_and_process_video(
-                bot.clone(), msg.chat.id, msg.id, status_msg.id,
-                url.to_string(), caption, pool, user.id,
-            ));
+            let job = RetryableJob::Download {
+                chat_id: msg.chat.id.0, user_message_id: msg.id.0, status_message_id: status_msg.id.0,
+                url, caption, options,
+            };
+            spawn_retryable_job(bot.clone(), pool, config.clone(), user.id, job);
         } else {
              bot.send_message(msg.chat.id, "Send a video with a caption, a link with a caption, or a photo to get a surprise.").await?;
         }
@@ -1216,8 +5643,23 @@ async fn handle_message(bot: Bot, msg: Message, pool: SharedState) -> Result<(),
 }
 
 
-async fn handle_callback_query(bot: Bot, q: CallbackQuery, pool: SharedState) -> Result<(), teloxide::RequestError> {
+async fn handle_callback_query(bot: Bot, q: CallbackQuery, pool: SharedState, config: Arc<Config>) -> Result<(), teloxide::RequestError> {
     let Some(data) = q.data else { return Ok(()) };
+
+    // Retry buttons also sit on inline messages, which have no `message`.
+    if let Some(job_id) = data.strip_prefix("retry_") {
+        let retried = match job_id.parse::<i64>() {
+            Ok(job_id) => retry_failed_job(bot.clone(), pool, config.clone(), q.from.id, job_id).await,
+            Err(_) => false,
+        };
+        if retried {
+            bot.answer_callback_query(q.id).await?;
+        } else {
+            bot.answer_callback_query(q.id).text("This retry has expired.").await?;
+        }
+        return Ok(());
+    }
+
     let Some(message) = q.message else { return Ok(()) };
     let user_id = q.from.id;
 
@@ -1232,6 +5674,98 @@ async fn handle_callback_query(bot: Bot, q: CallbackQuery, pool: SharedState) ->
             }
         }
     }
+    else if let Some(page_str) = data.strip_prefix("rename_page_") {
+        if let Ok(page) = page_str.parse::<i64>() {
+            if let Ok(Some(keyboard)) = build_rename_keyboard(&pool, user_id, page).await {
+                bot.edit_message_reply_markup(message.chat.id, message.id).reply_markup(keyboard).await?;
+            }
+        }
+    }
+    else if let Some(page_str) = data.strip_prefix("send_page_") {
+        let term = message.text().and_then(search_term_from_prompt);
+        if let (Ok(page), Some(term)) = (page_str.parse::<i64>(), term) {
+            if let Ok(Some(keyboard)) = build_search_keyboard(&pool, user_id, term, page).await {
+                bot.edit_message_reply_markup(message.chat.id, message.id).reply_markup(keyboard).await?;
+            }
+        }
+    }
+    // Only the user who ran /dedup can answer its prompt.
+    else if let Some(owner) = data.strip_prefix("dedup_confirm_") {
+        if owner == user_id.0.to_string() {
+            let reply = match dedup_user_videos(&pool, user_id).await {
+                Ok(removed) => format!("✅ Removed {} duplicate video{}, keeping the newest of each caption.", removed, if removed == 1 { "" } else { "s" }),
+                Err(e) => {
+                    log::error!("Failed to deduplicate videos: {}", e);
+                    "❌ Error: Could not remove duplicate videos.".to_string()
+                }
+            };
+            bot.edit_message_text(message.chat.id, message.id, reply).await?;
+        }
+    }
+    else if let Some(owner) = data.strip_prefix("dedup_cancel_") {
+        if owner == user_id.0.to_string() {
+            bot.edit_message_text(message.chat.id, message.id, "Nothing was removed.").await?;
+        }
+    }
+    else if let Some(page_str) = data.strip_prefix("list_page_") {
+        if let Ok(page) = page_str.parse::<i64>() {
+            if let Ok(Some((text, keyboard))) = build_caption_list(&pool, user_id, page).await {
+                bot.edit_message_text(message.chat.id, message.id, text).reply_markup(keyboard).await?;
+            }
+        }
+    }
+    else if let Some(page_str) = data.strip_prefix("note_page_") {
+        if let Ok(page) = page_str.parse::<i64>() {
+            if let Ok(Some(keyboard)) = build_note_keyboard(&pool, user_id, page).await {
+                bot.edit_message_reply_markup(message.chat.id, message.id).reply_markup(keyboard).await?;
+            }
+        }
+    }
+    else if let Some(note_data) = data.strip_prefix("note_") {
+        if let Some((_, prefix)) = note_data.split_once('_') {
+            let pattern = format!("{}%", prefix);
+            if let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption, kind FROM videos WHERE file_id LIKE ? AND user_id = ?")
+                .bind(&pattern).bind(user_id.0 as i64).fetch_optional(&pool).await.unwrap_or(None)
+            {
+                if video.kind == KIND_AUDIO {
+                    bot.edit_message_text(message.chat.id, message.id, "❌ Error: Audio can't be sent as a video note.").await?;
+                    return Ok(());
+                }
+                bot.edit_message_text(message.chat.id, message.id, format!("⏳ Making a video note from '{}'...", video.caption)).await?;
+                let status = JobStatus::Chat(message.chat.id, message.id);
+                tokio::spawn(run_queued_job(bot.clone(), pool.clone(), config.clone(), user_id, "note", status,
+                    send_as_video_note(bot, message.chat.id, message.id, video.file_id, config)));
+            }
+        }
+    }
+    else if let Some(send_data) = data.strip_prefix("send_") {
+        if let Some((_, prefix)) = send_data.split_once('_') {
+            let pattern = format!("{}%", prefix);
+            if let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption, kind FROM videos WHERE file_id LIKE ? AND user_id = ?")
+                .bind(&pattern).bind(user_id.0 as i64).fetch_optional(&pool).await.unwrap_or(None)
+            {
+                let file = InputFile::file_id(video.file_id);
+                let caption = truncate_caption(&video.caption);
+                match video.kind.as_str() {
+                    KIND_ANIMATION => { bot.send_animation(message.chat.id, file).caption(caption).await?; }
+                    KIND_AUDIO => { bot.send_audio(message.chat.id, file).caption(caption).await?; }
+                    _ => { bot.send_video(message.chat.id, file).caption(caption).await?; }
+                }
+            }
+        }
+    }
+    else if let Some(rename_data) = data.strip_prefix("rename_") {
+        if let Some((_, prefix)) = rename_data.split_once('_') {
+            let pattern = format!("{}%", prefix);
+            if let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption FROM videos WHERE file_id LIKE ? AND user_id = ?")
+                .bind(&pattern).bind(user_id.0 as i64).fetch_optional(&pool).await.unwrap_or(None)
+            {
+                let prompt = format!("✏️ Send the new caption for '{}'.", video.caption);
+                PENDING_RENAMES.lock().unwrap().insert((message.chat.id, user_id), PendingRename { file_id: video.file_id, old_caption: video.caption });
+                bot.edit_message_text(message.chat.id, message.id, prompt).await?;
+            }
+        }
+    }
     else if let Some(delete_data) = data.strip_prefix("delete_") {
         if let Some((page_str, prefix)) = delete_data.split_once('_') {
             if let Ok(page) = page_str.parse::<i64>() {
@@ -1239,7 +5773,13 @@ async fn handle_callback_query(bot: Bot, q: CallbackQuery, pool: SharedState) ->
                 if let Some(video) = sqlx::query_as::<_, VideoData>("SELECT file_id, caption FROM videos WHERE file_id LIKE ? AND user_id = ?")
                     .bind(&pattern).bind(user_id.0 as i64).fetch_optional(&pool).await.unwrap_or(None)
                 {
-                    sqlx::query("DELETE FROM videos WHERE file_id = ?").bind(&video.file_id).execute(&pool).await.ok();
+                    // The keyboard is rebuilt from a fresh count, so the delete has to finish first.
+                    if let Err(e) = sqlx::query("DELETE FROM videos WHERE file_id = ? AND user_id = ?")
+                        .bind(&video.file_id).bind(user_id.0 as i64).execute(&pool).await {
+                        log::error!("Failed to delete video: {}", e);
+                        bot.edit_message_text(message.chat.id, message.id, "❌ DB error while removing the video.").await?;
+                        return Ok(());
+                    }
                     let confirmation_text = format!("✅ Removed '{}'\n\nSelect another video to remove:", video.caption);
 
                     match build_remove_keyboard(&pool, user_id, page).await {
@@ -1260,3 +5800,445 @@ async fn handle_callback_query(bot: Bot, q: CallbackQuery, pool: SharedState) ->
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The default `MAX_EDIT_BOXES`.
+    const TEST_MAX_BOXES: usize = 4;
+
+    #[test]
+    fn edit_params_plain_text() {
+        assert_eq!(parse_edit_params("New funny text", TEST_MAX_BOXES), EditSpec::Plain("New funny text".to_string()));
+    }
+
+    #[test]
+    fn edit_params_empty() {
+        assert_eq!(parse_edit_params("", TEST_MAX_BOXES), EditSpec::Plain(String::new()));
+    }
+
+    #[test]
+    fn edit_params_timed() {
+        assert_eq!(parse_edit_params("Before /5.5 After", TEST_MAX_BOXES), EditSpec::Timed {
+            first: "Before".to_string(), at: 5.5, second: "After".to_string(),
+        });
+    }
+
+    #[test]
+    fn edit_params_time_without_following_text_is_plain() {
+        assert_eq!(parse_edit_params("Before /5.5", TEST_MAX_BOXES), EditSpec::Plain("Before /5.5".to_string()));
+    }
+
+    #[test]
+    fn edit_params_multiple_slashes_use_the_last_one() {
+        assert_eq!(parse_edit_params("a/b /2 c / d", TEST_MAX_BOXES), EditSpec::Plain("a/b /2 c / d".to_string()));
+        assert_eq!(parse_edit_params("a /1 b /2 c", TEST_MAX_BOXES), EditSpec::Timed {
+            first: "a /1 b".to_string(), at: 2.0, second: "c".to_string(),
+        });
+    }
+
+    #[test]
+    fn edit_params_boxes() {
+        assert_eq!(parse_edit_params("Top /box2 Bottom", TEST_MAX_BOXES), EditSpec::Boxes(vec!["Top".to_string(), "Bottom".to_string()]));
+    }
+
+    #[test]
+    fn edit_params_numeric_box_text_is_not_timed() {
+        assert_eq!(parse_edit_params("a /box2 999 /box3 c", TEST_MAX_BOXES),
+            EditSpec::Boxes(vec!["a".to_string(), "999".to_string(), "c".to_string()]));
+    }
+
+    #[test]
+    fn edit_time_accepted_formats() {
+        assert_eq!(parse_edit_time("5.5"), Some(5.5));
+        assert_eq!(parse_edit_time("0:05"), Some(5.0));
+        assert_eq!(parse_edit_time("1:30"), Some(90.0));
+        assert_eq!(parse_edit_time("1:02:03.5"), Some(3723.5));
+        assert_eq!(parse_edit_time("1m2s"), Some(62.0));
+        assert_eq!(parse_edit_time("90s"), Some(90.0));
+        assert_eq!(parse_edit_time("1.5m"), Some(90.0));
+        assert_eq!(parse_edit_time("1h"), Some(3600.0));
+    }
+
+    #[test]
+    fn edit_time_rejects_invalid_values() {
+        for text in ["", "abc", "-1", "nan", "1:60", "1:60:00", "1:2:3:4", "1s2m", "1m2", "m", "box2"] {
+            assert_eq!(parse_edit_time(text), None, "{:?} should be rejected", text);
+        }
+    }
+
+    #[test]
+    fn ass_escape_keeps_override_sequences_literal() {
+        assert_eq!(escape_ass_text("a\\Nb", true), "a\\\u{2060}Nb");
+        assert_eq!(escape_ass_text("a\\Nb", false), "a\\ Nb");
+        assert_eq!(escape_ass_text("{\\b1}bold", true), "\\{\\b1\\}bold");
+        assert_eq!(escape_ass_text("\\{", true), "\\\\{");
+    }
+
+    #[test]
+    fn ass_escape_converts_newlines() {
+        assert_eq!(escape_ass_text("one\r\ntwo\nthree", true), "one\\Ntwo\\Nthree");
+    }
+
+    #[test]
+    fn ass_escape_passes_emoji_through() {
+        assert_eq!(escape_ass_text("cat 😂🐈", true), "cat 😂🐈");
+    }
+
+    /// A font with only a `cmap` table holding one format 4 subtable for `start..=end`.
+    fn font_with_cmap_range(start: u16, end: u16) -> Vec<u8> {
+        let mut font = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        font.extend_from_slice(b"cmap");
+        font.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 28, 0, 0, 0, 0]);
+        // cmap header and one encoding record pointing 12 bytes in.
+        font.extend_from_slice(&[0, 0, 0, 1, 0, 3, 0, 1, 0, 0, 0, 12]);
+        // Format 4 with two segments: the range, then the required 0xFFFF end marker.
+        font.extend_from_slice(&[0, 4, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0]);
+        for value in [end, 0xFFFF, 0, start, 0xFFFF] {
+            font.extend_from_slice(&value.to_be_bytes());
+        }
+        font
+    }
+
+    #[test]
+    fn font_coverage_reads_cmap_ranges() {
+        assert_eq!(font_covers(&font_with_cmap_range(0x2000, 0x206F), '\u{2060}'), Some(true));
+        assert_eq!(font_covers(&font_with_cmap_range(0x20, 0x7E), '\u{2060}'), Some(false));
+        assert_eq!(font_covers(b"not a font", '\u{2060}'), None);
+    }
+
+    #[test]
+    fn cropdetect_picks_the_settled_crop_over_a_fade_in() {
+        let stderr = "\
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] x1:0 x2:1279 y1:0 y2:719 w:1280 h:720 x:0 y:0 pts:0 t:0.000000 limit:0.094118 crop=1280:720:0:0
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] x1:96 x2:1183 y1:160 y2:559 w:1088 h:400 x:96 y:160 pts:512 t:0.033333 limit:0.094118 crop=1088:400:96:160
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] x1:16 x2:1263 y1:96 y2:623 w:1248 h:528 x:16 y:96 pts:1024 t:0.066667 limit:0.094118 crop=1248:528:16:96
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] x1:0 x2:1279 y1:88 y2:631 w:1280 h:544 x:0 y:88 pts:1536 t:0.100000 limit:0.094118 crop=1280:544:0:88
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] x1:0 x2:1279 y1:88 y2:631 w:1280 h:544 x:0 y:88 pts:2048 t:0.133333 limit:0.094118 crop=1280:544:0:88
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] crop=1280:544:0
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] x1:0 x2:1279 y1:88 y2:631 w:1280 h:544 x:0 y:88 pts:2560 t:0.166667 limit:0.094118 crop=1280:544:0:88
+[Parsed_cropdetect_0 @ 0x55d5c1e0a5c0] x1:0 x2:1279 y1:0 y2:719 w:1280 h:720 x:0 y:0 pts:3072 t:0.200000 limit:0.094118 crop=1280:720:0:0";
+        let suggestions: Vec<CropRect> = stderr.lines().filter_map(parse_cropdetect_line).collect();
+        assert_eq!(suggestions.len(), 7);
+        assert_eq!(most_common_crop(&suggestions), Some(CropRect { w: 1280, h: 544, x: 0, y: 88 }));
+    }
+
+    #[test]
+    fn cropdetect_rejects_malformed_lines() {
+        assert_eq!(parse_cropdetect_line("crop=1280:544:0"), None);
+        assert_eq!(parse_cropdetect_line("crop=1280:544:0:x"), None);
+        assert_eq!(parse_cropdetect_line("crop=-1280:-720:1280:720"), None);
+        assert_eq!(parse_cropdetect_line("frame=  120 fps=0.0 q=-0.0"), None);
+        assert_eq!(most_common_crop(&[]), None);
+    }
+
+    #[test]
+    fn trim_range_accepts_valid_ranges() {
+        assert_eq!(parse_trim_range("3-12"), Some((3.0, 12.0)));
+        assert_eq!(parse_trim_range("1.5-4"), Some((1.5, 4.0)));
+    }
+
+    #[test]
+    fn trim_range_rejects_malformed_ranges() {
+        for range in ["", "12", "12-3", "5-5", "-1-4", "a-b", "3-", "-4", "inf-5", "1-inf"] {
+            assert_eq!(parse_trim_range(range), None, "{:?} should be rejected", range);
+        }
+    }
+
+    #[test]
+    fn save_options_report_bad_trim() {
+        assert!(parse_save_options("cat video /trim 12-3").is_err());
+        assert!(parse_save_options("cat video /trim").is_err());
+        let (caption, options) = parse_save_options("cat video /trim 3-12").unwrap();
+        assert_eq!(caption, "cat video");
+        assert_eq!(options.trim, Some((3.0, 12.0)));
+    }
+
+    #[test]
+    fn like_pattern_escapes_wildcards() {
+        assert_eq!(escape_like_pattern("100% real"), "100\\% real");
+        assert_eq!(escape_like_pattern("snake_case"), "snake\\_case");
+        assert_eq!(escape_like_pattern("back\\slash"), "back\\\\slash");
+        assert_eq!(escape_like_pattern("plain words"), "plain words");
+    }
+
+    #[test]
+    fn url_matcher_accepts_supported_hosts() {
+        for url in [
+            "https://vimeo.com/123",
+            "https://clips.twitch.tv/FunnyClip",
+            "https://www.twitch.tv/somechannel/clip/FunnyClip-1",
+            "https://x.com/user/status/1",
+            "https://m.youtube.com/shorts/abc",
+            "vk.com/video-1_2",
+            "(https://www.reddit.com/r/videos/comments/abc)",
+        ] {
+            assert!(is_supported_url(url), "{url}");
+        }
+    }
+
+    #[test]
+    fn url_matcher_rejects_lookalike_hosts() {
+        for url in [
+            "https://netflix.com/title/1",
+            "https://notvk.com/video1",
+            "https://www.twitch.tv/somechannel",
+            "https://youtube.com/watch?v=abc",
+            "https://example.com/?next=vk.com",
+            "hello",
+        ] {
+            assert!(!is_supported_url(url), "{url}");
+        }
+    }
+
+    #[test]
+    fn normalize_url_strips_trailing_punctuation() {
+        assert_eq!(normalize_url("https://vimeo.com/123.").as_deref(), Some("https://vimeo.com/123"));
+        assert_eq!(normalize_url("(https://x.com/user/status/1)").as_deref(), Some("https://x.com/user/status/1"));
+        assert_eq!(normalize_url("https://vk.com/video-1_2,").as_deref(), Some("https://vk.com/video-1_2"));
+        assert_eq!(normalize_url("«https://bsky.app/profile/a/post/b»!").as_deref(), Some("https://bsky.app/profile/a/post/b"));
+    }
+
+    #[test]
+    fn normalize_url_adds_missing_scheme() {
+        assert_eq!(normalize_url("vk.com/video-1_2").as_deref(), Some("https://vk.com/video-1_2"));
+        assert_eq!(normalize_url("tiktok.com/@x/video/1.").as_deref(), Some("https://tiktok.com/@x/video/1"));
+        assert_eq!(normalize_url("ftp://vk.com/video-1_2"), None);
+    }
+
+    #[test]
+    fn normalize_url_drops_instagram_tracking() {
+        assert_eq!(
+            normalize_url("https://www.instagram.com/reel/abc/?igsh=xyz&utm_source=ig").as_deref(),
+            Some("https://www.instagram.com/reel/abc/")
+        );
+    }
+
+    const TEST_BACKOFF: BackoffConfig = BackoffConfig {
+        base: Duration::from_secs(1), max: Duration::from_secs(5), factor: 2.0, jitter: 0.5, max_attempts: 4,
+    };
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let delays: Vec<Duration> = (0..5).map(|retry| TEST_BACKOFF.delay(retry, 0.0)).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs));
+        assert_eq!(TEST_BACKOFF.delay(60, 0.0), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn backoff_jitter_only_shortens_the_delay() {
+        assert_eq!(TEST_BACKOFF.delay(1, 0.5), Duration::from_millis(1500));
+        let shortest = TEST_BACKOFF.delay(0, 0.999_999);
+        assert!(shortest > Duration::from_millis(500) && shortest < Duration::from_secs(1));
+        for _ in 0..100 {
+            let sample = jitter_sample();
+            assert!((0.0..1.0).contains(&sample));
+        }
+    }
+
+    /// A 160x120 frame with static grey bars around a 100x80 checkerboard at (30, 20). The
+    /// `inverted` frame swaps the checkerboard's colours, so only the content area moves.
+    fn letterboxed_frame(dir: &Path, name: &str, inverted: bool) -> PathBuf {
+        let mut frame = image::GrayImage::from_pixel(160, 120, Luma([30]));
+        for y in 20..100 {
+            for x in 30..130 {
+                let light = ((x / 8 + y / 8) % 2 == 0) != inverted;
+                frame.put_pixel(x, y, Luma([if light { 200 } else { 60 }]));
+            }
+        }
+        let path = dir.join(name);
+        frame.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn motion_crop_finds_the_moving_area() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = letterboxed_frame(dir.path(), "a.png", false);
+        let b = letterboxed_frame(dir.path(), "b.png", true);
+        let crop = detect_motion_crop(&a, &b, MotionThresholds::DEFAULT).expect("a crop");
+        // The blur spreads the motion a pixel or two past the content edges.
+        assert!(crop.x.abs_diff(30) <= 3 && crop.y.abs_diff(20) <= 3, "{crop:?}");
+        assert!(crop.w.abs_diff(100) <= 6 && crop.h.abs_diff(80) <= 6, "{crop:?}");
+        assert!(crop.w.is_multiple_of(2) && crop.h.is_multiple_of(2), "{crop:?}");
+        // Nothing changes by more than 250, and no line is more than 90% content.
+        let strict_pixels = MotionThresholds { pixel_change: 250, ..MotionThresholds::DEFAULT };
+        assert_eq!(detect_motion_crop(&a, &b, strict_pixels), None);
+        let strict_lines = MotionThresholds { line_motion_percent: 0.9, ..MotionThresholds::DEFAULT };
+        assert_eq!(detect_motion_crop(&a, &b, strict_lines), None);
+    }
+
+    /// The edge scans of `find_motion_area` done one after another on one thread.
+    fn sequential_motion_area(a: &Path, b: &Path, thresholds: MotionThresholds) -> Option<(CropRect, u32, u32)> {
+        let blurred_a = image::imageops::blur(&ImageReader::open(a).ok()?.decode().ok()?.to_luma8(), 1.5);
+        let blurred_b = image::imageops::blur(&ImageReader::open(b).ok()?.decode().ok()?.to_luma8(), 1.5);
+        let (width, height) = blurred_a.dimensions();
+        let changed = |x: u32, y: u32| {
+            (blurred_a.get_pixel(x, y)[0] as i16 - blurred_b.get_pixel(x, y)[0] as i16).abs() > thresholds.pixel_change as i16
+        };
+        let moves = |count: usize, total: u32| count as f32 / total as f32 > thresholds.line_motion_percent;
+        let row_moves = |y: u32| moves((0..width).filter(|&x| changed(x, y)).count(), width);
+        let top = (0..height).find(|&y| row_moves(y)).unwrap_or(0);
+        let bottom = (0..height).rev().find(|&y| row_moves(y)).unwrap_or(height);
+        let column_moves = |x: u32| moves((top..bottom).filter(|&y| changed(x, y)).count(), bottom - top);
+        let left = (0..width).find(|&x| column_moves(x)).unwrap_or(0);
+        let right = (0..width).rev().find(|&x| column_moves(x)).unwrap_or(width);
+        if top >= bottom || left >= right {
+            return None;
+        }
+        Some((CropRect { x: left, y: top, w: right - left, h: bottom - top }, width, height))
+    }
+
+    #[test]
+    fn parallel_edge_scans_match_sequential_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        // An off-centre block of noise that changes between frames, over faintly noisy bars.
+        let mut seed = 12345u32;
+        let mut noise = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        let mut paths = vec![];
+        for name in ["a.png", "b.png"] {
+            let mut frame = image::GrayImage::new(320, 180);
+            for (x, y, pixel) in frame.enumerate_pixels_mut() {
+                let content = (37..251).contains(&x) && (23..149).contains(&y);
+                *pixel = Luma([if content { noise() } else { 20 + noise() % 4 }]);
+            }
+            paths.push(dir.path().join(name));
+            frame.save(&paths[paths.len() - 1]).unwrap();
+        }
+        let parallel = find_motion_area(&paths[0], &paths[1], MotionThresholds::DEFAULT);
+        let sequential = sequential_motion_area(&paths[0], &paths[1], MotionThresholds::DEFAULT);
+        assert!(parallel.is_some());
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn deleting_the_sole_item_on_the_last_page_clamps_back() {
+        // Nine videos fill page 0 and leave one on page 1; deleting that one leaves eight.
+        let total_after_delete = REMOVE_PAGE_SIZE;
+        assert_eq!(clamp_page(1, total_after_delete + 1, REMOVE_PAGE_SIZE), 1);
+        assert_eq!(clamp_page(1, total_after_delete, REMOVE_PAGE_SIZE), 0);
+    }
+
+    #[test]
+    fn clamp_page_stays_in_range() {
+        assert_eq!(clamp_page(0, 0, REMOVE_PAGE_SIZE), 0);
+        assert_eq!(clamp_page(-3, 20, REMOVE_PAGE_SIZE), 0);
+        assert_eq!(clamp_page(7, 20, REMOVE_PAGE_SIZE), 2);
+        assert_eq!(clamp_page(1, 20, REMOVE_PAGE_SIZE), 1);
+    }
+
+    #[test]
+    fn upright_videos_skip_the_rotation_pass() {
+        assert_eq!(rotation_filter(0), None);
+        assert_eq!(rotation_filter(90), Some("transpose=clock"));
+        assert_eq!(rotation_filter(180), Some("hflip,vflip"));
+        assert_eq!(rotation_filter(270), Some("transpose=cclock"));
+    }
+
+    #[test]
+    fn folded_captions_match_folded_queries() {
+        assert_eq!(caption_folded("  Élève   CAFÉ straße "), "eleve cafe strasse");
+        assert_eq!(caption_folded("Привет"), "привет");
+    }
+
+    #[test]
+    fn fuzzy_fragments_survive_allowed_typos() {
+        for (query, caption) in [("cat", "cat"), ("banana", "bnana"), ("kitten", "kitxen"), ("elephant", "elefhamt"), ("dancing", "dancnig")] {
+            assert!(fuzzy_caption_score(query, caption).is_some(), "{query} should match {caption}");
+            let fragments = fuzzy_word_fragments(query);
+            assert_eq!(fragments.len(), allowed_typos(query.chars().count()) + 1);
+            assert!(fragments.iter().any(|fragment| caption.contains(fragment.as_str())), "{fragments:?} vs {caption}");
+        }
+    }
+
+    #[test]
+    fn fuzzy_prefilter_needs_every_word() {
+        let (condition, patterns) = fuzzy_prefilter_sql("caption_norm", "50% banana");
+        assert_eq!(patterns, ["%50\\%%", "%ban%", "%ana%"]);
+        assert_eq!(condition.matches(" LIKE ? ").count(), 3);
+        assert_eq!(condition.matches(" AND ").count(), 1);
+        assert_eq!(fuzzy_prefilter_sql("caption_norm", " ").0, "1");
+    }
+
+    #[test]
+    fn request_path_reads_the_request_line() {
+        assert_eq!(request_path(b"GET /metrics HTTP/1.1\r\nHost: bot\r\n\r\n"), "/metrics");
+        assert_eq!(request_path(b"GET / HTTP/1.1\r\n"), "/");
+        assert_eq!(request_path(b""), "/");
+        assert_eq!(request_path(&[0xff, 0xfe]), "/");
+    }
+
+    #[test]
+    fn download_bucket_refills_over_the_window() {
+        let start = Instant::now();
+        // Two tokens per ten seconds.
+        let mut bucket = DownloadBucket { tokens: 2.0, refilled_at: start };
+        assert_eq!(bucket.take(start, 2.0, 0.2), Ok(()));
+        assert_eq!(bucket.take(start, 2.0, 0.2), Ok(()));
+        assert_eq!(bucket.take(start, 2.0, 0.2), Err(5));
+        assert_eq!(bucket.take(start + Duration::from_secs(5), 2.0, 0.2), Ok(()));
+        assert_eq!(bucket.tokens_at(start + Duration::from_secs(60), 2.0, 0.2), 2.0);
+    }
+
+    #[test]
+    fn login_walls_are_not_reported_as_private() {
+        let bot_check = "ERROR: [youtube] abc: Sign in to confirm you're not a bot. Use --cookies-from-browser";
+        assert!(matches!(DownloadFailure::from_stderr(bot_check), DownloadFailure::AuthRequired));
+        let login = "ERROR: [instagram] xyz: Requested content is not available, rate-limit reached or login required";
+        assert!(matches!(DownloadFailure::from_stderr(login), DownloadFailure::AuthRequired));
+        let private = "ERROR: [youtube] abc: Private video. Sign in if you've been granted access to this video";
+        assert!(matches!(DownloadFailure::from_stderr(private), DownloadFailure::Private));
+        let network = "ERROR: Unable to download webpage: <urlopen error [Errno -3] Temporary failure in name resolution>";
+        assert!(matches!(DownloadFailure::from_stderr(network), DownloadFailure::Transient(_)));
+    }
+
+    #[test]
+    fn message_lines_are_packed_under_the_limit() {
+        let lines: Vec<String> = ["aaaa", "bbbb", "cccc"].iter().map(|l| l.to_string()).collect();
+        assert_eq!(split_message_lines(&lines, 9), vec!["aaaa\nbbbb", "cccc"]);
+        assert_eq!(split_message_lines(&lines, 100), vec!["aaaa\nbbbb\ncccc"]);
+
+        let long = vec!["x".repeat(10), "y".to_string()];
+        assert_eq!(split_message_lines(&long, 4), vec!["xxxx", "xxxx", "xx\ny"]);
+
+        // Emoji take two UTF-16 units, which is what Telegram counts.
+        let emoji = vec!["🎬🎬".to_string(), "🎬".to_string()];
+        assert_eq!(split_message_lines(&emoji, 4), vec!["🎬🎬", "🎬"]);
+    }
+
+    /// A dark 200x100 frame with a 120x40 and a 50x15 white rectangle.
+    fn two_box_frame(dir: &Path) -> PathBuf {
+        let mut frame = image::GrayImage::from_pixel(200, 100, Luma([40]));
+        for (x0, y0, w, h) in [(10, 10, 120, 40), (140, 70, 50, 15)] {
+            for y in y0..y0 + h {
+                for x in x0..x0 + w {
+                    frame.put_pixel(x, y, Luma([255]));
+                }
+            }
+        }
+        let path = dir.join("frame.png");
+        frame.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn box_detection_respects_size_ratios() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = two_box_frame(dir.path());
+        let sizes = |ratios: BoxRatios| detect_white_or_black_boxes(&path, ratios, TEST_MAX_BOXES).iter().map(|b| (b.w, b.h)).collect::<Vec<_>>();
+        // The small box is 0.25 x 0.15 of the frame, under the default 0.4 x 0.2.
+        assert_eq!(sizes(BoxRatios::DEFAULT), vec![(120, 40)]);
+        assert_eq!(sizes(BoxRatios { min_width: 0.2, min_height: 0.1 }), vec![(120, 40), (50, 15)]);
+    }
+
+    #[test]
+    fn edit_params_accept_fuzzy_times() {
+        assert_eq!(parse_edit_params("Before /1m2s After", TEST_MAX_BOXES), EditSpec::Timed {
+            first: "Before".to_string(), at: 62.0, second: "After".to_string(),
+        });
+    }
+}